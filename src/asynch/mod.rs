@@ -0,0 +1,808 @@
+//! Async driver for use with executors such as Embassy.
+//!
+//! [`AsyncDevice`] accesses the chip over an [`AsyncBus`], e.g. [`FourWire`](crate::bus::FourWire)
+//! with an [`embedded_hal_async::spi::SpiDevice`], and implements the
+//! [`embedded-nal-async`](embedded_nal_async) stack traits. Its sockets and their local ports
+//! are allocated from a [`DeviceState`] like the ones of a
+//! [`Device`](crate::Device). Instead of polling the chip, pending operations enable the socket's
+//! interrupts and await the INTn line (an [`embedded_hal_async::digital::Wait`] pin) or a
+//! [`PollDelay`](crate::interrupt::PollDelay).
+//!
+//! ```no_run
+//! # async fn example<SPI: embedded_hal_async::spi::SpiDevice>(
+//! #     spi: SPI,
+//! #     int_pin: impl embedded_hal_async::digital::Wait,
+//! # ) -> Result<(), w5500::Error<SPI::Error>> {
+//! use core::net::{Ipv4Addr, SocketAddr};
+//! use embedded_io_async::Write;
+//! use embedded_nal_async::TcpConnect;
+//! use w5500::{asynch::AsyncDevice, bus::FourWire, MacAddress, Mode};
+//!
+//! let device = AsyncDevice::initialize_manual(
+//!     FourWire::new(spi),
+//!     MacAddress::new(0x02, 0x00, 0x00, 0x00, 0x00, 0x01),
+//!     Ipv4Addr::new(192, 168, 0, 2),
+//!     Mode::default(),
+//!     int_pin,
+//! )
+//! .await?;
+//! let remote = SocketAddr::new(Ipv4Addr::new(192, 168, 0, 1).into(), 80);
+//! let mut connection = device.connect(remote).await?;
+//! connection.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+//! connection.close().await?;
+//! # Ok(())
+//! # }
+//! ```
+use core::cell::{Cell, RefCell, RefMut};
+use core::future::poll_fn;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::ops::{Deref, DerefMut};
+use core::task::{Poll, Waker};
+
+use bit_field::BitField;
+
+use crate::bus::AsyncBus;
+use crate::device::{DeviceState, State};
+use crate::host::Manual;
+use crate::interrupt::AsyncInterruptPin;
+use crate::register::{self, common, socketn};
+use crate::socket::Socket;
+use crate::uninitialized_device::InitializeError;
+use crate::{MacAddress, Mode};
+
+mod tcp;
+mod udp;
+
+pub use self::tcp::TcpConnection;
+pub use self::udp::{AsyncUdpSocket, BoundUdpSocket};
+
+/// The W5500 driven by an async bus.
+///
+/// All methods take `&self`, sockets borrow the device and may be used from different tasks of
+/// the same executor. The bus is locked for each SPI frame, so other tasks may access the chip
+/// while a transfer is in progress.
+pub struct AsyncDevice<SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin> {
+    bus: RefCell<SpiBus>,
+    /// The tasks waiting for the bus
+    bus_waiters: Waiters,
+    state: RefCell<StateImpl>,
+    /// The sockets dropped without closing them, closed on the chip with the next bus access
+    closing: Cell<u8>,
+    /// Taken by the task waiting on it
+    interrupt_pin: RefCell<Option<IntPin>>,
+    /// The other tasks waiting for an interrupt
+    waiters: Waiters,
+}
+
+impl<SpiBus: AsyncBus, IntPin: AsyncInterruptPin> AsyncDevice<SpiBus, DeviceState<Manual>, IntPin> {
+    /// Reset the chip and configure it with a static IP, see
+    /// [`UninitializedDevice::initialize_manual`](crate::UninitializedDevice::initialize_manual).
+    pub async fn initialize_manual(
+        bus: SpiBus,
+        mac: MacAddress,
+        ip: Ipv4Addr,
+        mode_options: Mode,
+        interrupt_pin: IntPin,
+    ) -> Result<Self, InitializeError<SpiBus::Error>> {
+        let mut ip_bytes = ip.octets();
+        ip_bytes[3] = 1;
+        let gateway = Ipv4Addr::from(ip_bytes);
+        let subnet = Ipv4Addr::new(255, 255, 255, 0);
+        Self::initialize_advanced(bus, mac, ip, gateway, subnet, mode_options, interrupt_pin).await
+    }
+
+    /// Reset the chip and configure it with a static IP configuration.
+    pub async fn initialize_advanced(
+        bus: SpiBus,
+        mac: MacAddress,
+        ip: Ipv4Addr,
+        gateway: Ipv4Addr,
+        subnet: Ipv4Addr,
+        mode_options: Mode,
+        interrupt_pin: IntPin,
+    ) -> Result<Self, InitializeError<SpiBus::Error>> {
+        let state = DeviceState::new(Manual::new(mac, ip, gateway, subnet));
+        let device = Self::from_parts(bus, state, interrupt_pin);
+
+        #[cfg(not(feature = "no-chip-version-assertion"))]
+        {
+            let mut version = [0u8];
+            device
+                .read(register::COMMON, common::VERSION, &mut version)
+                .await?;
+            if version[0] != 0x04 {
+                return Err(InitializeError::ChipNotConnected);
+            }
+        }
+
+        // Set RST common register of the w5500
+        device
+            .write(register::COMMON, common::MODE, &[0b10000000])
+            .await?;
+        device
+            .write(register::COMMON, common::MODE, &mode_options.to_register())
+            .await?;
+
+        device
+            .write(register::COMMON, common::MAC, &mac.octets)
+            .await?;
+        device
+            .write(register::COMMON, common::IP, &ip.octets())
+            .await?;
+        device
+            .write(register::COMMON, common::GATEWAY, &gateway.octets())
+            .await?;
+        device
+            .write(register::COMMON, common::SUBNET_MASK, &subnet.octets())
+            .await?;
+        device
+            .write(register::COMMON, common::SOCKET_INTERRUPT_MASK, &[0xFF])
+            .await?;
+
+        Ok(device)
+    }
+}
+
+impl<SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin>
+    AsyncDevice<SpiBus, StateImpl, IntPin>
+{
+    /// Drive an initialized chip, e.g. with the state [released](crate::Device::release) by a
+    /// [`Device`](crate::Device) that obtained an address with DHCP.
+    ///
+    /// This enables the interrupts of all sockets in SIMR. The interrupts of a socket (`Sn_IMR`)
+    /// are only enabled while an operation waits for them.
+    pub async fn new(
+        bus: SpiBus,
+        state: StateImpl,
+        interrupt_pin: IntPin,
+    ) -> Result<Self, SpiBus::Error> {
+        let device = Self::from_parts(bus, state, interrupt_pin);
+        device
+            .write(register::COMMON, common::SOCKET_INTERRUPT_MASK, &[0xFF])
+            .await?;
+        Ok(device)
+    }
+
+    fn from_parts(bus: SpiBus, state: StateImpl, interrupt_pin: IntPin) -> Self {
+        Self {
+            bus: RefCell::new(bus),
+            bus_waiters: Waiters::new(),
+            state: RefCell::new(state),
+            closing: Cell::new(0),
+            interrupt_pin: RefCell::new(Some(interrupt_pin)),
+            waiters: Waiters::new(),
+        }
+    }
+
+    /// Return the bus, the state and the pin. All sockets have to be dropped beforehand.
+    pub async fn release(self) -> Result<(SpiBus, StateImpl, IntPin), SpiBus::Error> {
+        // This closes the sockets dropped since the last access as well.
+        self.write(register::COMMON, common::SOCKET_INTERRUPT_MASK, &[0])
+            .await?;
+        let interrupt_pin = self
+            .interrupt_pin
+            .into_inner()
+            .expect("the pin is returned when the wait ends");
+        Ok((
+            self.bus.into_inner(),
+            self.state.into_inner(),
+            interrupt_pin,
+        ))
+    }
+
+    pub async fn ip(&self) -> Result<Ipv4Addr, SpiBus::Error> {
+        let mut octets = [0u8; 4];
+        self.read(register::COMMON, common::IP, &mut octets).await?;
+        Ok(Ipv4Addr::from(octets))
+    }
+
+    /// Start the ephemeral ports at a random offset into the dynamic range, see
+    /// [`Device::seed_ports`](crate::Device::seed_ports).
+    pub fn seed_ports(&self, rng: &mut impl rand_core::RngCore) {
+        self.state.borrow_mut().ports_mut().seed(rng);
+    }
+
+    /// The socket bitmap and the local ports of the sockets
+    pub(crate) fn state(&self) -> RefMut<'_, StateImpl> {
+        self.state.borrow_mut()
+    }
+
+    pub(crate) fn is_link_down(&self) -> bool {
+        self.state.borrow().link_up() == Some(false)
+    }
+
+    /// Release a socket that is dropped without closing it.
+    ///
+    /// Drop can't access the bus, so the socket is closed on the chip with the next access.
+    pub(crate) fn close_on_drop(&self, socket: &Socket) {
+        let mut closing = self.closing.get();
+        closing.set_bit(socket.index.into(), true);
+        self.closing.set(closing);
+        self.release_socket(socket);
+    }
+
+    pub(crate) fn release_socket(&self, socket: &Socket) {
+        self.state
+            .borrow_mut()
+            .release_socket(Socket::new(socket.index));
+    }
+
+    /// Wait until no other task is using the bus, then close the sockets dropped in the meantime.
+    async fn lock(&self) -> Result<BusGuard<'_, SpiBus>, SpiBus::Error> {
+        let mut bus = loop {
+            let generation = self.bus_waiters.generation();
+            if let Ok(bus) = self.bus.try_borrow_mut() {
+                break BusGuard {
+                    bus,
+                    waiters: &self.bus_waiters,
+                };
+            }
+            self.bus_waiters.wait(generation).await;
+        };
+
+        for index in 0..8 {
+            if self.closing.get().get_bit(index) {
+                let socket = Socket::new(index as u8);
+                let mode = [socketn::Protocol::Closed as u8];
+                bus.write_frame(socket.register(), socketn::MODE, &mode)
+                    .await?;
+                let command = [socketn::Command::Close as u8];
+                bus.write_frame(socket.register(), socketn::COMMAND, &command)
+                    .await?;
+                // Pending interrupts of a closed socket would keep INTn asserted.
+                let interrupts = [socketn::Interrupt::All as u8];
+                bus.write_frame(socket.register(), socketn::INTERRUPT, &interrupts)
+                    .await?;
+
+                let mut closing = self.closing.get();
+                closing.set_bit(index, false);
+                self.closing.set(closing);
+            }
+        }
+        Ok(bus)
+    }
+
+    pub(crate) async fn read(
+        &self,
+        block: u8,
+        address: u16,
+        data: &mut [u8],
+    ) -> Result<(), SpiBus::Error> {
+        let mut bus = self.lock().await?;
+        bus.read_frame(block, address, data).await
+    }
+
+    pub(crate) async fn write(
+        &self,
+        block: u8,
+        address: u16,
+        data: &[u8],
+    ) -> Result<(), SpiBus::Error> {
+        let mut bus = self.lock().await?;
+        bus.write_frame(block, address, data).await
+    }
+
+    pub(crate) async fn read_u8(&self, socket: &Socket, address: u16) -> Result<u8, SpiBus::Error> {
+        let mut data = [0u8];
+        self.read(socket.register(), address, &mut data).await?;
+        Ok(data[0])
+    }
+
+    pub(crate) async fn write_u8(
+        &self,
+        socket: &Socket,
+        address: u16,
+        value: u8,
+    ) -> Result<(), SpiBus::Error> {
+        self.write(socket.register(), address, &[value]).await
+    }
+
+    /// Read a 16 bit socket register.
+    ///
+    /// The size and pointer registers may change while they are read, so the read is repeated
+    /// until two sequential reads are stable (Section 4.2 of the datasheet).
+    pub(crate) async fn read_u16(
+        &self,
+        socket: &Socket,
+        address: u16,
+    ) -> Result<u16, SpiBus::Error> {
+        loop {
+            let mut sample_0 = [0u8; 2];
+            self.read(socket.register(), address, &mut sample_0).await?;
+            let mut sample_1 = [0u8; 2];
+            self.read(socket.register(), address, &mut sample_1).await?;
+            if sample_0 == sample_1 {
+                break Ok(u16::from_be_bytes(sample_0));
+            }
+        }
+    }
+
+    pub(crate) async fn write_u16(
+        &self,
+        socket: &Socket,
+        address: u16,
+        value: u16,
+    ) -> Result<(), SpiBus::Error> {
+        self.write(socket.register(), address, &value.to_be_bytes())
+            .await
+    }
+
+    pub(crate) async fn status(&self, socket: &Socket) -> Result<u8, SpiBus::Error> {
+        self.read_u8(socket, socketn::STATUS).await
+    }
+
+    pub(crate) async fn command(
+        &self,
+        socket: &Socket,
+        command: socketn::Command,
+    ) -> Result<(), SpiBus::Error> {
+        self.write_u8(socket, socketn::COMMAND, command as u8).await
+    }
+
+    pub(crate) async fn interrupts(&self, socket: &Socket) -> Result<u8, SpiBus::Error> {
+        self.read_u8(socket, socketn::INTERRUPT).await
+    }
+
+    /// Clear the interrupts in the bitmap `interrupts`.
+    pub(crate) async fn reset_interrupts(
+        &self,
+        socket: &Socket,
+        interrupts: u8,
+    ) -> Result<(), SpiBus::Error> {
+        self.write_u8(socket, socketn::INTERRUPT, interrupts).await
+    }
+
+    /// Close the socket, then open it in `mode` on `local_port` with its interrupts masked.
+    pub(crate) async fn open(
+        &self,
+        socket: &Socket,
+        mode: socketn::Protocol,
+        local_port: u16,
+    ) -> Result<(), SpiBus::Error> {
+        self.command(socket, socketn::Command::Close).await?;
+        self.reset_interrupts(socket, socketn::Interrupt::All as u8)
+            .await?;
+        self.write_u8(socket, socketn::INTERRUPT_MASK, 0).await?;
+        self.write_u16(socket, socketn::SOURCE_PORT, local_port)
+            .await?;
+        self.write_u8(socket, socketn::MODE, mode as u8).await?;
+        self.command(socket, socketn::Command::Open).await
+    }
+
+    pub(crate) async fn set_destination(
+        &self,
+        socket: &Socket,
+        remote: SocketAddrV4,
+    ) -> Result<(), SpiBus::Error> {
+        self.write(
+            socket.register(),
+            socketn::DESTINATION_IP,
+            &remote.ip().octets(),
+        )
+        .await?;
+        self.write_u16(socket, socketn::DESTINATION_PORT, remote.port())
+            .await
+    }
+
+    /// Copy `data` from the socket's RX buffer starting `offset` bytes after the read pointer.
+    /// Returns the read pointer after `offset + data.len()`.
+    pub(crate) async fn read_rx(
+        &self,
+        socket: &Socket,
+        offset: u16,
+        data: &mut [u8],
+    ) -> Result<u16, SpiBus::Error> {
+        let read_pointer = self
+            .read_u16(socket, socketn::RX_DATA_READ_POINTER)
+            .await?
+            .wrapping_add(offset);
+        self.read(socket.rx_buffer(), read_pointer, data).await?;
+        Ok(read_pointer.wrapping_add(data.len() as u16))
+    }
+
+    /// Hand the RX buffer up to `read_pointer` back to the chip.
+    pub(crate) async fn commit_rx(
+        &self,
+        socket: &Socket,
+        read_pointer: u16,
+    ) -> Result<(), SpiBus::Error> {
+        self.write_u16(socket, socketn::RX_DATA_READ_POINTER, read_pointer)
+            .await?;
+        self.command(socket, socketn::Command::Receive).await
+    }
+
+    /// Wait until one of `interrupts` may have been raised on `socket`.
+    ///
+    /// Callers clear the interrupts they wait for before checking the socket, so one raised
+    /// afterwards asserts INTn right away. The interrupts of a socket are only enabled while
+    /// waiting for them, so events nobody waits for don't keep INTn asserted.
+    pub(crate) async fn wait_for(
+        &self,
+        socket: &Socket,
+        interrupts: u8,
+    ) -> Result<(), SpiBus::Error> {
+        // Taken before enabling the interrupts, the task waiting on the pin may mask them again
+        // before this one starts waiting.
+        let generation = self.waiters.generation();
+        self.write_u8(socket, socketn::INTERRUPT_MASK, interrupts)
+            .await?;
+        self.wait_for_interrupt(generation).await
+    }
+
+    /// Wait until INTn is asserted, unless the task waiting on the pin returned since
+    /// `generation`.
+    ///
+    /// Only one task waits on the pin. It masks the sockets that raised an interrupt, their tasks
+    /// enable them again, and wakes the others.
+    async fn wait_for_interrupt(&self, generation: u32) -> Result<(), SpiBus::Error> {
+        if self.waiters.generation() != generation {
+            return Ok(());
+        }
+        let interrupt_pin = self.interrupt_pin.borrow_mut().take();
+        let Some(interrupt_pin) = interrupt_pin else {
+            self.waiters.wait(generation).await;
+            return Ok(());
+        };
+
+        let mut guard = PinGuard {
+            device: self,
+            interrupt_pin: Some(interrupt_pin),
+        };
+        if let Some(interrupt_pin) = guard.interrupt_pin.as_mut() {
+            interrupt_pin.wait().await;
+        }
+        let mut sockets = [0u8];
+        self.read(register::COMMON, common::SOCKET_INTERRUPT, &mut sockets)
+            .await?;
+        for index in 0..8 {
+            if sockets[0].get_bit(index) {
+                self.write_u8(&Socket::new(index as u8), socketn::INTERRUPT_MASK, 0)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Gives other tasks access to the bus once it's dropped.
+struct BusGuard<'a, SpiBus> {
+    bus: RefMut<'a, SpiBus>,
+    waiters: &'a Waiters,
+}
+
+impl<SpiBus> Deref for BusGuard<'_, SpiBus> {
+    type Target = SpiBus;
+
+    fn deref(&self) -> &SpiBus {
+        &self.bus
+    }
+}
+
+impl<SpiBus> DerefMut for BusGuard<'_, SpiBus> {
+    fn deref_mut(&mut self) -> &mut SpiBus {
+        &mut self.bus
+    }
+}
+
+impl<SpiBus> Drop for BusGuard<'_, SpiBus> {
+    fn drop(&mut self) {
+        self.waiters.wake_all();
+    }
+}
+
+/// Returns the pin to the device once the wait ends, even if the waiting future is dropped.
+struct PinGuard<'a, SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin> {
+    device: &'a AsyncDevice<SpiBus, StateImpl, IntPin>,
+    interrupt_pin: Option<IntPin>,
+}
+
+impl<SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin> Drop
+    for PinGuard<'_, SpiBus, StateImpl, IntPin>
+{
+    fn drop(&mut self) {
+        *self.device.interrupt_pin.borrow_mut() = self.interrupt_pin.take();
+        self.device.waiters.wake_all();
+    }
+}
+
+/// The number of tasks that can wait for the bus or an interrupt at the same time
+const MAX_WAITERS: usize = 8;
+
+/// The wakers of the tasks waiting for the bus or for the task that waits on the pin
+struct Waiters {
+    wakers: RefCell<[Option<Waker>; MAX_WAITERS]>,
+    /// Incremented by each [`Waiters::wake_all`]
+    generation: Cell<u32>,
+}
+
+impl Waiters {
+    const fn new() -> Self {
+        const NONE: Option<Waker> = None;
+        Self {
+            wakers: RefCell::new([NONE; MAX_WAITERS]),
+            generation: Cell::new(0),
+        }
+    }
+
+    fn generation(&self) -> u32 {
+        self.generation.get()
+    }
+
+    /// Wait for the first [`Waiters::wake_all`] after [`Waiters::generation`] returned
+    /// `generation`.
+    async fn wait(&self, generation: u32) {
+        poll_fn(|cx| {
+            if self.generation.get() != generation {
+                return Poll::Ready(());
+            }
+            let mut wakers = self.wakers.borrow_mut();
+            let slot = wakers
+                .iter()
+                .position(|waker| {
+                    waker
+                        .as_ref()
+                        .is_some_and(|waker| waker.will_wake(cx.waker()))
+                })
+                .or_else(|| wakers.iter().position(Option::is_none));
+            match slot {
+                Some(slot) => wakers[slot] = Some(cx.waker().clone()),
+                // Too many waiters, poll again instead.
+                None => cx.waker().wake_by_ref(),
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    fn wake_all(&self) {
+        self.generation.set(self.generation.get().wrapping_add(1));
+        let wakers = core::mem::take(&mut *self.wakers.borrow_mut());
+        IntoIterator::into_iter(wakers)
+            .flatten()
+            .for_each(Waker::wake);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::cell::RefCell;
+    use core::convert::Infallible;
+    use core::future::{poll_fn, Future};
+    use core::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    use embassy_futures::join::join;
+    use embedded_hal::spi::{ErrorType, Operation, SpiDevice};
+    use embedded_io_async::{Read, Write};
+    use embedded_nal::{nb, TcpClientStack, TcpFullStack};
+    use embedded_nal_async::{ConnectedUdp, TcpConnect, UdpStack, UnconnectedUdp};
+
+    use super::*;
+    use crate::bus::FourWire;
+    use crate::device::Device;
+    use crate::emulator::Emulator;
+    use crate::tcp::TcpSocketError;
+    use crate::udp::UdpSocketError;
+
+    const MAC: MacAddress = MacAddress {
+        octets: [0x02, 0x00, 0x00, 0x00, 0x00, 0x01],
+    };
+    const IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+    /// The emulator, waking the task waiting on INTn once it's asserted
+    struct Chip<'a>(&'a RefCell<Emulator>, &'a RefCell<Option<Waker>>);
+
+    impl Chip<'_> {
+        fn transfer(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+            self.0.borrow_mut().transaction(operations)?;
+            if self.0.borrow().interrupt_asserted() {
+                if let Some(waker) = self.1.borrow_mut().take() {
+                    waker.wake();
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl ErrorType for Chip<'_> {
+        type Error = Infallible;
+    }
+
+    impl SpiDevice for Chip<'_> {
+        fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+            self.transfer(operations)
+        }
+    }
+
+    impl embedded_hal_async::spi::SpiDevice for Chip<'_> {
+        async fn transaction(
+            &mut self,
+            operations: &mut [Operation<'_, u8>],
+        ) -> Result<(), Infallible> {
+            self.transfer(operations)
+        }
+    }
+
+    struct Int<'a>(&'a RefCell<Emulator>, &'a RefCell<Option<Waker>>);
+
+    impl embedded_hal::digital::ErrorType for Int<'_> {
+        type Error = Infallible;
+    }
+
+    impl embedded_hal_async::digital::Wait for Int<'_> {
+        async fn wait_for_high(&mut self) -> Result<(), Infallible> {
+            unreachable!("the driver only waits for INTn to be asserted")
+        }
+        async fn wait_for_low(&mut self) -> Result<(), Infallible> {
+            poll_fn(|cx| {
+                if self.0.borrow().interrupt_asserted() {
+                    Poll::Ready(Ok(()))
+                } else {
+                    *self.1.borrow_mut() = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            })
+            .await
+        }
+        async fn wait_for_rising_edge(&mut self) -> Result<(), Infallible> {
+            unreachable!("the driver only waits for INTn to be asserted")
+        }
+        async fn wait_for_falling_edge(&mut self) -> Result<(), Infallible> {
+            self.wait_for_low().await
+        }
+        async fn wait_for_any_edge(&mut self) -> Result<(), Infallible> {
+            self.wait_for_low().await
+        }
+    }
+
+    struct Woken(AtomicBool);
+
+    impl Wake for Woken {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Poll `future` only after it was woken, so a missing wake-up fails instead of hanging and
+    /// polling in a loop is caught by the limit.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let woken = Arc::new(Woken(AtomicBool::new(true)));
+        let waker = Waker::from(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        for _ in 0..100 {
+            assert!(woken.0.swap(false, Ordering::SeqCst), "not woken");
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+        panic!("polled too often");
+    }
+
+    #[test]
+    fn test_async_device() {
+        let emulator = RefCell::new(Emulator::new());
+        let waker = RefCell::new(None);
+        let device = block_on(AsyncDevice::initialize_manual(
+            FourWire::new(Chip(&emulator, &waker)),
+            MAC,
+            IP,
+            Mode::default(),
+            Int(&emulator, &waker),
+        ))
+        .unwrap();
+        // A blocking device plays the peer on the other sockets.
+        device.state().set_available_sockets(0x0F);
+        let mut state = DeviceState::new(Manual::new(
+            MAC,
+            IP,
+            Ipv4Addr::new(192, 168, 0, 1),
+            Ipv4Addr::new(255, 255, 255, 0),
+        ));
+        state.set_available_sockets(0xF0);
+        let mut peer = Device::new(FourWire::new(Chip(&emulator, &waker)), state);
+        let remote = SocketAddr::new(IpAddr::V4(IP), 80);
+
+        let mut listener = peer.socket().unwrap();
+        peer.bind(&mut listener, 80).unwrap();
+        peer.listen(&mut listener).unwrap();
+
+        // The read waits on INTn until the peer's data arrives.
+        let mut connection = block_on(device.connect(remote)).unwrap();
+        let (mut server, _) = peer.accept(&mut listener).unwrap();
+        let mut buffer = [0; 16];
+        let (received, ()) = block_on(join(connection.read(&mut buffer), async {
+            peer.send(&mut server, b"hello").unwrap();
+        }));
+        assert_eq!(&buffer[..received.unwrap()], b"hello");
+
+        block_on(async {
+            connection.write_all(b"ping").await?;
+            connection.flush().await
+        })
+        .unwrap();
+        let len = peer.receive(&mut server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"ping");
+
+        // Once the peer closed its side, reading returns 0.
+        let (received, ()) = block_on(join(connection.read(&mut buffer), async {
+            peer.close(server).unwrap();
+        }));
+        assert_eq!(received.unwrap(), 0);
+        block_on(connection.close()).unwrap();
+
+        // Closing sends the FIN.
+        let connection = block_on(device.connect(remote)).unwrap();
+        let (mut server, _) = peer.accept(&mut listener).unwrap();
+        block_on(connection.close()).unwrap();
+        assert_eq!(peer.receive(&mut server, &mut buffer).unwrap(), 0);
+        peer.close(server).unwrap();
+
+        // Dropping the connection closes it with the next access of the bus.
+        let connection = block_on(device.connect(remote)).unwrap();
+        let (mut server, _) = peer.accept(&mut listener).unwrap();
+        drop(connection);
+        // Nothing answers ARP for other addresses.
+        let unreachable = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 99)), 80);
+        assert!(matches!(
+            block_on(device.connect(unreachable)),
+            Err(TcpSocketError::Timeout)
+        ));
+        assert!(matches!(
+            peer.receive(&mut server, &mut buffer),
+            Err(nb::Error::Other(TcpSocketError::NotConnected))
+        ));
+        peer.close(server).unwrap();
+
+        // The chip gives up on unacknowledged data.
+        let mut connection = block_on(device.connect(remote)).unwrap();
+        let (server, _) = peer.accept(&mut listener).unwrap();
+        emulator.borrow_mut().set_link_up(false);
+        assert_eq!(block_on(connection.write(b"ping")).unwrap(), 4);
+        assert!(matches!(
+            block_on(connection.flush()),
+            Err(TcpSocketError::Timeout)
+        ));
+        emulator.borrow_mut().set_link_up(true);
+        drop(connection);
+        peer.close(server).unwrap();
+        peer.close(listener).unwrap();
+
+        let (mut sender, mut receiver) = block_on(async {
+            let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+            let (_, receiver) = (&device)
+                .bind_single(SocketAddr::new(IpAddr::V4(IP), 5000))
+                .await
+                .unwrap();
+            let (_, sender) = (&device)
+                .connect_from(local, SocketAddr::new(IpAddr::V4(IP), 5000))
+                .await
+                .unwrap();
+            (sender, receiver)
+        });
+        assert!(matches!(
+            block_on((&device).bind_single(SocketAddr::new(IpAddr::V4(IP), 5000))),
+            Err(UdpSocketError::PortInUse)
+        ));
+        // The receive waits on INTn until the datagram arrives.
+        let (received, sent) = block_on(join(
+            UnconnectedUdp::receive_into(&mut receiver, &mut buffer),
+            ConnectedUdp::send(&mut sender, b"ping"),
+        ));
+        sent.unwrap();
+        let (len, local, remote) = received.unwrap();
+        assert_eq!(&buffer[..len], b"ping");
+        assert_eq!(local, SocketAddr::new(IpAddr::V4(IP), 5000));
+        assert_eq!(remote, SocketAddr::V4(sender.local_addr()));
+        drop((sender, receiver));
+
+        // Events nobody waits for don't keep INTn asserted.
+        assert!(!emulator.borrow().interrupt_asserted());
+        let (_, state, _) = block_on(device.release()).unwrap();
+        assert!(!state.any_allocated());
+    }
+}
@@ -0,0 +1,406 @@
+use core::convert::TryFrom;
+use core::net::SocketAddr;
+
+use embedded_io_async::{ErrorType, Read, Write};
+use embedded_nal_async::TcpConnect;
+
+use crate::asynch::AsyncDevice;
+use crate::bus::AsyncBus;
+use crate::device::State;
+use crate::interrupt::AsyncInterruptPin;
+use crate::port::Transport;
+use crate::register::socketn;
+use crate::socket::Socket;
+use crate::tcp::TcpSocketError;
+
+/// A TCP connection of an [`AsyncDevice`].
+///
+/// Dropping the connection closes it without the FIN handshake once the device accesses the bus
+/// the next time, use [`TcpConnection::close`] for the FIN handshake.
+pub struct TcpConnection<'a, SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin> {
+    device: &'a AsyncDevice<SpiBus, StateImpl, IntPin>,
+    socket: Socket,
+    /// Whether a SEND command is in progress
+    sending: bool,
+    /// `Sn_TX_WR` when the SEND in progress was issued, it's complete once `Sn_TX_RD` gets there
+    send_end: u16,
+}
+
+impl<SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin>
+    TcpConnection<'_, SpiBus, StateImpl, IntPin>
+{
+    /// Close the connection with the FIN handshake and release the socket.
+    ///
+    /// Queued data is sent first. [`TcpSocketError::Timeout`] is returned if the chip's
+    /// retransmission timeout expired. A peer that never closes its side keeps this pending, drop
+    /// the future to close the connection immediately.
+    pub async fn close(mut self) -> Result<(), TcpSocketError<SpiBus::Error>> {
+        match self.flush().await {
+            Ok(()) => {
+                self.device
+                    .command(&self.socket, socketn::Command::Discon)
+                    .await?;
+                self.wait_closed().await?;
+            }
+            // The chip gave up on unacknowledged data and closed the socket.
+            Err(TcpSocketError::NotConnected) if self.take_timeout().await? => {
+                return Err(TcpSocketError::Timeout);
+            }
+            // Not connected (anymore), nothing to shut down.
+            Err(TcpSocketError::NotConnected) => {}
+            Err(error) => return Err(error),
+        }
+
+        self.device
+            .write_u8(&self.socket, socketn::MODE, socketn::Protocol::Closed as u8)
+            .await?;
+        self.device
+            .command(&self.socket, socketn::Command::Close)
+            .await?;
+        // Pending interrupts of a closed socket would keep INTn asserted.
+        self.device
+            .reset_interrupts(&self.socket, socketn::Interrupt::All as u8)
+            .await?;
+        self.device.release_socket(&self.socket);
+        // The socket is closed already.
+        core::mem::forget(self);
+        Ok(())
+    }
+
+    async fn status(&self) -> Result<Option<socketn::Status>, TcpSocketError<SpiBus::Error>> {
+        Ok(socketn::Status::try_from(self.device.status(&self.socket).await?).ok())
+    }
+
+    /// Wait for the FIN handshake started by DISCON to complete.
+    async fn wait_closed(&self) -> Result<(), TcpSocketError<SpiBus::Error>> {
+        loop {
+            self.device
+                .reset_interrupts(&self.socket, socketn::Interrupt::Disconnect as u8)
+                .await?;
+            // The socket is closed after a timeout as well.
+            if self.take_timeout().await? {
+                return Err(TcpSocketError::Timeout);
+            }
+            match self.status().await? {
+                // DISCON may not have been processed yet.
+                Some(socketn::Status::Established)
+                | Some(socketn::Status::CloseWait)
+                | Some(socketn::Status::FinWait)
+                | Some(socketn::Status::Closing)
+                | Some(socketn::Status::TimeWait)
+                | Some(socketn::Status::LastAck) => {}
+
+                _ => return Ok(()),
+            }
+            self.device
+                .wait_for(
+                    &self.socket,
+                    socketn::Interrupt::Disconnect as u8 | socketn::Interrupt::Timeout as u8,
+                )
+                .await?;
+        }
+    }
+
+    /// Check for and clear TIMEOUT, which the chip sets when it gives up on a connection.
+    async fn take_timeout(&self) -> Result<bool, TcpSocketError<SpiBus::Error>> {
+        let timeout = socketn::Interrupt::Timeout as u8;
+        let timed_out = self.device.interrupts(&self.socket).await? & timeout != 0;
+        if timed_out {
+            self.device.reset_interrupts(&self.socket, timeout).await?;
+        }
+        Ok(timed_out)
+    }
+
+    /// Clear DISCON and check whether data can be sent, i.e. the connection is established and
+    /// no FIN was sent yet. DISCON raised afterwards ends a wait for the chip.
+    async fn is_connected(&mut self) -> Result<bool, TcpSocketError<SpiBus::Error>> {
+        self.device
+            .reset_interrupts(&self.socket, socketn::Interrupt::Disconnect as u8)
+            .await?;
+        // A timed out connection is closed already, so this comes first.
+        self.poll_send().await?;
+        Ok(matches!(
+            self.status().await?,
+            Some(socketn::Status::Established) | Some(socketn::Status::CloseWait)
+        ))
+    }
+
+    /// Check whether the SEND command in progress completed.
+    async fn poll_send(&mut self) -> Result<(), TcpSocketError<SpiBus::Error>> {
+        if !self.sending {
+            return Ok(());
+        }
+        if self.take_timeout().await? {
+            // The peer didn't acknowledge the data, e.g. because the link is down. The chip gives
+            // up and closes the socket.
+            self.sending = false;
+            return Err(TcpSocketError::Timeout);
+        }
+        let read_pointer = self
+            .device
+            .read_u16(&self.socket, socketn::TX_DATA_READ_POINTER)
+            .await?;
+        if read_pointer == self.send_end {
+            // Clear SEND_OK, the next wait would end right away otherwise.
+            self.device
+                .reset_interrupts(&self.socket, socketn::Interrupt::SendOk as u8)
+                .await?;
+            self.sending = false;
+        }
+        Ok(())
+    }
+
+    /// Issue SEND for the queued data, unless a SEND is still in progress.
+    async fn start_send(&mut self) -> Result<(), TcpSocketError<SpiBus::Error>> {
+        if self.sending {
+            return Ok(());
+        }
+        let write_pointer = self
+            .device
+            .read_u16(&self.socket, socketn::TX_DATA_WRITE_POINTER)
+            .await?;
+        let read_pointer = self
+            .device
+            .read_u16(&self.socket, socketn::TX_DATA_READ_POINTER)
+            .await?;
+        if read_pointer == write_pointer {
+            return Ok(());
+        }
+        self.device
+            .command(&self.socket, socketn::Command::Send)
+            .await?;
+        self.sending = true;
+        self.send_end = write_pointer;
+        Ok(())
+    }
+
+    /// Wait for the SEND in progress to complete or the connection to end.
+    async fn wait_for_send(&self) -> Result<(), TcpSocketError<SpiBus::Error>> {
+        self.device
+            .wait_for(
+                &self.socket,
+                socketn::Interrupt::SendOk as u8
+                    | socketn::Interrupt::Timeout as u8
+                    | socketn::Interrupt::Disconnect as u8,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+impl<SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin> Drop
+    for TcpConnection<'_, SpiBus, StateImpl, IntPin>
+{
+    fn drop(&mut self) {
+        self.device.close_on_drop(&self.socket);
+    }
+}
+
+impl<SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin> ErrorType
+    for TcpConnection<'_, SpiBus, StateImpl, IntPin>
+{
+    type Error = TcpSocketError<SpiBus::Error>;
+}
+
+impl<SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin> Read
+    for TcpConnection<'_, SpiBus, StateImpl, IntPin>
+{
+    /// Wait for data to be received. Returns `Ok(0)` once the peer closed the connection and all
+    /// data has been read.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            // Cleared before checking the size, so data arriving afterwards ends the wait.
+            self.device
+                .reset_interrupts(
+                    &self.socket,
+                    socketn::Interrupt::Receive as u8 | socketn::Interrupt::Disconnect as u8,
+                )
+                .await?;
+            let peer_closed = match self.status().await? {
+                Some(socketn::Status::Established) => false,
+                // The peer sent its FIN, the data received before can still be read.
+                Some(socketn::Status::CloseWait) => true,
+                _ => return Err(TcpSocketError::NotConnected),
+            };
+
+            let received = self
+                .device
+                .read_u16(&self.socket, socketn::RECEIVED_SIZE)
+                .await?;
+            if received > 0 {
+                let len = buf.len().min(usize::from(received));
+                let read_pointer = self
+                    .device
+                    .read_rx(&self.socket, 0, &mut buf[..len])
+                    .await?;
+                self.device.commit_rx(&self.socket, read_pointer).await?;
+                return Ok(len);
+            }
+            if peer_closed {
+                return Ok(0);
+            }
+
+            self.device
+                .wait_for(
+                    &self.socket,
+                    socketn::Interrupt::Receive as u8
+                        | socketn::Interrupt::Disconnect as u8
+                        | socketn::Interrupt::Timeout as u8,
+                )
+                .await?;
+        }
+    }
+}
+
+impl<SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin> Write
+    for TcpConnection<'_, SpiBus, StateImpl, IntPin>
+{
+    /// Queue as much data as fits into the TX buffer, waiting while it's full.
+    ///
+    /// Only one SEND command may be in progress at a time. Data queued while the previous SEND is
+    /// in progress is sent by a later call or by [`Write::flush`].
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if !self.is_connected().await? {
+                return Err(TcpSocketError::NotConnected);
+            }
+
+            let free_size = self
+                .device
+                .read_u16(&self.socket, socketn::TX_FREE_SIZE)
+                .await?;
+            if free_size > 0 {
+                let data = &buf[..buf.len().min(usize::from(free_size))];
+                let write_pointer = self
+                    .device
+                    .read_u16(&self.socket, socketn::TX_DATA_WRITE_POINTER)
+                    .await?;
+                self.device
+                    .write(self.socket.tx_buffer(), write_pointer, data)
+                    .await?;
+                self.device
+                    .write_u16(
+                        &self.socket,
+                        socketn::TX_DATA_WRITE_POINTER,
+                        write_pointer.wrapping_add(data.len() as u16),
+                    )
+                    .await?;
+                self.start_send().await?;
+                return Ok(data.len());
+            }
+
+            // The TX buffer frees up as the SEND in progress completes.
+            self.start_send().await?;
+            self.wait_for_send().await?;
+        }
+    }
+
+    /// Wait until the peer acknowledged all queued data.
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        loop {
+            if !self.is_connected().await? {
+                return Err(TcpSocketError::NotConnected);
+            }
+            self.start_send().await?;
+            if !self.sending {
+                // The chip frees the TX buffer once the peer acknowledged the data.
+                let buffer_size = self
+                    .device
+                    .read_u8(&self.socket, socketn::TXBUF_SIZE)
+                    .await?;
+                let free_size = self
+                    .device
+                    .read_u16(&self.socket, socketn::TX_FREE_SIZE)
+                    .await?;
+                if usize::from(free_size) == usize::from(buffer_size) * 1024 {
+                    return Ok(());
+                }
+            }
+            self.wait_for_send().await?;
+        }
+    }
+}
+
+impl<SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin> TcpConnect
+    for AsyncDevice<SpiBus, StateImpl, IntPin>
+{
+    type Error = TcpSocketError<SpiBus::Error>;
+    type Connection<'a>
+        = TcpConnection<'a, SpiBus, StateImpl, IntPin>
+    where
+        Self: 'a;
+
+    async fn connect<'a>(
+        &'a self,
+        remote: SocketAddr,
+    ) -> Result<Self::Connection<'a>, Self::Error> {
+        let SocketAddr::V4(remote) = remote else {
+            return Err(TcpSocketError::UnsupportedAddress);
+        };
+        if self.is_link_down() {
+            return Err(TcpSocketError::LinkDown);
+        }
+
+        let (socket, local_port) = {
+            let mut state = self.state();
+            let socket = state.socket().ok_or(TcpSocketError::NoMoreSockets)?;
+            let local_port = state.ports_mut().allocate(socket.index, Transport::Tcp);
+            (socket, local_port)
+        };
+        // Dropping the connection on errors closes and releases the socket.
+        let connection = TcpConnection {
+            device: self,
+            socket,
+            sending: false,
+            send_end: 0,
+        };
+        let socket = &connection.socket;
+
+        self.open(socket, socketn::Protocol::Tcp, local_port)
+            .await?;
+        self.set_destination(socket, remote).await?;
+        self.command(socket, socketn::Command::Connect).await?;
+
+        loop {
+            match connection.status().await? {
+                // The peer may already have closed its side again, data can still be read.
+                Some(socketn::Status::Established) | Some(socketn::Status::CloseWait) => {
+                    self.reset_interrupts(socket, socketn::Interrupt::Connect as u8)
+                        .await?;
+                    return Ok(connection);
+                }
+
+                // The CONNECT command may not have been processed yet.
+                Some(socketn::Status::Init) | Some(socketn::Status::SynSent) => {}
+
+                // The socket is closed if the ARP request or the SYN timed out, or if the peer
+                // answered with a RST.
+                Some(socketn::Status::Closed) => {
+                    return Err(if connection.take_timeout().await? {
+                        TcpSocketError::Timeout
+                    } else {
+                        TcpSocketError::ConnectionRefused
+                    });
+                }
+
+                _ => return Err(TcpSocketError::UnsupportedMode),
+            }
+
+            self.wait_for(
+                socket,
+                socketn::Interrupt::Connect as u8
+                    | socketn::Interrupt::Disconnect as u8
+                    | socketn::Interrupt::Timeout as u8,
+            )
+            .await?;
+        }
+    }
+}
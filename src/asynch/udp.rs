@@ -0,0 +1,297 @@
+use core::convert::TryFrom;
+use core::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use embedded_nal_async::{ConnectedUdp, UdpStack, UnconnectedUdp};
+
+use crate::asynch::AsyncDevice;
+use crate::bus::AsyncBus;
+use crate::device::State;
+use crate::interrupt::AsyncInterruptPin;
+use crate::port::Transport;
+use crate::register::socketn;
+use crate::socket::Socket;
+use crate::udp::{UdpHeader, UdpSocketError};
+
+/// A UDP socket of an [`AsyncDevice`].
+///
+/// Created by [`UdpStack::connect`] for a fixed remote, or by [`UdpStack::bind_single`] and
+/// [`UdpStack::bind_multiple`] (as [`BoundUdpSocket`]) to exchange datagrams with any remote.
+/// The socket is returned to the device when it is dropped and closed with the device's next
+/// access of the bus.
+pub struct AsyncUdpSocket<'a, SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin> {
+    device: &'a AsyncDevice<SpiBus, StateImpl, IntPin>,
+    socket: Socket,
+    local: SocketAddrV4,
+    remote: Option<SocketAddrV4>,
+}
+
+/// A UDP socket that is only bound to a local port
+pub type BoundUdpSocket<'a, SpiBus, StateImpl, IntPin> =
+    AsyncUdpSocket<'a, SpiBus, StateImpl, IntPin>;
+
+impl<'a, SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin>
+    AsyncUdpSocket<'a, SpiBus, StateImpl, IntPin>
+{
+    /// Open a socket on the port of `local`, or on an ephemeral port if it's `0`.
+    async fn open(
+        device: &'a AsyncDevice<SpiBus, StateImpl, IntPin>,
+        local: SocketAddr,
+    ) -> Result<Self, UdpSocketError<SpiBus::Error>> {
+        let SocketAddr::V4(local) = local else {
+            return Err(UdpSocketError::UnsupportedAddress);
+        };
+
+        let (socket, port) = {
+            let mut state = device.state();
+            let socket = state.socket().ok_or(UdpSocketError::NoMoreSockets)?;
+            let port = match local.port() {
+                0 => Some(state.ports_mut().allocate(socket.index, Transport::Udp)),
+                port if state.ports_mut().bind(socket.index, Transport::Udp, port) => Some(port),
+                _ => None,
+            };
+            (socket, port)
+        };
+        let Some(port) = port else {
+            device.release_socket(&socket);
+            return Err(UdpSocketError::PortInUse);
+        };
+        // Dropping the socket on errors closes and releases it.
+        let mut udp = Self {
+            device,
+            socket,
+            local: SocketAddrV4::new(*local.ip(), port),
+            remote: None,
+        };
+
+        device
+            .open(&udp.socket, socketn::Protocol::Udp, port)
+            .await?;
+        if local.ip().is_unspecified() {
+            udp.local.set_ip(device.ip().await?);
+        }
+        Ok(udp)
+    }
+
+    /// The local address the socket is bound to
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.local
+    }
+
+    async fn set_destination(
+        &mut self,
+        remote: SocketAddrV4,
+    ) -> Result<(), UdpSocketError<SpiBus::Error>> {
+        if self.remote != Some(remote) {
+            self.device.set_destination(&self.socket, remote).await?;
+            self.remote = Some(remote);
+        }
+        Ok(())
+    }
+
+    async fn check_open(&self) -> Result<(), UdpSocketError<SpiBus::Error>> {
+        match socketn::Status::try_from(self.device.status(&self.socket).await?) {
+            Ok(socketn::Status::Udp) => Ok(()),
+            Ok(_) => Err(UdpSocketError::SocketNotOpen),
+            Err(_) => Err(UdpSocketError::UnrecognisedStatus),
+        }
+    }
+
+    /// Send a datagram to the current destination and wait until the chip sent it.
+    async fn send_datagram(&mut self, data: &[u8]) -> Result<(), UdpSocketError<SpiBus::Error>> {
+        if self.device.is_link_down() {
+            return Err(UdpSocketError::LinkDown);
+        }
+        self.check_open().await?;
+
+        let send_ok = socketn::Interrupt::SendOk as u8;
+        let timeout = socketn::Interrupt::Timeout as u8;
+        let mut sent = 0;
+        while sent < data.len() {
+            let free_size = self
+                .device
+                .read_u16(&self.socket, socketn::TX_FREE_SIZE)
+                .await?;
+            // The previous SEND completed, so this only happens if something else used the socket.
+            if free_size == 0 {
+                return Err(UdpSocketError::BufferFull);
+            }
+            let chunk = &data[sent..data.len().min(sent + usize::from(free_size))];
+            let write_pointer = self
+                .device
+                .read_u16(&self.socket, socketn::TX_DATA_WRITE_POINTER)
+                .await?;
+            self.device
+                .write(self.socket.tx_buffer(), write_pointer, chunk)
+                .await?;
+            self.device
+                .write_u16(
+                    &self.socket,
+                    socketn::TX_DATA_WRITE_POINTER,
+                    write_pointer.wrapping_add(chunk.len() as u16),
+                )
+                .await?;
+            self.device
+                .reset_interrupts(&self.socket, send_ok | timeout)
+                .await?;
+            self.device
+                .command(&self.socket, socketn::Command::Send)
+                .await?;
+            sent += chunk.len();
+
+            loop {
+                let interrupts = self.device.interrupts(&self.socket).await?;
+                if interrupts & send_ok != 0 {
+                    self.device.reset_interrupts(&self.socket, send_ok).await?;
+                    break;
+                }
+                if interrupts & timeout != 0 {
+                    // The ARP request for the destination wasn't answered.
+                    self.device.reset_interrupts(&self.socket, timeout).await?;
+                    return Err(UdpSocketError::WriteTimeout);
+                }
+                self.device
+                    .wait_for(&self.socket, send_ok | timeout)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for a datagram. Datagrams larger than `buffer` are truncated.
+    async fn receive_datagram(
+        &mut self,
+        buffer: &mut [u8],
+    ) -> Result<(usize, SocketAddrV4), UdpSocketError<SpiBus::Error>> {
+        let receive = socketn::Interrupt::Receive as u8;
+        loop {
+            self.check_open().await?;
+            // Cleared before checking the size, so a datagram arriving afterwards ends the wait.
+            self.device.reset_interrupts(&self.socket, receive).await?;
+            let received = self
+                .device
+                .read_u16(&self.socket, socketn::RECEIVED_SIZE)
+                .await?;
+            // Nothing to read until at least a complete header has been received.
+            if received >= 8 {
+                let mut header = [0u8; 8];
+                self.device.read_rx(&self.socket, 0, &mut header).await?;
+                let header = UdpHeader::from_array(header);
+
+                let packet_size = header.len.min(usize::from(received) - 8);
+                let len = packet_size.min(buffer.len());
+                self.device
+                    .read_rx(&self.socket, 8, &mut buffer[..len])
+                    .await?;
+
+                // Skip anything of the packet that didn't fit into the buffer.
+                let read_pointer = self
+                    .device
+                    .read_u16(&self.socket, socketn::RX_DATA_READ_POINTER)
+                    .await?
+                    .wrapping_add(8 + packet_size as u16);
+                self.device.commit_rx(&self.socket, read_pointer).await?;
+
+                return Ok((len, header.origin));
+            }
+            self.device.wait_for(&self.socket, receive).await?;
+        }
+    }
+}
+
+impl<SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin> Drop
+    for AsyncUdpSocket<'_, SpiBus, StateImpl, IntPin>
+{
+    fn drop(&mut self) {
+        self.device.close_on_drop(&self.socket);
+    }
+}
+
+impl<SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin> ConnectedUdp
+    for AsyncUdpSocket<'_, SpiBus, StateImpl, IntPin>
+{
+    type Error = UdpSocketError<SpiBus::Error>;
+
+    async fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        if self.remote.is_none() {
+            return Err(UdpSocketError::DestinationNotSet);
+        }
+        self.send_datagram(data).await
+    }
+
+    /// Receive a datagram. Datagrams from other remotes than the connected one are accepted as
+    /// well, the chip does not filter them.
+    async fn receive_into(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        let (len, _) = self.receive_datagram(buffer).await?;
+        Ok(len)
+    }
+}
+
+impl<SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin> UnconnectedUdp
+    for AsyncUdpSocket<'_, SpiBus, StateImpl, IntPin>
+{
+    type Error = UdpSocketError<SpiBus::Error>;
+
+    async fn send(
+        &mut self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        data: &[u8],
+    ) -> Result<(), Self::Error> {
+        let (SocketAddr::V4(local), SocketAddr::V4(remote)) = (local, remote) else {
+            return Err(UdpSocketError::UnsupportedAddress);
+        };
+        if local.port() != self.local.port()
+            || !(local.ip().is_unspecified() || *local.ip() == *self.local.ip())
+        {
+            return Err(UdpSocketError::UnsupportedAddress);
+        }
+
+        self.set_destination(remote).await?;
+        self.send_datagram(data).await
+    }
+
+    async fn receive_into(
+        &mut self,
+        buffer: &mut [u8],
+    ) -> Result<(usize, SocketAddr, SocketAddr), Self::Error> {
+        let (len, remote) = self.receive_datagram(buffer).await?;
+        Ok((len, SocketAddr::V4(self.local), SocketAddr::V4(remote)))
+    }
+}
+
+impl<'a, SpiBus: AsyncBus, StateImpl: State, IntPin: AsyncInterruptPin> UdpStack
+    for &'a AsyncDevice<SpiBus, StateImpl, IntPin>
+{
+    type Error = UdpSocketError<SpiBus::Error>;
+    type Connected = AsyncUdpSocket<'a, SpiBus, StateImpl, IntPin>;
+    type UniquelyBound = BoundUdpSocket<'a, SpiBus, StateImpl, IntPin>;
+    type MultiplyBound = BoundUdpSocket<'a, SpiBus, StateImpl, IntPin>;
+
+    async fn connect_from(
+        &self,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> Result<(SocketAddr, Self::Connected), Self::Error> {
+        let SocketAddr::V4(remote) = remote else {
+            return Err(UdpSocketError::UnsupportedAddress);
+        };
+        let mut socket = AsyncUdpSocket::open(self, local).await?;
+        socket.set_destination(remote).await?;
+        Ok((SocketAddr::V4(socket.local), socket))
+    }
+
+    async fn bind_single(
+        &self,
+        local: SocketAddr,
+    ) -> Result<(SocketAddr, Self::UniquelyBound), Self::Error> {
+        let socket = AsyncUdpSocket::open(self, local).await?;
+        Ok((SocketAddr::V4(socket.local), socket))
+    }
+
+    /// The W5500 only has a single IP address, so this is equivalent to
+    /// [`bind_single`](UdpStack::bind_single) with an unspecified address.
+    async fn bind_multiple(&self, local: SocketAddr) -> Result<Self::MultiplyBound, Self::Error> {
+        let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), local.port());
+        AsyncUdpSocket::open(self, local).await
+    }
+}
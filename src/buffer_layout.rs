@@ -0,0 +1,169 @@
+use core::fmt;
+
+use crate::bus::Bus;
+use crate::error::ErrorKind;
+use crate::register;
+use crate::socket::Socket;
+
+/// Total RX and TX buffer memory of the chip, in KB
+const TOTAL_KB: u8 = 16;
+
+/// Allocation of the chip's 16 KB RX and 16 KB TX buffer memory to its 8 sockets.
+///
+/// Each socket gets 0, 1, 2, 4, 8 or 16 KB per direction and the sizes of each direction must not
+/// add up to more than 16 KB. Sockets without RX or TX memory can't be used and are not handed
+/// out by the [`Device`](crate::Device). Sockets are handed out in ascending order, so put the
+/// larger buffers on the first sockets and open the socket that needs them first.
+///
+/// # Example
+///
+/// ```
+/// use w5500::BufferLayout;
+///
+/// // 8 KB for a bulk-transfer socket, the rest is shared by four smaller sockets.
+/// let layout = BufferLayout::new([8, 2, 2, 2, 2, 0, 0, 0], [8, 2, 2, 2, 2, 0, 0, 0]).unwrap();
+/// assert_eq!(layout.socket_count(), 5);
+///
+/// assert!(BufferLayout::new([16, 1, 0, 0, 0, 0, 0, 0], [2; 8]).is_err());
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BufferLayout {
+    rx: [u8; 8],
+    tx: [u8; 8],
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BufferLayoutError {
+    /// A socket was given a size other than 0, 1, 2, 4, 8 or 16 KB
+    InvalidSize,
+    /// The RX sizes add up to more than 16 KB
+    RxTooLarge,
+    /// The TX sizes add up to more than 16 KB
+    TxTooLarge,
+}
+
+impl BufferLayoutError {
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::InvalidInput
+    }
+}
+
+impl fmt::Display for BufferLayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BufferLayoutError::InvalidSize => "buffer size must be 0, 1, 2, 4, 8 or 16 KB",
+            BufferLayoutError::RxTooLarge => "RX buffers exceed 16 KB",
+            BufferLayoutError::TxTooLarge => "TX buffers exceed 16 KB",
+        })
+    }
+}
+
+impl core::error::Error for BufferLayoutError {}
+
+impl BufferLayout {
+    /// The layout after reset: 2 KB RX and 2 KB TX for each of the 8 sockets
+    pub const UNIFORM: BufferLayout = BufferLayout {
+        rx: [2; 8],
+        tx: [2; 8],
+    };
+
+    /// Create a layout from the RX and TX sizes in KB of sockets 0 to 7.
+    pub fn new(rx: [u8; 8], tx: [u8; 8]) -> Result<Self, BufferLayoutError> {
+        if rx.iter().chain(tx.iter()).any(|size| !is_valid_size(*size)) {
+            return Err(BufferLayoutError::InvalidSize);
+        }
+        if rx.iter().sum::<u8>() > TOTAL_KB {
+            return Err(BufferLayoutError::RxTooLarge);
+        }
+        if tx.iter().sum::<u8>() > TOTAL_KB {
+            return Err(BufferLayoutError::TxTooLarge);
+        }
+        Ok(Self { rx, tx })
+    }
+
+    /// Spread the buffer memory evenly across `sockets` sockets (1, 2, 4 or 8), leaving the
+    /// others without memory.
+    pub fn evenly(sockets: u8) -> Result<Self, BufferLayoutError> {
+        if !matches!(sockets, 1 | 2 | 4 | 8) {
+            return Err(BufferLayoutError::InvalidSize);
+        }
+        let mut sizes = [0u8; 8];
+        sizes[..usize::from(sockets)].fill(TOTAL_KB / sockets);
+        Self::new(sizes, sizes)
+    }
+
+    /// The RX buffer size of socket `index` in KB
+    pub fn rx_size(&self, index: u8) -> u8 {
+        self.rx[usize::from(index)]
+    }
+
+    /// The TX buffer size of socket `index` in KB
+    pub fn tx_size(&self, index: u8) -> u8 {
+        self.tx[usize::from(index)]
+    }
+
+    /// The number of sockets with both RX and TX memory
+    pub fn socket_count(&self) -> u8 {
+        self.sockets().count_ones() as u8
+    }
+
+    /// Bitmap of the usable sockets
+    pub(crate) fn sockets(&self) -> u8 {
+        (0..8)
+            .filter(|&index| self.rx[index] > 0 && self.tx[index] > 0)
+            .fold(0, |sockets, index| sockets | (1 << index))
+    }
+
+    pub(crate) fn write<SpiBus: Bus>(&self, bus: &mut SpiBus) -> Result<(), SpiBus::Error> {
+        for index in 0..8u8 {
+            let socket = Socket::new(index);
+            bus.write_frame(
+                socket.register(),
+                register::socketn::RXBUF_SIZE,
+                &[self.rx_size(index)],
+            )?;
+            bus.write_frame(
+                socket.register(),
+                register::socketn::TXBUF_SIZE,
+                &[self.tx_size(index)],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for BufferLayout {
+    fn default() -> Self {
+        Self::UNIFORM
+    }
+}
+
+fn is_valid_size(size: u8) -> bool {
+    matches!(size, 0 | 1 | 2 | 4 | 8 | 16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validates_layout() {
+        assert_eq!(BufferLayout::UNIFORM.socket_count(), 8);
+        assert_eq!(BufferLayout::evenly(2).unwrap().rx_size(1), 8);
+        assert_eq!(BufferLayout::evenly(2).unwrap().sockets(), 0b11);
+        assert_eq!(
+            BufferLayout::new([3, 0, 0, 0, 0, 0, 0, 0], [2; 8]),
+            Err(BufferLayoutError::InvalidSize)
+        );
+        assert_eq!(
+            BufferLayout::new([2; 8], [8, 8, 1, 0, 0, 0, 0, 0]),
+            Err(BufferLayoutError::TxTooLarge)
+        );
+
+        // A socket without TX memory can't be used.
+        let layout = BufferLayout::new([8, 4, 4, 0, 0, 0, 0, 0], [8, 4, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(layout.socket_count(), 2);
+    }
+}
@@ -54,6 +54,47 @@ impl<SPI: SpiDevice> Bus for FourWire<SPI> {
     }
 }
 
+#[cfg(feature = "async")]
+impl<SPI: embedded_hal_async::spi::SpiDevice> crate::bus::AsyncBus for FourWire<SPI> {
+    type Error = <SPI as ErrorType>::Error;
+
+    async fn read_frame(
+        &mut self,
+        block: u8,
+        address: u16,
+        data: &mut [u8],
+    ) -> Result<(), SPI::Error> {
+        let address_phase = address.to_be_bytes();
+        let control_phase = block << 3;
+
+        self.spi
+            .transaction(&mut [
+                Operation::Write(&address_phase),
+                Operation::Write(&[control_phase]),
+                Operation::TransferInPlace(data),
+            ])
+            .await
+    }
+
+    async fn write_frame(
+        &mut self,
+        block: u8,
+        address: u16,
+        data: &[u8],
+    ) -> Result<(), SPI::Error> {
+        let control_phase = (block << 3) | WRITE_MODE_MASK;
+
+        let address_phase = address.to_be_bytes();
+        self.spi
+            .transaction(&mut [
+                Operation::Write(&address_phase),
+                Operation::Write(&[control_phase]),
+                Operation::Write(data),
+            ])
+            .await
+    }
+}
+
 #[cfg(test)]
 mod test {
     use embedded_hal_mock::eh1::spi::{Mock as SpiMock, Transaction as SpiTransaction};
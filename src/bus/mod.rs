@@ -14,3 +14,24 @@ pub trait Bus {
 
     fn write_frame(&mut self, block: u8, address: u16, data: &[u8]) -> Result<(), Self::Error>;
 }
+
+/// Async counterpart of [`Bus`], used by [`AsyncDevice`](crate::asynch::AsyncDevice).
+#[cfg(feature = "async")]
+#[allow(async_fn_in_trait)]
+pub trait AsyncBus {
+    type Error: Debug;
+
+    async fn read_frame(
+        &mut self,
+        block: u8,
+        address: u16,
+        data: &mut [u8],
+    ) -> Result<(), Self::Error>;
+
+    async fn write_frame(
+        &mut self,
+        block: u8,
+        address: u16,
+        data: &[u8],
+    ) -> Result<(), Self::Error>;
+}
@@ -4,8 +4,9 @@ use core::fmt;
 use embedded_hal::spi::{ErrorType, Operation, SpiBus};
 
 use crate::bus::Bus;
+use crate::error::ErrorKind;
 
-const WRITE_MODE_MASK: u8 = 0b00000_1_0;
+const WRITE_MODE_MASK: u8 = 0b00000_1_00;
 
 const FIXED_DATA_LENGTH_MODE_1: u8 = 0b000000_01;
 const FIXED_DATA_LENGTH_MODE_2: u8 = 0b000000_10;
@@ -28,6 +29,17 @@ impl<SPI> ThreeWire<SPI> {
     }
 }
 
+/// The operation mode bits and length of the next chunk of a frame with `len` bytes left
+fn fixed_data_length(len: usize) -> (u8, u16) {
+    if len >= 4 {
+        (FIXED_DATA_LENGTH_MODE_4, 4)
+    } else if len >= 2 {
+        (FIXED_DATA_LENGTH_MODE_2, 2)
+    } else {
+        (FIXED_DATA_LENGTH_MODE_1, 1)
+    }
+}
+
 impl<SPI: SpiBus> Bus for ThreeWire<SPI> {
     type Error = <SPI as ErrorType>::Error;
 
@@ -48,21 +60,12 @@ impl<SPI: SpiBus> Bus for ThreeWire<SPI> {
         mut address: u16,
         data: &mut [u8],
     ) -> Result<(), Self::Error> {
-        let mut control_phase = block << 3;
+        let control_phase = block << 3;
 
         let mut data_phase = data;
-        let mut last_length_written: u16;
         while !data_phase.is_empty() {
-            if data_phase.len() >= 4 {
-                control_phase |= FIXED_DATA_LENGTH_MODE_4;
-                last_length_written = 4;
-            } else if data_phase.len() >= 2 {
-                control_phase |= FIXED_DATA_LENGTH_MODE_2;
-                last_length_written = 2;
-            } else {
-                control_phase |= FIXED_DATA_LENGTH_MODE_1;
-                last_length_written = 1;
-            }
+            let (mode, last_length_written) = fixed_data_length(data_phase.len());
+            let control_phase = control_phase | mode;
 
             let address_phase = address.to_be_bytes();
             self.spi
@@ -71,28 +74,19 @@ impl<SPI: SpiBus> Bus for ThreeWire<SPI> {
             self.spi
                 .transfer_in_place(&mut data_phase[..last_length_written as usize])?;
 
-            address += last_length_written;
+            address = address.wrapping_add(last_length_written);
             data_phase = &mut data_phase[last_length_written as usize..];
         }
         Ok(())
     }
 
     fn write_frame(&mut self, block: u8, mut address: u16, data: &[u8]) -> Result<(), Self::Error> {
-        let mut control_phase = (block << 3) | WRITE_MODE_MASK;
+        let control_phase = (block << 3) | WRITE_MODE_MASK;
 
         let mut data_phase = data;
-        let mut last_length_written: u16;
         while !data_phase.is_empty() {
-            if data_phase.len() >= 4 {
-                control_phase |= FIXED_DATA_LENGTH_MODE_4;
-                last_length_written = 4;
-            } else if data_phase.len() >= 2 {
-                control_phase |= FIXED_DATA_LENGTH_MODE_2;
-                last_length_written = 2;
-            } else {
-                control_phase |= FIXED_DATA_LENGTH_MODE_1;
-                last_length_written = 1;
-            }
+            let (mode, last_length_written) = fixed_data_length(data_phase.len());
+            let control_phase = control_phase | mode;
 
             let address_phase = address.to_be_bytes();
             self.spi
@@ -100,14 +94,18 @@ impl<SPI: SpiBus> Bus for ThreeWire<SPI> {
                 .and_then(|_| self.spi.write(&[control_phase]))
                 .and_then(|_| self.spi.write(&data_phase[..last_length_written as usize]))?;
 
-            address += last_length_written;
+            address = address.wrapping_add(last_length_written);
             data_phase = &data_phase[last_length_written as usize..];
         }
         Ok(())
     }
 }
 
+/// Distinguishes errors of SPI transfers and writes.
+///
+/// Not returned by [`ThreeWire`], which reports the errors of its [`SpiBus`] as they are.
 // Must use map_err, ambiguity prevents From from being implemented
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ThreeWireError<TransferError, WriteError> {
     TransferError(TransferError),
     WriteError(WriteError),
@@ -125,3 +123,67 @@ impl<TransferError, WriteError> fmt::Debug for ThreeWireError<TransferError, Wri
         )
     }
 }
+
+impl<TransferError, WriteError> fmt::Display for ThreeWireError<TransferError, WriteError> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::TransferError(_) => "SPI transfer failed",
+            Self::WriteError(_) => "SPI write failed",
+        })
+    }
+}
+
+impl<TransferError, WriteError> core::error::Error for ThreeWireError<TransferError, WriteError> {}
+
+impl<TransferError, WriteError> ThreeWireError<TransferError, WriteError> {
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::Bus
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use embedded_hal_mock::eh1::spi::{Mock as SpiMock, Transaction as SpiTransaction};
+
+    use crate::bus::Bus;
+
+    use super::ThreeWire;
+
+    #[test]
+    fn test_write_frame() {
+        let data = [1, 2, 3, 4, 5, 6, 7];
+        let expectations = [
+            SpiTransaction::write_vec(vec![0x00, 0x0C]),
+            SpiTransaction::write(0b00001_1_11),
+            SpiTransaction::write_vec(data[..4].to_vec()),
+            SpiTransaction::write_vec(vec![0x00, 0x10]),
+            SpiTransaction::write(0b00001_1_10),
+            SpiTransaction::write_vec(data[4..6].to_vec()),
+            SpiTransaction::write_vec(vec![0x00, 0x12]),
+            SpiTransaction::write(0b00001_1_01),
+            SpiTransaction::write_vec(data[6..].to_vec()),
+        ];
+
+        let mut three_wire = ThreeWire::new(SpiMock::new(&expectations));
+        three_wire.write_frame(0x01, 0x0C, &data).unwrap();
+        three_wire.release().done();
+    }
+
+    #[test]
+    fn test_read_frame() {
+        let expectations = [
+            SpiTransaction::write_vec(vec![0x00, 0x26]),
+            SpiTransaction::write(0b00001_0_10),
+            SpiTransaction::transfer_in_place(vec![0, 0], vec![0x12, 0x34]),
+            SpiTransaction::write_vec(vec![0x00, 0x28]),
+            SpiTransaction::write(0b00001_0_01),
+            SpiTransaction::transfer_in_place(vec![0], vec![0x56]),
+        ];
+
+        let mut three_wire = ThreeWire::new(SpiMock::new(&expectations));
+        let mut data = [0u8; 3];
+        three_wire.read_frame(0x01, 0x26, &mut data).unwrap();
+        assert_eq!(data, [0x12, 0x34, 0x56]);
+        three_wire.release().done();
+    }
+}
@@ -1,8 +1,15 @@
+use core::fmt::{self, Debug};
+
 use bit_field::BitField;
 
+use crate::buffer_layout::BufferLayout;
 use crate::bus::{Bus, FourWire, ThreeWire};
+use crate::error::ErrorKind;
 use crate::host::Host;
+use crate::interrupt::{InterruptPin, NoPin};
 use crate::net::Ipv4Addr;
+use crate::port::PortAllocator;
+use crate::register::common::{PhyDuplexStatus, PhySpeedStatus};
 use crate::socket::Socket;
 use crate::uninitialized_device::UninitializedDevice;
 use crate::{
@@ -17,12 +24,84 @@ pub enum ResetError<E> {
     Other(E),
 }
 
+impl<E: Debug> ResetError<E> {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ResetError::SocketsNotReleased => ErrorKind::Other,
+            ResetError::Other(_) => ErrorKind::Bus,
+        }
+    }
+}
+
+impl<E: Debug> fmt::Display for ResetError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResetError::SocketsNotReleased => f.write_str("sockets not released before reset"),
+            ResetError::Other(error) => write!(f, "{}: {:?}", ErrorKind::Bus, error),
+        }
+    }
+}
+
+impl<E: Debug> core::error::Error for ResetError<E> {}
+
 impl<E> From<E> for ResetError<E> {
     fn from(error: E) -> ResetError<E> {
         ResetError::Other(error)
     }
 }
 
+/// Why [`Device::set_buffer_layout`] failed
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SetBufferLayoutError<E> {
+    /// A socket is still in use, its buffered data would be lost
+    SocketsInUse,
+    Other(E),
+}
+
+impl<E: Debug> SetBufferLayoutError<E> {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            SetBufferLayoutError::SocketsInUse => ErrorKind::Other,
+            SetBufferLayoutError::Other(_) => ErrorKind::Bus,
+        }
+    }
+}
+
+impl<E: Debug> fmt::Display for SetBufferLayoutError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SetBufferLayoutError::SocketsInUse => {
+                f.write_str("sockets not released before changing the buffer layout")
+            }
+            SetBufferLayoutError::Other(error) => write!(f, "{}: {:?}", ErrorKind::Bus, error),
+        }
+    }
+}
+
+impl<E: Debug> core::error::Error for SetBufferLayoutError<E> {}
+
+impl<E> From<E> for SetBufferLayoutError<E> {
+    fn from(error: E) -> SetBufferLayoutError<E> {
+        SetBufferLayoutError::Other(error)
+    }
+}
+
+/// A change of the Ethernet link, see [`Device::poll_link`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LinkEvent {
+    /// The link is up with the negotiated (or forced) speed and duplex mode
+    Up {
+        #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+        speed: PhySpeedStatus,
+        #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+        duplex: PhyDuplexStatus,
+    },
+    /// The link is down, e.g. because the cable was unplugged
+    Down,
+}
+
 mod private {
     pub trait Sealed {}
 
@@ -30,9 +109,21 @@ mod private {
 }
 
 pub trait State: private::Sealed {
+    type Host: Host;
+
+    fn host(&self) -> &Self::Host;
+    fn host_mut(&mut self) -> &mut Self::Host;
     fn socket(&mut self) -> Option<Socket>;
     fn release_socket(&mut self, socket: Socket);
     fn any_allocated(&self) -> bool;
+    /// Restrict the sockets handed out to the bitmap `sockets`. Only called while no socket is
+    /// allocated.
+    fn set_available_sockets(&mut self, sockets: u8);
+    /// The link state seen by the last [`Device::poll_link`], `None` if it was never polled.
+    fn link_up(&self) -> Option<bool>;
+    fn set_link_up(&mut self, up: bool);
+    /// The local ports of the sockets, released together with the socket.
+    fn ports_mut(&mut self) -> &mut PortAllocator;
 }
 
 #[derive(Debug)]
@@ -40,12 +131,18 @@ pub trait State: private::Sealed {
 pub struct DeviceState<HostImpl: Host> {
     host: HostImpl,
     sockets: u8,
+    available: u8,
+    link_up: Option<bool>,
+    ports: PortAllocator,
 }
 
 impl<HostImpl: Host> DeviceState<HostImpl> {
     pub fn new(host: HostImpl) -> Self {
         Self {
             sockets: 0xFF,
+            available: 0xFF,
+            link_up: None,
+            ports: PortAllocator::new(),
             host,
         }
     }
@@ -54,6 +151,16 @@ impl<HostImpl: Host> DeviceState<HostImpl> {
 impl<HostImpl: Host> private::Sealed for DeviceState<HostImpl> {}
 
 impl<HostImpl: Host> State for DeviceState<HostImpl> {
+    type Host = HostImpl;
+
+    fn host(&self) -> &HostImpl {
+        &self.host
+    }
+
+    fn host_mut(&mut self) -> &mut HostImpl {
+        &mut self.host
+    }
+
     fn socket(&mut self) -> Option<Socket> {
         for index in 0..8 {
             if self.sockets.get_bit(index) {
@@ -65,15 +172,43 @@ impl<HostImpl: Host> State for DeviceState<HostImpl> {
     }
 
     fn release_socket(&mut self, socket: Socket) {
+        self.ports.release(socket.index);
         self.sockets.set_bit(socket.index.into(), true);
     }
 
     fn any_allocated(&self) -> bool {
-        self.sockets != 0xFF
+        self.sockets != self.available
+    }
+
+    fn set_available_sockets(&mut self, sockets: u8) {
+        self.sockets = sockets;
+        self.available = sockets;
+    }
+
+    fn link_up(&self) -> Option<bool> {
+        self.link_up
+    }
+
+    fn set_link_up(&mut self, up: bool) {
+        self.link_up = Some(up);
+    }
+
+    fn ports_mut(&mut self) -> &mut PortAllocator {
+        &mut self.ports
     }
 }
 
 impl<T: State> State for &'_ mut T {
+    type Host = T::Host;
+
+    fn host(&self) -> &Self::Host {
+        T::host(self)
+    }
+
+    fn host_mut(&mut self) -> &mut Self::Host {
+        T::host_mut(self)
+    }
+
     fn socket(&mut self) -> Option<Socket> {
         T::socket(self)
     }
@@ -85,20 +220,39 @@ impl<T: State> State for &'_ mut T {
     fn any_allocated(&self) -> bool {
         T::any_allocated(self)
     }
+
+    fn set_available_sockets(&mut self, sockets: u8) {
+        T::set_available_sockets(self, sockets)
+    }
+
+    fn link_up(&self) -> Option<bool> {
+        T::link_up(self)
+    }
+
+    fn set_link_up(&mut self, up: bool) {
+        T::set_link_up(self, up)
+    }
+
+    fn ports_mut(&mut self) -> &mut PortAllocator {
+        T::ports_mut(self)
+    }
 }
 
-pub struct Device<SpiBus: Bus, StateImpl: State> {
+pub struct Device<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin = NoPin> {
     pub(crate) bus: SpiBus,
-    state: StateImpl,
+    pub(crate) state: StateImpl,
+    pub(crate) interrupt_pin: IntPin,
 }
 
+/// Resetting, releasing or deactivating the device requires to release the interrupt pin first,
+/// see [`Device::release_interrupt_pin`].
 impl<SpiBus: Bus, StateImpl: State> Device<SpiBus, StateImpl> {
     pub(crate) fn new(bus: SpiBus, state: StateImpl) -> Self {
-        Device { bus, state }
-    }
-
-    pub fn get_state(&self) -> &StateImpl {
-        &self.state
+        Device {
+            bus,
+            state,
+            interrupt_pin: NoPin,
+        }
     }
 
     pub fn reset(mut self) -> Result<UninitializedDevice<SpiBus>, ResetError<SpiBus::Error>> {
@@ -114,6 +268,42 @@ impl<SpiBus: Bus, StateImpl: State> Device<SpiBus, StateImpl> {
         (self.bus, self.state)
     }
 
+    pub fn deactivate(self) -> (SpiBus, InactiveDevice<StateImpl>) {
+        (self.bus, InactiveDevice(self.state))
+    }
+}
+
+impl<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin> Device<SpiBus, StateImpl, IntPin> {
+    pub fn get_state(&self) -> &StateImpl {
+        &self.state
+    }
+
+    /// The host configuration, e.g. to query the DNS servers announced via DHCP
+    pub fn host(&self) -> &StateImpl::Host {
+        self.state.host()
+    }
+
+    /// Start the ephemeral ports of TCP connections and UDP sockets at a random offset into the
+    /// dynamic range (49152 to 65535).
+    ///
+    /// Without a seed, the ports are the same after each reset, so a reconnecting client may
+    /// reuse the ports of a connection the server still considers open.
+    pub fn seed_ports(&mut self, rng: &mut impl rand_core::RngCore) {
+        self.state.ports_mut().seed(rng);
+    }
+
+    pub(crate) fn replace_interrupt_pin<Pin: InterruptPin>(
+        self,
+        interrupt_pin: Pin,
+    ) -> (Device<SpiBus, StateImpl, Pin>, IntPin) {
+        let device = Device {
+            bus: self.bus,
+            state: self.state,
+            interrupt_pin,
+        };
+        (device, self.interrupt_pin)
+    }
+
     pub fn take_socket(&mut self) -> Option<Socket> {
         // TODO maybe return Future that resolves when release_socket invoked
         self.state.socket()
@@ -123,6 +313,22 @@ impl<SpiBus: Bus, StateImpl: State> Device<SpiBus, StateImpl> {
         self.state.release_socket(socket)
     }
 
+    /// Reallocate the RX and TX buffer memory of the sockets.
+    ///
+    /// All sockets must be released, the chip discards the buffered data of every socket.
+    /// Afterwards only the sockets with memory in `layout` are handed out.
+    pub fn set_buffer_layout(
+        &mut self,
+        layout: BufferLayout,
+    ) -> Result<(), SetBufferLayoutError<SpiBus::Error>> {
+        if self.state.any_allocated() {
+            return Err(SetBufferLayoutError::SocketsInUse);
+        }
+        layout.write(&mut self.bus)?;
+        self.state.set_available_sockets(layout.sockets());
+        Ok(())
+    }
+
     pub fn gateway(&mut self) -> Result<Ipv4Addr, SpiBus::Error> {
         let mut octets = [0u8; 4];
         self.bus
@@ -158,6 +364,76 @@ impl<SpiBus: Bus, StateImpl: State> Device<SpiBus, StateImpl> {
         Ok(phy[0].into())
     }
 
+    /// Check the link state and report when it changed since the last call.
+    ///
+    /// This should be called periodically from the main loop. The first call reports the
+    /// current state. Changes after that are passed on to the host (see
+    /// [`Host::on_link_change`]), and while the link is down sockets fail with a `LinkDown`
+    /// error instead of waiting forever.
+    pub fn poll_link(&mut self) -> Result<Option<LinkEvent>, SpiBus::Error> {
+        let phy = self.phy_config()?;
+        let up = phy.link_up();
+        let previous = self.state.link_up();
+        if previous == Some(up) {
+            return Ok(None);
+        }
+
+        self.state.set_link_up(up);
+        if previous.is_some() {
+            self.state.host_mut().on_link_change(up);
+        }
+
+        Ok(Some(if up {
+            LinkEvent::Up {
+                speed: phy.speed(),
+                duplex: phy.duplex(),
+            }
+        } else {
+            LinkEvent::Down
+        }))
+    }
+
+    /// Whether the link was down at the last [`Device::poll_link`]
+    pub(crate) fn is_link_down(&self) -> bool {
+        self.state.link_up() == Some(false)
+    }
+
+    /// Configure the PHY to operate in `mode`, overriding the PMODE pins.
+    ///
+    /// As required by the datasheet, the configuration is written first and then applied with a
+    /// PHY reset. All links are dropped while the PHY resets. Use
+    /// [`PhyOperationMode::PowerDown`](register::common::PhyOperationMode::PowerDown) to power
+    /// down the PHY, or one of the auto-negotiation modes to (re)start auto-negotiation.
+    pub fn set_phy_config(
+        &mut self,
+        mode: register::common::PhyOperationMode,
+    ) -> Result<(), SpiBus::Error> {
+        let config = register::common::PhyConfig::with_operation_mode(mode);
+        self.bus.write_frame(
+            register::COMMON,
+            register::common::PHY_CONFIG,
+            &config.to_register(),
+        )?;
+        self.reset_phy()
+    }
+
+    /// Reset the PHY, keeping its operation mode configuration.
+    ///
+    /// This drops the link and restarts auto-negotiation if it is enabled.
+    pub fn reset_phy(&mut self) -> Result<(), SpiBus::Error> {
+        let config = self.phy_config()?;
+        self.bus.write_frame(
+            register::COMMON,
+            register::common::PHY_CONFIG,
+            &config.with_reset(true).to_register(),
+        )?;
+        self.bus.write_frame(
+            register::COMMON,
+            register::common::PHY_CONFIG,
+            &config.with_reset(false).to_register(),
+        )
+    }
+
     #[inline]
     pub fn reset_device(&mut self) -> Result<(), SpiBus::Error> {
         // Set RST common register of the w5500
@@ -274,10 +550,6 @@ impl<SpiBus: Bus, StateImpl: State> Device<SpiBus, StateImpl> {
 
         Ok(retry_count_register[0])
     }
-
-    pub fn deactivate(self) -> (SpiBus, InactiveDevice<StateImpl>) {
-        (self.bus, InactiveDevice(self.state))
-    }
 }
 
 #[derive(Debug)]
@@ -287,13 +559,87 @@ pub struct InactiveDevice<StateImpl: State>(StateImpl);
 impl<StateImpl: State> InactiveDevice<StateImpl> {
     /// Activates the device by taking ownership
     pub fn activate<SpiBus: Bus>(self, bus: SpiBus) -> Device<SpiBus, StateImpl> {
-        Device { bus, state: self.0 }
+        Device::new(bus, self.0)
     }
 
     pub fn activate_ref<SpiBus: Bus>(&mut self, bus: SpiBus) -> Device<SpiBus, &mut StateImpl> {
-        Device {
-            bus,
-            state: &mut self.0,
-        }
+        Device::new(bus, &mut self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::net::{IpAddr, SocketAddr};
+
+    use embedded_nal::{nb, TcpClientStack, TcpFullStack, UdpClientStack};
+
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::tcp::TcpSocketError;
+    use crate::udp::UdpSocketError;
+
+    #[test]
+    fn test_poll_link() {
+        let ip = Ipv4Addr::new(192, 168, 0, 2);
+        let mut device = UninitializedDevice::new(Emulator::new())
+            .initialize_manual(MacAddress::new(2, 0, 0, 0, 0, 1), ip, Mode::default())
+            .unwrap();
+        let up = LinkEvent::Up {
+            speed: PhySpeedStatus::Mbps100,
+            duplex: PhyDuplexStatus::FullDuplex,
+        };
+
+        // The first poll reports the current state, later ones only changes.
+        assert_eq!(device.poll_link().unwrap(), Some(up));
+        assert_eq!(device.poll_link().unwrap(), None);
+
+        let mut listener = TcpClientStack::socket(&mut device).unwrap();
+        device.bind(&mut listener, 80).unwrap();
+        device.listen(&mut listener).unwrap();
+        let mut client = TcpClientStack::socket(&mut device).unwrap();
+        let remote = SocketAddr::new(IpAddr::V4(ip), 80);
+        nb::block!(TcpClientStack::connect(&mut device, &mut client, remote)).unwrap();
+        let (mut server, _) = nb::block!(device.accept(&mut listener)).unwrap();
+        nb::block!(TcpClientStack::send(&mut device, &mut client, b"before")).unwrap();
+        let mut udp = UdpClientStack::socket(&mut device).unwrap();
+        UdpClientStack::connect(&mut device, &mut udp, remote).unwrap();
+
+        device.bus.set_link_up(false);
+        // Sockets only notice once the link was polled.
+        assert_eq!(device.poll_link().unwrap(), Some(LinkEvent::Down));
+        assert_eq!(device.poll_link().unwrap(), None);
+
+        let mut buffer = [0; 16];
+        assert!(matches!(
+            TcpClientStack::send(&mut device, &mut client, b"after"),
+            Err(nb::Error::Other(TcpSocketError::LinkDown))
+        ));
+        // Data received before the link went down can still be read.
+        let len = TcpClientStack::receive(&mut device, &mut server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"before");
+        assert!(matches!(
+            TcpClientStack::receive(&mut device, &mut server, &mut buffer),
+            Err(nb::Error::Other(TcpSocketError::LinkDown))
+        ));
+        let mut other = TcpClientStack::socket(&mut device).unwrap();
+        assert!(matches!(
+            TcpClientStack::connect(&mut device, &mut other, remote),
+            Err(nb::Error::Other(TcpSocketError::LinkDown))
+        ));
+        assert!(matches!(
+            UdpClientStack::send(&mut device, &mut udp, b"after"),
+            Err(nb::Error::Other(UdpSocketError::LinkDown))
+        ));
+
+        device.bus.set_link_up(true);
+        assert_eq!(device.poll_link().unwrap(), Some(up));
+        nb::block!(TcpClientStack::send(&mut device, &mut client, b"again")).unwrap();
+        let len = nb::block!(TcpClientStack::receive(
+            &mut device,
+            &mut server,
+            &mut buffer
+        ))
+        .unwrap();
+        assert_eq!(&buffer[..len], b"again");
     }
 }
@@ -0,0 +1,834 @@
+//! DNS resolver for A records.
+//!
+//! The [`DnsResolver`] keeps the server list, a small TTL-aware cache and the query in flight.
+//! It is used through [`Device::dns`], which implements [`embedded_nal::Dns`]:
+//!
+//! ```no_run
+//! # use core::net::Ipv4Addr;
+//! # use embedded_nal::{AddrType, Dns};
+//! # fn now() -> u64 { 0 }
+//! # fn example<SpiBus: w5500::bus::Bus>(
+//! #     device: &mut w5500::Device<SpiBus, w5500::DeviceState<w5500::Dhcp>>,
+//! # ) {
+//! let mut resolver = w5500::dns::DnsResolver::new();
+//! resolver.set_servers(device.host().dns_servers());
+//!
+//! let address = nb::block!(device
+//!     .dns(&mut resolver, now())
+//!     .get_host_by_name("example.com", AddrType::IPv4));
+//! # }
+//! ```
+use core::fmt::{self, Debug};
+use core::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+
+use embedded_nal::{nb, AddrType};
+
+use crate::bus::Bus;
+use crate::device::{Device, State};
+use crate::error::{ErrorKind, UNSUPPORTED_ADDRESS};
+use crate::interrupt::{InterruptPin, NoPin};
+use crate::port::Transport;
+use crate::udp::{NbError, UdpSocket, UdpSocketError};
+
+const DNS_PORT: u16 = 53;
+
+/// Maximum number of DNS servers that can be configured
+pub const MAX_SERVERS: usize = 3;
+
+/// Number of cached host names
+pub const CACHE_SIZE: usize = 4;
+
+/// Maximum length of a host name, without the trailing dot (RFC 1035, section 2.3.4)
+pub const MAX_HOSTNAME_LEN: usize = 253;
+
+/// Time to wait for a response before retransmitting the query
+const QUERY_TIMEOUT_MS: u64 = 2_000;
+
+/// Number of queries sent to each server before moving on to the next one
+const ATTEMPTS_PER_SERVER: u8 = 2;
+
+/// Size of the fixed DNS message header
+const HEADER_LEN: usize = 12;
+
+/// Largest DNS message over UDP without EDNS (RFC 1035, section 4.2.1)
+const MAX_MESSAGE_LEN: usize = 512;
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DnsError<E: Debug> {
+    /// No DNS server has been configured
+    NoServers,
+    NoMoreSockets,
+    /// Only IPv4 (A record) lookups are supported
+    UnsupportedAddress,
+    /// The host name is empty, too long or contains an invalid label
+    InvalidHostname,
+    /// The name does not exist or has no A record
+    NotFound,
+    /// None of the servers answered in time
+    Timeout,
+    Socket(UdpSocketError<E>),
+}
+
+impl<E: Debug> DnsError<E> {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            DnsError::NoServers => ErrorKind::Other,
+            DnsError::NoMoreSockets => ErrorKind::NoMoreSockets,
+            DnsError::UnsupportedAddress | DnsError::InvalidHostname => ErrorKind::InvalidInput,
+            DnsError::NotFound => ErrorKind::NotFound,
+            DnsError::Timeout => ErrorKind::TimedOut,
+            DnsError::Socket(error) => error.kind(),
+        }
+    }
+}
+
+impl<E: Debug> fmt::Display for DnsError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsError::NoServers => f.write_str("no DNS server configured"),
+            DnsError::UnsupportedAddress => f.write_str(UNSUPPORTED_ADDRESS),
+            DnsError::InvalidHostname => f.write_str("invalid host name"),
+            DnsError::Timeout => f.write_str("no DNS server answered"),
+            DnsError::Socket(error) => fmt::Display::fmt(error, f),
+            error => fmt::Display::fmt(&error.kind(), f),
+        }
+    }
+}
+
+impl<E: Debug> core::error::Error for DnsError<E> {}
+
+impl<E: Debug> From<UdpSocketError<E>> for DnsError<E> {
+    fn from(error: UdpSocketError<E>) -> Self {
+        DnsError::Socket(error)
+    }
+}
+
+impl<E: Debug> From<E> for DnsError<E> {
+    fn from(error: E) -> Self {
+        DnsError::Socket(UdpSocketError::Other(error))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Hostname {
+    bytes: [u8; MAX_HOSTNAME_LEN],
+    len: usize,
+}
+
+impl Hostname {
+    fn new(name: &str) -> Option<Self> {
+        // A trailing dot denotes the root and is implied
+        let name = name.strip_suffix('.').unwrap_or(name);
+        if name.is_empty()
+            || name.len() > MAX_HOSTNAME_LEN
+            || name
+                .split('.')
+                .any(|label| label.is_empty() || label.len() > 63)
+        {
+            return None;
+        }
+
+        let mut bytes = [0u8; MAX_HOSTNAME_LEN];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Some(Self {
+            bytes,
+            len: name.len(),
+        })
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    fn matches(&self, other: &Hostname) -> bool {
+        self.as_bytes().eq_ignore_ascii_case(other.as_bytes())
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct CacheEntry {
+    name: Hostname,
+    address: Ipv4Addr,
+    expires_at: u64,
+}
+
+#[derive(Debug)]
+struct Query {
+    socket: UdpSocket,
+    name: Hostname,
+    id: u16,
+    server: usize,
+    attempts: u8,
+    sent_at: u64,
+}
+
+/// State of the DNS resolver: configured servers, cached answers and the pending query.
+///
+/// While a query is in flight, a socket is taken from the device. It is released again once the
+/// query completed or failed.
+#[derive(Debug)]
+pub struct DnsResolver {
+    servers: [Option<Ipv4Addr>; MAX_SERVERS],
+    cache: [Option<CacheEntry>; CACHE_SIZE],
+    query: Option<Query>,
+    next_id: u16,
+}
+
+impl Default for DnsResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DnsResolver {
+    pub const fn new() -> Self {
+        Self {
+            servers: [None; MAX_SERVERS],
+            cache: [None; CACHE_SIZE],
+            query: None,
+            next_id: 0,
+        }
+    }
+
+    /// Replace the server list. Servers are tried in order, any beyond [`MAX_SERVERS`] are ignored.
+    ///
+    /// The servers announced via DHCP (option 6) are available from
+    /// [`Dhcp::dns_servers`](crate::Dhcp::dns_servers).
+    pub fn set_servers(&mut self, servers: impl IntoIterator<Item = Ipv4Addr>) {
+        self.servers = [None; MAX_SERVERS];
+        for (slot, server) in self.servers.iter_mut().zip(servers) {
+            *slot = Some(server);
+        }
+    }
+
+    /// The configured servers, in the order they are tried
+    pub fn servers(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.servers.iter().flatten().copied()
+    }
+
+    /// Remove all cached answers
+    pub fn clear_cache(&mut self) {
+        self.cache = [None; CACHE_SIZE];
+    }
+
+    fn lookup(&self, name: &Hostname, now: u64) -> Option<Ipv4Addr> {
+        self.cache
+            .iter()
+            .flatten()
+            .find(|entry| entry.expires_at > now && entry.name.matches(name))
+            .map(|entry| entry.address)
+    }
+
+    fn insert(&mut self, name: Hostname, address: Ipv4Addr, ttl: u32, now: u64) {
+        let entry = CacheEntry {
+            name,
+            address,
+            expires_at: now.saturating_add(u64::from(ttl) * 1000),
+        };
+        // Replace an entry for the same name, or the one expiring first
+        let slot = match self
+            .cache
+            .iter()
+            .position(|slot| matches!(slot, Some(cached) if cached.name.matches(&name)))
+        {
+            Some(index) => &mut self.cache[index],
+            None => self
+                .cache
+                .iter_mut()
+                .min_by_key(|slot| slot.map(|cached| cached.expires_at).unwrap_or(0))
+                .expect("cache is not empty"),
+        };
+        *slot = Some(entry);
+    }
+
+    fn resolve<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin>(
+        &mut self,
+        device: &mut Device<SpiBus, StateImpl, IntPin>,
+        hostname: &str,
+        now: u64,
+    ) -> nb::Result<Ipv4Addr, DnsError<SpiBus::Error>> {
+        if let Ok(address) = hostname.parse::<Ipv4Addr>() {
+            return Ok(address);
+        }
+
+        let name = Hostname::new(hostname).ok_or(nb::Error::Other(DnsError::InvalidHostname))?;
+        if let Some(address) = self.lookup(&name, now) {
+            return Ok(address);
+        }
+
+        match &self.query {
+            Some(query) if query.name.matches(&name) => {}
+            _ => self.start(device, name, now)?,
+        }
+
+        self.poll(device, now)
+    }
+
+    fn start<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin>(
+        &mut self,
+        device: &mut Device<SpiBus, StateImpl, IntPin>,
+        name: Hostname,
+        now: u64,
+    ) -> Result<(), DnsError<SpiBus::Error>> {
+        // Abandon a query for a different name
+        self.finish(device)?;
+
+        if self.servers[0].is_none() {
+            return Err(DnsError::NoServers);
+        }
+
+        let socket = device.take_socket().ok_or(DnsError::NoMoreSockets)?;
+        let port = device
+            .state
+            .ports_mut()
+            .allocate(socket.index, Transport::Udp);
+        let mut socket = UdpSocket::new(socket, port);
+        if let Err(error) = socket.open(&mut device.bus) {
+            device.release_socket(socket.socket);
+            return Err(error.into());
+        }
+
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut query = Query {
+            socket,
+            name,
+            id: self.next_id ^ (now as u16).rotate_left(7),
+            server: 0,
+            attempts: 0,
+            sent_at: now,
+        };
+        let result = self.send(device, &mut query, now);
+        self.query = Some(query);
+        if let Err(error) = result {
+            self.finish(device)?;
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Send the query to the current server, or to the next servers if sending to it fails.
+    /// Returns the last error if the query couldn't be sent to any of them.
+    fn send<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin>(
+        &self,
+        device: &mut Device<SpiBus, StateImpl, IntPin>,
+        query: &mut Query,
+        now: u64,
+    ) -> Result<(), DnsError<SpiBus::Error>> {
+        loop {
+            match self.send_once(device, query, now) {
+                Ok(()) => return Ok(()),
+                // The bus fails for every server alike.
+                Err(error @ DnsError::Socket(UdpSocketError::Other(_))) => return Err(error),
+                Err(error) => {
+                    query.server += 1;
+                    query.attempts = 0;
+                    if query.server >= MAX_SERVERS || self.servers[query.server].is_none() {
+                        return Err(error);
+                    }
+                }
+            }
+        }
+    }
+
+    fn send_once<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin>(
+        &self,
+        device: &mut Device<SpiBus, StateImpl, IntPin>,
+        query: &mut Query,
+        now: u64,
+    ) -> Result<(), DnsError<SpiBus::Error>> {
+        let server = self.servers[query.server].ok_or(DnsError::NoServers)?;
+        let mut buffer = [0u8; HEADER_LEN + MAX_HOSTNAME_LEN + 6];
+        let len = encode_query(&mut buffer, query.id, &query.name);
+
+        query.attempts += 1;
+        query.sent_at = now;
+        match query.socket.socket_send_to(
+            &mut device.bus,
+            &mut device.interrupt_pin,
+            SocketAddrV4::new(server, DNS_PORT),
+            &buffer[..len],
+        ) {
+            Ok(()) | Err(NbError::WouldBlock) => Ok(()),
+            Err(NbError::Other(error)) => Err(error.into()),
+        }
+    }
+
+    fn poll<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin>(
+        &mut self,
+        device: &mut Device<SpiBus, StateImpl, IntPin>,
+        now: u64,
+    ) -> nb::Result<Ipv4Addr, DnsError<SpiBus::Error>> {
+        let Some(mut query) = self.query.take() else {
+            return Err(nb::Error::WouldBlock);
+        };
+
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        let mut retry = false;
+        loop {
+            match query.socket.socket_receive(&mut device.bus, &mut buffer) {
+                Ok((len, header)) => {
+                    if header.origin.port() != DNS_PORT {
+                        continue;
+                    }
+                    match parse_response(&buffer[..len], query.id) {
+                        Some(Answer::Address(address, ttl)) => {
+                            self.insert(query.name, address, ttl, now);
+                            self.query = Some(query);
+                            self.finish(device)?;
+                            return Ok(address);
+                        }
+                        Some(Answer::NotFound) => {
+                            self.query = Some(query);
+                            self.finish(device)?;
+                            return Err(nb::Error::Other(DnsError::NotFound));
+                        }
+                        Some(Answer::ServerFailure) => {
+                            // Give up on this server right away
+                            query.attempts = ATTEMPTS_PER_SERVER;
+                            retry = true;
+                        }
+                        None => {}
+                    }
+                }
+                Err(NbError::WouldBlock) => break,
+                Err(NbError::Other(error)) => {
+                    self.query = Some(query);
+                    self.finish(device)?;
+                    return Err(nb::Error::Other(error.into()));
+                }
+            }
+        }
+
+        if retry || now.saturating_sub(query.sent_at) >= QUERY_TIMEOUT_MS {
+            if query.attempts >= ATTEMPTS_PER_SERVER {
+                query.server += 1;
+                query.attempts = 0;
+            }
+            if query.server >= MAX_SERVERS || self.servers[query.server].is_none() {
+                self.query = Some(query);
+                self.finish(device)?;
+                return Err(nb::Error::Other(DnsError::Timeout));
+            }
+            if let Err(error) = self.send(device, &mut query, now) {
+                self.query = Some(query);
+                self.finish(device)?;
+                return Err(nb::Error::Other(error));
+            }
+        }
+
+        self.query = Some(query);
+        Err(nb::Error::WouldBlock)
+    }
+
+    /// Abort the query in flight, if any, and return its socket to the device.
+    fn finish<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin>(
+        &mut self,
+        device: &mut Device<SpiBus, StateImpl, IntPin>,
+    ) -> Result<(), DnsError<SpiBus::Error>> {
+        if let Some(query) = self.query.take() {
+            let result = query.socket.socket_close(&mut device.bus);
+            device.release_socket(query.socket.socket);
+            result?;
+        }
+        Ok(())
+    }
+}
+
+/// A [`DnsResolver`] combined with the device it uses, see [`Device::dns`].
+pub struct DnsClient<'a, SpiBus: Bus, StateImpl: State, IntPin: InterruptPin = NoPin> {
+    device: &'a mut Device<SpiBus, StateImpl, IntPin>,
+    resolver: &'a mut DnsResolver,
+    now: u64,
+}
+
+impl<'a, SpiBus: Bus, StateImpl: State, IntPin: InterruptPin>
+    DnsClient<'a, SpiBus, StateImpl, IntPin>
+{
+    /// Abort the query in flight, if any, and return its socket to the device.
+    pub fn cancel(&mut self) -> Result<(), DnsError<SpiBus::Error>> {
+        self.resolver.finish(self.device)
+    }
+}
+
+impl<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin> Device<SpiBus, StateImpl, IntPin> {
+    /// Resolve host names with `resolver`, using one of the device's sockets.
+    ///
+    /// `now` is a monotonic timestamp in milliseconds, used for retransmissions and to expire
+    /// cached answers. Since lookups are non-blocking, create a new client with the current time
+    /// on every poll.
+    pub fn dns<'a>(
+        &'a mut self,
+        resolver: &'a mut DnsResolver,
+        now: u64,
+    ) -> DnsClient<'a, SpiBus, StateImpl, IntPin> {
+        DnsClient {
+            device: self,
+            resolver,
+            now,
+        }
+    }
+}
+
+impl<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin> embedded_nal::Dns
+    for DnsClient<'_, SpiBus, StateImpl, IntPin>
+{
+    type Error = DnsError<SpiBus::Error>;
+
+    fn get_host_by_name(
+        &mut self,
+        hostname: &str,
+        addr_type: AddrType,
+    ) -> nb::Result<IpAddr, Self::Error> {
+        if addr_type == AddrType::IPv6 {
+            return Err(nb::Error::Other(DnsError::UnsupportedAddress));
+        }
+        let address = self.resolver.resolve(self.device, hostname, self.now)?;
+        Ok(IpAddr::V4(address))
+    }
+
+    /// Reverse lookups are not supported.
+    fn get_host_by_address(
+        &mut self,
+        _addr: IpAddr,
+        _result: &mut [u8],
+    ) -> nb::Result<usize, Self::Error> {
+        Err(nb::Error::Other(DnsError::UnsupportedAddress))
+    }
+}
+
+/// Write a recursive A record query for `name` into `buffer`, returning the message length.
+fn encode_query(buffer: &mut [u8], id: u16, name: &Hostname) -> usize {
+    buffer[0..2].copy_from_slice(&id.to_be_bytes());
+    // Standard query, recursion desired
+    buffer[2..4].copy_from_slice(&0x0100u16.to_be_bytes());
+    // One question, no answer, authority or additional records
+    buffer[4..12].copy_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    let mut len = HEADER_LEN;
+    for label in name.as_bytes().split(|&byte| byte == b'.') {
+        buffer[len] = label.len() as u8;
+        buffer[len + 1..len + 1 + label.len()].copy_from_slice(label);
+        len += 1 + label.len();
+    }
+    buffer[len] = 0;
+    len += 1;
+
+    buffer[len..len + 2].copy_from_slice(&TYPE_A.to_be_bytes());
+    buffer[len + 2..len + 4].copy_from_slice(&CLASS_IN.to_be_bytes());
+    len + 4
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Answer {
+    /// The first A record and its TTL in seconds
+    Address(Ipv4Addr, u32),
+    NotFound,
+    ServerFailure,
+}
+
+/// Parse the response to query `id`, returns `None` if the message is not a response to it.
+fn parse_response(message: &[u8], id: u16) -> Option<Answer> {
+    let header = message.get(..HEADER_LEN)?;
+    let is_response = header[2] & 0x80 != 0;
+    if header[0..2] != id.to_be_bytes() || !is_response {
+        return None;
+    }
+
+    match header[3] & 0x0F {
+        0 => {}
+        3 => return Some(Answer::NotFound),
+        _ => return Some(Answer::ServerFailure),
+    }
+
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let answers = u16::from_be_bytes([header[6], header[7]]);
+
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        offset = skip_name(message, offset)? + 4;
+    }
+
+    for _ in 0..answers {
+        offset = skip_name(message, offset)?;
+        let record = message.get(offset..offset + 10)?;
+        let kind = u16::from_be_bytes([record[0], record[1]]);
+        let class = u16::from_be_bytes([record[2], record[3]]);
+        let ttl = u32::from_be_bytes([record[4], record[5], record[6], record[7]]);
+        let len = usize::from(u16::from_be_bytes([record[8], record[9]]));
+        let data = message.get(offset + 10..offset + 10 + len)?;
+
+        // Skips CNAME records, the A record of the canonical name follows them
+        if kind == TYPE_A && class == CLASS_IN && len == 4 {
+            let address = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+            return Some(Answer::Address(address, ttl));
+        }
+        offset += 10 + len;
+    }
+
+    Some(Answer::NotFound)
+}
+
+/// Skip a (possibly compressed) domain name starting at `offset`, returns the offset after it.
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)?;
+        match len {
+            0 => return Some(offset + 1),
+            // A compression pointer always ends the name
+            len if len & 0xC0 == 0xC0 => return Some(offset + 2),
+            len => offset += 1 + usize::from(len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use embedded_nal::Dns;
+
+    use super::*;
+    use crate::emulator::{ConnectError, ConnectionReset, Emulator, Network};
+    use crate::{MacAddress, Mode, UninitializedDevice};
+
+    const UNREACHABLE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const SILENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const ANSWERING: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+
+    /// DNS servers: [`UNREACHABLE`] doesn't answer ARP, [`SILENT`] drops all queries and
+    /// [`ANSWERING`] resolves any name to [`ADDRESS`].
+    #[derive(Default)]
+    struct Servers {
+        /// The servers the queries were sent to, including the unreachable ones
+        queries: Vec<Ipv4Addr>,
+        replies: VecDeque<Vec<u8>>,
+    }
+
+    impl Network for Servers {
+        fn connect(&mut self, _socket: u8, _remote: SocketAddrV4) -> Result<(), ConnectError> {
+            Err(ConnectError::TimedOut)
+        }
+
+        fn send(&mut self, _socket: u8, _data: &[u8]) -> usize {
+            0
+        }
+
+        fn receive(
+            &mut self,
+            _socket: u8,
+            _buffer: &mut [u8],
+        ) -> Result<Option<usize>, ConnectionReset> {
+            Ok(None)
+        }
+
+        fn open_udp(&mut self, _socket: u8, _local_port: u16) {}
+
+        fn send_to(&mut self, _socket: u8, remote: SocketAddrV4, data: &[u8]) -> bool {
+            assert_eq!(remote.port(), DNS_PORT);
+            self.queries.push(*remote.ip());
+            match *remote.ip() {
+                UNREACHABLE => return false,
+                ANSWERING => {
+                    let mut reply = data.to_vec();
+                    // Response, recursion available, one answer pointing to the question
+                    reply[2..4].copy_from_slice(&[0x81, 0x80]);
+                    reply[6..8].copy_from_slice(&[0, 1]);
+                    reply.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                    reply.extend_from_slice(&ADDRESS.octets());
+                    self.replies.push_back(reply);
+                }
+                _ => {}
+            }
+            true
+        }
+
+        fn receive_from(
+            &mut self,
+            _socket: u8,
+            buffer: &mut [u8],
+        ) -> Option<(SocketAddrV4, usize)> {
+            let reply = self.replies.pop_front()?;
+            buffer[..reply.len()].copy_from_slice(&reply);
+            Some((SocketAddrV4::new(ANSWERING, DNS_PORT), reply.len()))
+        }
+
+        fn close(&mut self, _socket: u8) {}
+    }
+
+    #[test]
+    fn test_hostname_validation() {
+        assert!(Hostname::new("example.com").is_some());
+        assert!(Hostname::new("example.com.").is_some());
+        assert!(Hostname::new("").is_none());
+        assert!(Hostname::new("example..com").is_none());
+        assert!(Hostname::new(core::str::from_utf8(&[b'a'; 64]).unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_longest_hostname() {
+        let label = "a".repeat(63);
+        let longest = format!("{0}.{0}.{0}.{1}", label, "a".repeat(61));
+        assert_eq!(longest.len(), MAX_HOSTNAME_LEN);
+        assert!(Hostname::new(&format!("{}a", longest)).is_none());
+
+        let name = Hostname::new(&format!("{}.", longest)).unwrap();
+        let mut buffer = [0u8; HEADER_LEN + MAX_HOSTNAME_LEN + 6];
+        let len = encode_query(&mut buffer, 1, &name);
+        // The encoded name is the longest allowed, 255 bytes
+        assert_eq!(len, buffer.len());
+        assert_eq!(&buffer[HEADER_LEN..HEADER_LEN + 2], &[63, b'a']);
+        assert_eq!(&buffer[len - 6..], &[b'a', 0, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_encode_query() {
+        let mut buffer = [0u8; 64];
+        let name = Hostname::new("example.com").unwrap();
+        let len = encode_query(&mut buffer, 0xABCD, &name);
+
+        assert_eq!(
+            &buffer[..len],
+            &[
+                0xAB, 0xCD, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, // header
+                7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, // name
+                0, 1, 0, 1, // type A, class IN
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let mut message = [0u8; 128];
+        let name = Hostname::new("www.example.com").unwrap();
+        let mut len = encode_query(&mut message, 0x1234, &name);
+        // Flags: response, recursion available; one answer
+        message[2..4].copy_from_slice(&[0x81, 0x80]);
+        message[6..8].copy_from_slice(&[0, 2]);
+
+        // CNAME www.example.com -> example.com, using a compression pointer to the question
+        let cname = [
+            0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xC0, 16, //
+        ];
+        message[len..len + cname.len()].copy_from_slice(&cname);
+        len += cname.len();
+
+        let a = [
+            0xC0, 16, 0, 1, 0, 1, 0, 0, 0x0E, 0x10, 0, 4, 93, 184, 216, 34,
+        ];
+        message[len..len + a.len()].copy_from_slice(&a);
+        len += a.len();
+
+        assert_eq!(
+            parse_response(&message[..len], 0x1234),
+            Some(Answer::Address(Ipv4Addr::new(93, 184, 216, 34), 3600))
+        );
+        assert_eq!(parse_response(&message[..len], 0x4321), None);
+
+        // NXDOMAIN
+        message[3] = 0x83;
+        assert_eq!(
+            parse_response(&message[..len], 0x1234),
+            Some(Answer::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut resolver = DnsResolver::new();
+        let name = Hostname::new("example.com").unwrap();
+        let address = Ipv4Addr::new(93, 184, 216, 34);
+
+        resolver.insert(name, address, 10, 1_000);
+        assert_eq!(
+            resolver.lookup(&Hostname::new("EXAMPLE.com").unwrap(), 5_000),
+            Some(address)
+        );
+        assert_eq!(resolver.lookup(&name, 11_000), None);
+    }
+
+    #[test]
+    fn test_get_host_by_name() {
+        let mut device = UninitializedDevice::new(Emulator::with_network(Servers::default()))
+            .initialize_manual(
+                MacAddress::new(0x02, 0, 0, 0, 0, 1),
+                Ipv4Addr::new(10, 0, 0, 50),
+                Mode::default(),
+            )
+            .unwrap();
+        let mut resolver = DnsResolver::new();
+        resolver.set_servers([UNREACHABLE, SILENT, ANSWERING]);
+
+        // The unreachable server is skipped right away, the silent one after two attempts.
+        for now in [0, 1_000, 2_000, 3_000] {
+            assert!(matches!(
+                device
+                    .dns(&mut resolver, now)
+                    .get_host_by_name("example.com", AddrType::IPv4),
+                Err(nb::Error::WouldBlock)
+            ));
+        }
+        assert!(device.state.any_allocated());
+        assert!(matches!(
+            device
+                .dns(&mut resolver, 4_000)
+                .get_host_by_name("example.com", AddrType::IPv4),
+            Err(nb::Error::WouldBlock)
+        ));
+        assert_eq!(
+            device
+                .dns(&mut resolver, 4_001)
+                .get_host_by_name("example.com", AddrType::IPv4)
+                .unwrap(),
+            IpAddr::V4(ADDRESS)
+        );
+        assert_eq!(
+            device.bus.network_mut().queries,
+            [UNREACHABLE, SILENT, SILENT, ANSWERING]
+        );
+        assert!(!device.state.any_allocated());
+
+        // Cached answers don't take a socket.
+        assert_eq!(
+            device
+                .dns(&mut resolver, 5_000)
+                .get_host_by_name("EXAMPLE.com", AddrType::IPv4)
+                .unwrap(),
+            IpAddr::V4(ADDRESS)
+        );
+        assert_eq!(device.bus.network_mut().queries.len(), 4);
+
+        // An error is only reported once no server is left.
+        resolver.set_servers([UNREACHABLE, SILENT]);
+        for now in [0, 2_000] {
+            assert!(matches!(
+                device
+                    .dns(&mut resolver, now)
+                    .get_host_by_name("example.org", AddrType::IPv4),
+                Err(nb::Error::WouldBlock)
+            ));
+        }
+        assert!(matches!(
+            device
+                .dns(&mut resolver, 4_000)
+                .get_host_by_name("example.org", AddrType::IPv4),
+            Err(nb::Error::Other(DnsError::Timeout))
+        ));
+        assert!(!device.state.any_allocated());
+
+        resolver.set_servers([UNREACHABLE]);
+        assert!(matches!(
+            device
+                .dns(&mut resolver, 0)
+                .get_host_by_name("example.net", AddrType::IPv4),
+            Err(nb::Error::Other(DnsError::Socket(
+                UdpSocketError::WriteTimeout
+            )))
+        ));
+        assert!(!device.state.any_allocated());
+    }
+}
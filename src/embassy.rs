@@ -0,0 +1,286 @@
+//! An [`embassy-net`](https://docs.rs/embassy-net) driver for the W5500 in MACRAW mode.
+//!
+//! [`new`] splits a [`RawDevice`] into a [`Device`], which implements
+//! [`embassy_net_driver::Driver`](driver::Driver) and is handed to the `embassy-net` stack, and a
+//! [`Runner`], whose [`run`](Runner::run) future has to be spawned as a task. The runner waits on
+//! the INTn line, moves frames between the chip's socket 0 buffers and the driver's channel and
+//! reports the link state of the PHY.
+//!
+//! ```no_run
+//! # async fn example(
+//! #     raw: w5500::raw_device::RawDevice<impl w5500::bus::Bus>,
+//! #     int_pin: impl embedded_hal_async::digital::Wait,
+//! #     delay: impl embedded_hal_async::delay::DelayNs,
+//! # ) {
+//! use w5500::embassy::{self, State};
+//!
+//! static mut STATE: State<4, 4> = State::new();
+//! let state = unsafe { &mut *core::ptr::addr_of_mut!(STATE) };
+//! let (device, runner) = embassy::new(state, raw, int_pin, delay).unwrap();
+//! // Spawn `runner.run()` and pass `device` to `embassy_net::new`.
+//! # }
+//! ```
+use embassy_futures::select::{select3, Either3};
+use embassy_net_driver_channel as ch;
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::digital::Wait;
+
+pub use embassy_net_driver_channel::driver;
+
+use self::driver::{HardwareAddress, LinkState};
+use crate::bus::Bus;
+use crate::raw_device::RawDevice;
+use crate::register::socketn;
+
+/// The largest Ethernet frame without the FCS, which the chip adds and strips
+const MTU: usize = 1514;
+
+/// How often the runner checks the link while there is no traffic
+const LINK_POLL_INTERVAL_MS: u32 = 500;
+
+/// The frame buffers of the channel between [`Runner`] and [`Device`], for `N_RX` received and
+/// `N_TX` outgoing frames
+pub struct State<const N_RX: usize, const N_TX: usize> {
+    channel: ch::State<MTU, N_RX, N_TX>,
+}
+
+impl<const N_RX: usize, const N_TX: usize> State<N_RX, N_TX> {
+    pub const fn new() -> Self {
+        Self {
+            channel: ch::State::new(),
+        }
+    }
+}
+
+impl<const N_RX: usize, const N_TX: usize> Default for State<N_RX, N_TX> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The network device for `embassy-net`
+pub type Device<'d> = ch::Device<'d, MTU>;
+
+/// Drives the chip on behalf of the [`Device`]
+pub struct Runner<'d, SpiBus: Bus, IntPin: Wait, Delay: DelayNs> {
+    device: RawDevice<SpiBus>,
+    int_pin: IntPin,
+    delay: Delay,
+    channel: ch::Runner<'d, MTU>,
+}
+
+/// Create the [`Device`] for `embassy-net` and the [`Runner`] that drives the chip.
+///
+/// # Args
+/// * `state` - The frame buffers of the driver.
+/// * `device` - The chip in MACRAW mode. Its MAC address becomes the device's hardware address.
+/// * `int_pin` - The INTn line of the chip. The receive interrupt of socket 0 is enabled.
+/// * `delay` - Used to periodically check the link state.
+#[allow(clippy::type_complexity)]
+pub fn new<'d, const N_RX: usize, const N_TX: usize, SpiBus, IntPin, Delay>(
+    state: &'d mut State<N_RX, N_TX>,
+    mut device: RawDevice<SpiBus>,
+    int_pin: IntPin,
+    delay: Delay,
+) -> Result<(Device<'d>, Runner<'d, SpiBus, IntPin, Delay>), SpiBus::Error>
+where
+    SpiBus: Bus,
+    IntPin: Wait,
+    Delay: DelayNs,
+{
+    let mac = device.mac()?;
+    device.enable_interrupts(socketn::Interrupt::Receive as u8)?;
+    let (channel, driver) = ch::new(&mut state.channel, HardwareAddress::Ethernet(mac.octets));
+    Ok((
+        driver,
+        Runner {
+            device,
+            int_pin,
+            delay,
+            channel,
+        },
+    ))
+}
+
+impl<SpiBus: Bus, IntPin: Wait, Delay: DelayNs> Runner<'_, SpiBus, IntPin, Delay> {
+    /// Run the driver, this never returns.
+    ///
+    /// The SPI transfers are blocking, so the bus should be fast enough not to stall other tasks.
+    /// Bus errors drop the affected frame and report the link as down.
+    pub async fn run(self) -> ! {
+        let Self {
+            mut device,
+            mut int_pin,
+            mut delay,
+            channel,
+        } = self;
+        let (state, mut rx, mut tx) = channel.split();
+        // Frames may have been received before the interrupt was enabled.
+        let mut rx_pending = true;
+
+        loop {
+            let link_up = device.phy_config().is_ok_and(|phy| phy.link_up());
+            state.set_link_state(if link_up {
+                LinkState::Up
+            } else {
+                LinkState::Down
+            });
+
+            // Move frames from the chip into the channel until either is empty.
+            while rx_pending {
+                let buffer = match rx.try_rx_buf() {
+                    Some(buffer) => buffer,
+                    None => break,
+                };
+                match device.read_frame(buffer) {
+                    Ok(len) if len > 0 => rx.rx_done(len),
+                    _ => rx_pending = false,
+                }
+            }
+
+            let receive = async {
+                if rx_pending {
+                    // The chip holds more frames, wait for the stack to take one from the channel.
+                    rx.rx_buf().await;
+                } else {
+                    // Returning early on a pin error is fine, the chip is checked afterwards.
+                    let _ = int_pin.wait_for_low().await;
+                }
+            };
+            let event = select3(receive, tx.tx_buf(), delay.delay_ms(LINK_POLL_INTERVAL_MS)).await;
+            match event {
+                Either3::First(()) => {
+                    if !rx_pending {
+                        // Clear the interrupt before reading, so INTn is asserted again for
+                        // frames that arrive afterwards.
+                        let _ = device.clear_interrupts();
+                        rx_pending = true;
+                    }
+                }
+                Either3::Second(frame) => {
+                    // A frame that couldn't be sent is lost like on a busy wire.
+                    let _ = device.write_frame(frame);
+                    tx.tx_done();
+                }
+                Either3::Third(()) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::cell::RefCell;
+    use core::convert::Infallible;
+
+    use crate::bus::FourWire;
+    use crate::emulator::{Emulator, Shared};
+    use crate::raw_device::RawConfig;
+    use crate::{MacAddress, UninitializedDevice};
+
+    const MAC: MacAddress = MacAddress {
+        octets: [0x02, 0x00, 0x00, 0x00, 0x00, 0x01],
+    };
+
+    #[cfg(feature = "embassy")]
+    #[test]
+    fn test_embassy_runner() {
+        use core::future::poll_fn;
+        use core::task::Poll;
+
+        use embassy_futures::select::{select, Either};
+
+        use crate::embassy::driver::{Driver, LinkState, RxToken, TxToken};
+        use crate::embassy::{self, State};
+
+        /// An INTn line that always looks asserted, but lets the test run in between
+        struct Int;
+
+        impl embedded_hal::digital::ErrorType for Int {
+            type Error = Infallible;
+        }
+
+        impl embedded_hal_async::digital::Wait for Int {
+            async fn wait_for_high(&mut self) -> Result<(), Infallible> {
+                embassy_futures::yield_now().await;
+                Ok(())
+            }
+            async fn wait_for_low(&mut self) -> Result<(), Infallible> {
+                embassy_futures::yield_now().await;
+                Ok(())
+            }
+            async fn wait_for_rising_edge(&mut self) -> Result<(), Infallible> {
+                self.wait_for_high().await
+            }
+            async fn wait_for_falling_edge(&mut self) -> Result<(), Infallible> {
+                self.wait_for_low().await
+            }
+            async fn wait_for_any_edge(&mut self) -> Result<(), Infallible> {
+                self.wait_for_low().await
+            }
+        }
+
+        /// The link is only checked after traffic
+        struct Never;
+
+        impl embedded_hal_async::delay::DelayNs for Never {
+            async fn delay_ns(&mut self, _ns: u32) {
+                core::future::pending().await
+            }
+        }
+
+        let emulator = RefCell::new(Emulator::new());
+        let raw = UninitializedDevice::new(FourWire::new(Shared(&emulator)))
+            .initialize_macraw(MAC, RawConfig::default())
+            .unwrap();
+        let mut state = State::<2, 2>::new();
+        let (mut device, runner) = embassy::new(&mut state, raw, Int, Never).unwrap();
+        assert_eq!(
+            device.hardware_address(),
+            embassy::driver::HardwareAddress::Ethernet(MAC.octets)
+        );
+
+        let test = async {
+            poll_fn(|cx| match device.link_state(cx) {
+                LinkState::Up => Poll::Ready(()),
+                LinkState::Down => Poll::Pending,
+            })
+            .await;
+
+            let mut frame = [0u8; 60];
+            frame[..6].copy_from_slice(&MAC.octets);
+            frame[59] = 0x42;
+            assert!(emulator.borrow_mut().inject_frame(&frame));
+            let received = poll_fn(|cx| match device.receive(cx) {
+                Some((rx, _)) => Poll::Ready(rx.consume(|received| received.to_vec())),
+                None => Poll::Pending,
+            })
+            .await;
+            assert_eq!(received, frame.to_vec());
+
+            poll_fn(|cx| match device.transmit(cx) {
+                Some(tx) => {
+                    tx.consume(42, |buffer| buffer.fill(0xAB));
+                    Poll::Ready(())
+                }
+                None => Poll::Pending,
+            })
+            .await;
+            poll_fn(|cx| {
+                let frames = emulator.borrow_mut().take_frames();
+                if frames.is_empty() {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                } else {
+                    Poll::Ready(frames)
+                }
+            })
+            .await
+        };
+
+        match embassy_futures::block_on(select(runner.run(), test)) {
+            Either::First(never) => never,
+            Either::Second(frames) => assert_eq!(frames, vec![vec![0xAB; 42]]),
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use core::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::time::Duration;
+
+use super::{ConnectError, ConnectionReset, Network};
+
+/// How long [`HostNetwork`] waits for a TCP connection to be accepted
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+enum Connection {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+/// A [`Network`] that maps the chip's TCP connections and UDP sockets onto sockets of the host,
+/// e.g. to run the application's networking code against servers on `127.0.0.1`
+///
+/// The IP address configured on the chip isn't used: connections and datagrams originate from the
+/// host's addresses. UDP sockets are bound to the chip socket's port on all interfaces, or to an
+/// ephemeral port if it's taken. Listening on a TCP port isn't supported.
+///
+/// ```no_run
+/// use core::net::SocketAddr;
+/// use embedded_nal::{nb, TcpClientStack};
+/// use w5500::emulator::{Emulator, HostNetwork};
+/// use w5500::{MacAddress, Mode, UninitializedDevice};
+///
+/// let mut device = UninitializedDevice::new(Emulator::with_network(HostNetwork::new()))
+///     .initialize_manual(
+///         MacAddress::new(0x02, 0, 0, 0, 0, 1),
+///         "192.168.0.2".parse().unwrap(),
+///         Mode::default(),
+///     )
+///     .unwrap();
+///
+/// let mut socket = device.socket().unwrap();
+/// let broker: SocketAddr = "127.0.0.1:1883".parse().unwrap();
+/// nb::block!(device.connect(&mut socket, broker)).unwrap();
+/// ```
+#[derive(Debug, Default)]
+pub struct HostNetwork {
+    connections: [Option<Connection>; 8],
+}
+
+impl HostNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn tcp(&mut self, socket: u8) -> Option<&mut TcpStream> {
+        match self.connections.get_mut(usize::from(socket)) {
+            Some(Some(Connection::Tcp(stream))) => Some(stream),
+            _ => None,
+        }
+    }
+
+    /// The local address of the host socket behind the chip's socket `socket`
+    ///
+    /// A UDP socket has the chip socket's port, unless it was taken and the host picked another.
+    pub fn local_addr(&self, socket: u8) -> Option<SocketAddr> {
+        match self.connections.get(usize::from(socket))? {
+            Some(Connection::Tcp(stream)) => stream.local_addr().ok(),
+            Some(Connection::Udp(udp)) => udp.local_addr().ok(),
+            None => None,
+        }
+    }
+
+    fn udp(&mut self, socket: u8) -> Option<&mut UdpSocket> {
+        match self.connections.get_mut(usize::from(socket)) {
+            Some(Some(Connection::Udp(udp))) => Some(udp),
+            _ => None,
+        }
+    }
+}
+
+impl Network for HostNetwork {
+    fn connect(&mut self, socket: u8, remote: SocketAddrV4) -> Result<(), ConnectError> {
+        let stream = TcpStream::connect_timeout(&SocketAddr::V4(remote), CONNECT_TIMEOUT).map_err(
+            |error| match error.kind() {
+                ErrorKind::ConnectionRefused => ConnectError::Refused,
+                _ => ConnectError::TimedOut,
+            },
+        )?;
+        stream
+            .set_nonblocking(true)
+            .map_err(|_| ConnectError::TimedOut)?;
+        // The chip sends a segment per SEND, don't let Nagle's algorithm hold it back.
+        let _ = stream.set_nodelay(true);
+        self.connections[usize::from(socket)] = Some(Connection::Tcp(stream));
+        Ok(())
+    }
+
+    fn send(&mut self, socket: u8, data: &[u8]) -> usize {
+        self.tcp(socket)
+            .and_then(|stream| stream.write(data).ok())
+            .unwrap_or(0)
+    }
+
+    fn receive(&mut self, socket: u8, buffer: &mut [u8]) -> Result<Option<usize>, ConnectionReset> {
+        // Reading into an empty buffer would look like the end of the stream.
+        if buffer.is_empty() {
+            return Ok(None);
+        }
+        let Some(stream) = self.tcp(socket) else {
+            return Ok(None);
+        };
+        match stream.read(buffer) {
+            Ok(len) => Ok(Some(len)),
+            Err(error)
+                if error.kind() == ErrorKind::WouldBlock
+                    || error.kind() == ErrorKind::Interrupted =>
+            {
+                Ok(None)
+            }
+            // Reset, aborted or otherwise broken, the connection can't be used anymore.
+            Err(_) => Err(ConnectionReset),
+        }
+    }
+
+    fn open_udp(&mut self, socket: u8, local_port: u16) {
+        let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, local_port))
+            .or_else(|_| UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)));
+        if let Ok(udp) = udp {
+            if udp.set_nonblocking(true).is_ok() {
+                self.connections[usize::from(socket)] = Some(Connection::Udp(udp));
+            }
+        }
+    }
+
+    fn send_to(&mut self, socket: u8, remote: SocketAddrV4, data: &[u8]) -> bool {
+        self.udp(socket)
+            .map(|udp| udp.send_to(data, remote).is_ok())
+            .unwrap_or(false)
+    }
+
+    fn receive_from(&mut self, socket: u8, buffer: &mut [u8]) -> Option<(SocketAddrV4, usize)> {
+        match self.udp(socket)?.recv_from(buffer) {
+            Ok((len, SocketAddr::V4(origin))) => Some((origin, len)),
+            _ => None,
+        }
+    }
+
+    fn close(&mut self, socket: u8) {
+        self.connections[usize::from(socket)] = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use embedded_nal::nb;
+
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::tcp::TcpSocketError;
+    use crate::{MacAddress, Mode, UninitializedDevice};
+
+    fn device() -> crate::Device<Emulator<HostNetwork>, crate::DeviceState<crate::Manual>> {
+        UninitializedDevice::new(Emulator::with_network(HostNetwork::new()))
+            .initialize_manual(
+                MacAddress::new(0x02, 0x00, 0x00, 0x00, 0x00, 0x01),
+                Ipv4Addr::new(192, 168, 0, 2),
+                Mode::default(),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_tcp_echo() {
+        use embedded_nal::TcpClientStack;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap();
+        let echo = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; 64];
+            loop {
+                match stream.read(&mut buffer).unwrap() {
+                    0 => break,
+                    len => stream.write_all(&buffer[..len]).unwrap(),
+                }
+            }
+        });
+
+        let mut device = device();
+        let mut socket = device.socket().unwrap();
+        nb::block!(device.connect(&mut socket, server)).unwrap();
+        assert_eq!(nb::block!(device.send(&mut socket, b"hello")).unwrap(), 5);
+
+        let mut buffer = [0; 5];
+        let mut received = 0;
+        while received < buffer.len() {
+            received += nb::block!(device.receive(&mut socket, &mut buffer[received..])).unwrap();
+        }
+        assert_eq!(&buffer, b"hello");
+        device.close(socket).unwrap();
+        echo.join().unwrap();
+
+        // Nobody listens on the port anymore.
+        let mut socket = device.socket().unwrap();
+        assert!(matches!(
+            nb::block!(device.connect(&mut socket, server)),
+            Err(TcpSocketError::ConnectionRefused)
+        ));
+    }
+
+    #[test]
+    fn test_udp_exchange() {
+        use embedded_nal::{UdpClientStack, UdpFullStack};
+
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut device = device();
+        let mut socket = device.socket().unwrap();
+        // Port 0 lets the host pick a free port, the chip's socket keeps port 0.
+        device.bind(&mut socket, 0).unwrap();
+        let local = device.bus.network_mut().local_addr(socket.index()).unwrap();
+        assert_ne!(local.port(), 0);
+        nb::block!(device.send_to(&mut socket, peer.local_addr().unwrap(), b"request")).unwrap();
+
+        let mut buffer = [0; 16];
+        let (len, origin) = peer.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"request");
+        assert_eq!(origin.port(), local.port());
+        peer.send_to(b"response", origin).unwrap();
+
+        let (len, from) = nb::block!(device.receive(&mut socket, &mut buffer)).unwrap();
+        assert_eq!(&buffer[..len], b"response");
+        assert_eq!(from, peer.local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_tcp_reset() {
+        use embedded_nal::TcpClientStack;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap();
+        let reset = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            // Closing with unread data answers with a RST instead of a FIN.
+            stream.peek(&mut [0]).unwrap();
+        });
+
+        let mut device = device();
+        let mut socket = device.socket().unwrap();
+        nb::block!(device.connect(&mut socket, server)).unwrap();
+        nb::block!(device.send(&mut socket, b"unread")).unwrap();
+        reset.join().unwrap();
+
+        let mut buffer = [0; 8];
+        assert!(matches!(
+            nb::block!(device.receive(&mut socket, &mut buffer)),
+            Err(TcpSocketError::NotConnected)
+        ));
+        device.close(socket).unwrap();
+    }
+}
@@ -0,0 +1,1396 @@
+//! A software model of the W5500 to test the driver without hardware.
+//!
+//! [`Emulator`] implements [`SpiDevice`] for [`FourWire`](crate::bus::FourWire) and [`SpiBus`]
+//! for [`ThreeWire`](crate::bus::ThreeWire). It decodes the frames shifted in over SPI and keeps
+//! the common registers, the socket registers and the sockets' TX and RX buffers. Socket commands
+//! take effect immediately:
+//!
+//! - Sockets reach each other through the chip's own IP address. A TCP socket connecting to it is
+//!   accepted by the socket listening on the port, UDP and IPRAW packets sent to it are received
+//!   by the sockets bound to the port or protocol. The chip answers ICMP echo requests itself.
+//! - UDP broadcasts and multicasts are received by the other sockets bound to the port.
+//!   Broadcasts are passed to the [`Network`] as well.
+//! - TCP connections and UDP datagrams to any other address go to the [`Network`]. Without one
+//!   they time out, like an unanswered ARP request. [`HostNetwork`] forwards them to the sockets
+//!   of the host running the tests.
+//! - Frames sent in MACRAW mode are collected by [`Emulator::take_frames`], frames to receive
+//!   are passed to [`Emulator::inject_frame`].
+//!
+//! Sent TCP data is acknowledged as soon as the peer has room for it. Closing a connection with
+//! DISCON completes the FIN handshake at once, so the closing side can't receive anymore while
+//! the peer is in CLOSE_WAIT. While the link is down, SEND and DISCON on a connection time out:
+//! the socket is closed with a TIMEOUT interrupt, as the chip does once its retransmissions
+//! aren't acknowledged.
+//!
+//! Both sides of a connection are driven by the same [`Device`](crate::Device):
+//!
+//! ```
+//! # #[cfg(feature = "std")]
+//! # {
+//! use core::net::{Ipv4Addr, SocketAddr};
+//! use embedded_nal::{nb, TcpClientStack, TcpFullStack};
+//! use w5500::{bus::FourWire, emulator::Emulator, MacAddress, Mode, UninitializedDevice};
+//!
+//! let ip = Ipv4Addr::new(192, 168, 0, 2);
+//! let mut device = UninitializedDevice::new(FourWire::new(Emulator::new()))
+//!     .initialize_manual(MacAddress::new(0x02, 0, 0, 0, 0, 1), ip, Mode::default())
+//!     .unwrap();
+//!
+//! let mut listener = device.socket().unwrap();
+//! device.bind(&mut listener, 80).unwrap();
+//! device.listen(&mut listener).unwrap();
+//!
+//! let mut client = device.socket().unwrap();
+//! nb::block!(device.connect(&mut client, SocketAddr::new(ip.into(), 80))).unwrap();
+//! let (mut server, _) = nb::block!(device.accept(&mut listener)).unwrap();
+//!
+//! nb::block!(device.send(&mut client, b"GET /")).unwrap();
+//! let mut buffer = [0; 16];
+//! let len = nb::block!(device.receive(&mut server, &mut buffer)).unwrap();
+//! assert_eq!(&buffer[..len], b"GET /");
+//! # }
+//! ```
+use core::convert::{Infallible, TryFrom, TryInto};
+use core::fmt;
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use embedded_hal::spi::{ErrorType, Operation, SpiBus, SpiDevice};
+
+use crate::bus::Bus;
+use crate::register::{self, common, socketn, socketn::Status};
+
+mod hosted;
+
+pub use self::hosted::HostNetwork;
+
+const WRITE_MODE_MASK: u8 = 0b0000_0100;
+const OPERATION_MODE_MASK: u8 = 0b0000_0011;
+
+const COMMON_REGISTERS: usize = 0x40;
+const SOCKET_REGISTERS: usize = 0x30;
+
+/// The largest buffer of a socket, in KB
+const MAX_BUFFER_KB: u8 = 16;
+
+/// `MR(RST)`: resets all registers
+const MODE_RESET: u8 = 1 << 7;
+/// `MR(PB)`: don't answer ICMP echo requests
+const MODE_PING_BLOCK: u8 = 1 << 4;
+
+/// `Sn_TTL`, not used by the driver
+const TTL: u16 = 0x16;
+/// `Sn_FRAG`, not used by the driver
+const FRAGMENT: u16 = 0x2D;
+
+const OPEN: u8 = socketn::Command::Open as u8;
+const LISTEN: u8 = socketn::Command::Listen as u8;
+const CONNECT: u8 = socketn::Command::Connect as u8;
+const DISCON: u8 = socketn::Command::Discon as u8;
+const CLOSE: u8 = socketn::Command::Close as u8;
+const SEND: u8 = socketn::Command::Send as u8;
+const SEND_MAC: u8 = 0x21;
+const RECV: u8 = socketn::Command::Receive as u8;
+
+const SEND_OK: u8 = socketn::Interrupt::SendOk as u8;
+const TIMEOUT: u8 = socketn::Interrupt::Timeout as u8;
+const RECEIVE: u8 = socketn::Interrupt::Receive as u8;
+const DISCONNECT: u8 = socketn::Interrupt::Disconnect as u8;
+const CON: u8 = socketn::Interrupt::Connect as u8;
+
+const ICMP: u8 = 1;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// What the next byte shifted in over SPI is part of
+#[derive(Debug, Copy, Clone)]
+enum Phase {
+    Address {
+        high: Option<u8>,
+    },
+    Control {
+        address: u16,
+    },
+    Data {
+        block: u8,
+        address: u16,
+        write: bool,
+        /// The bytes left in fixed data length mode
+        remaining: Option<u8>,
+    },
+}
+
+impl Phase {
+    const START: Phase = Phase::Address { high: None };
+}
+
+/// Why [`Network::connect`] failed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConnectError {
+    /// The peer answered with a RST
+    Refused,
+    /// The peer didn't answer
+    TimedOut,
+}
+
+/// The peer answered with a RST, see [`Network::receive`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ConnectionReset;
+
+/// The network the [`Emulator`] is connected to
+///
+/// It carries the TCP and UDP traffic that isn't for the chip itself. The sockets are identified
+/// by their index.
+pub trait Network {
+    /// Open a TCP connection to `remote`
+    fn connect(&mut self, socket: u8, remote: SocketAddrV4) -> Result<(), ConnectError>;
+
+    /// Send data over the TCP connection, returns how much of it was sent
+    fn send(&mut self, socket: u8, data: &[u8]) -> usize;
+
+    /// Receive data from the TCP connection. Returns `Ok(None)` if nothing was received,
+    /// `Ok(Some(0))` once the peer closed the connection and [`ConnectionReset`] if the peer
+    /// aborted it.
+    fn receive(&mut self, socket: u8, buffer: &mut [u8]) -> Result<Option<usize>, ConnectionReset>;
+
+    /// Open a UDP socket on `local_port`
+    fn open_udp(&mut self, socket: u8, local_port: u16);
+
+    /// Send a UDP datagram to `remote`, returns `false` if it couldn't be sent
+    fn send_to(&mut self, socket: u8, remote: SocketAddrV4, data: &[u8]) -> bool;
+
+    /// Receive a UDP datagram, returns its origin and size
+    fn receive_from(&mut self, socket: u8, buffer: &mut [u8]) -> Option<(SocketAddrV4, usize)>;
+
+    /// Close the TCP connection or UDP socket, if any
+    fn close(&mut self, socket: u8);
+}
+
+/// No [`Network`], connections and datagrams time out
+#[derive(Debug, Default)]
+pub struct NoNetwork;
+
+impl Network for NoNetwork {
+    fn connect(&mut self, _socket: u8, _remote: SocketAddrV4) -> Result<(), ConnectError> {
+        Err(ConnectError::TimedOut)
+    }
+
+    fn send(&mut self, _socket: u8, _data: &[u8]) -> usize {
+        0
+    }
+
+    fn receive(
+        &mut self,
+        _socket: u8,
+        _buffer: &mut [u8],
+    ) -> Result<Option<usize>, ConnectionReset> {
+        Ok(None)
+    }
+
+    fn open_udp(&mut self, _socket: u8, _local_port: u16) {}
+
+    fn send_to(&mut self, _socket: u8, _remote: SocketAddrV4, _data: &[u8]) -> bool {
+        false
+    }
+
+    fn receive_from(&mut self, _socket: u8, _buffer: &mut [u8]) -> Option<(SocketAddrV4, usize)> {
+        None
+    }
+
+    fn close(&mut self, _socket: u8) {}
+}
+
+/// The other end of a TCP connection
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Peer {
+    /// Another socket of the chip
+    Socket(usize),
+    /// A host on the [`Network`]
+    Network,
+}
+
+/// A W5500 connected over SPI, see the [module documentation](self)
+///
+/// It also implements [`Bus`] to be used without [`FourWire`](crate::bus::FourWire).
+pub struct Emulator<Net: Network = NoNetwork> {
+    common: [u8; COMMON_REGISTERS],
+    sockets: [SocketModel; 8],
+    link_up: bool,
+    phase: Phase,
+    frames: Vec<Vec<u8>>,
+    network: Net,
+}
+
+struct SocketModel {
+    registers: [u8; SOCKET_REGISTERS],
+    tx: Vec<u8>,
+    rx: Vec<u8>,
+    /// The other end of a TCP connection
+    peer: Option<Peer>,
+    /// `Sn_TX_WR` at the last SEND, the data up to it is moved to the peer once it has room
+    send_end: u16,
+}
+
+impl SocketModel {
+    fn new() -> Self {
+        let mut registers = [0; SOCKET_REGISTERS];
+        let destination_mac = usize::from(socketn::DESTINATION_MAC);
+        registers[destination_mac..destination_mac + 6].fill(0xFF);
+        registers[usize::from(TTL)] = 0x80;
+        registers[usize::from(socketn::RXBUF_SIZE)] = 2;
+        registers[usize::from(socketn::TXBUF_SIZE)] = 2;
+        registers[usize::from(socketn::INTERRUPT_MASK)] = 0xFF;
+        registers[usize::from(FRAGMENT)] = 0x40;
+        Self {
+            registers,
+            tx: vec![0; usize::from(MAX_BUFFER_KB) * 1024],
+            rx: vec![0; usize::from(MAX_BUFFER_KB) * 1024],
+            peer: None,
+            send_end: 0,
+        }
+    }
+
+    fn register_u16(&self, address: u16) -> u16 {
+        let address = usize::from(address);
+        u16::from_be_bytes([self.registers[address], self.registers[address + 1]])
+    }
+
+    fn set_register_u16(&mut self, address: u16, value: u16) {
+        let address = usize::from(address);
+        self.registers[address..address + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn status(&self) -> Option<Status> {
+        Status::try_from(self.registers[usize::from(socketn::STATUS)]).ok()
+    }
+
+    fn set_status(&mut self, status: Status) {
+        self.registers[usize::from(socketn::STATUS)] = status as u8;
+    }
+
+    fn interrupt(&mut self, interrupts: u8) {
+        self.registers[usize::from(socketn::INTERRUPT)] |= interrupts;
+    }
+
+    fn port(&self) -> u16 {
+        self.register_u16(socketn::SOURCE_PORT)
+    }
+
+    fn destination_ip(&self) -> [u8; 4] {
+        let address = usize::from(socketn::DESTINATION_IP);
+        self.registers[address..address + 4].try_into().unwrap()
+    }
+
+    fn tx_size(&self) -> u16 {
+        u16::from(self.registers[usize::from(socketn::TXBUF_SIZE)].min(MAX_BUFFER_KB)) * 1024
+    }
+
+    fn rx_size(&self) -> u16 {
+        u16::from(self.registers[usize::from(socketn::RXBUF_SIZE)].min(MAX_BUFFER_KB)) * 1024
+    }
+
+    /// `Sn_TX_FSR`, the data between `Sn_TX_RD` and `Sn_TX_WR` hasn't been sent yet
+    fn tx_free_size(&self) -> u16 {
+        let queued = self
+            .register_u16(socketn::TX_DATA_WRITE_POINTER)
+            .wrapping_sub(self.register_u16(socketn::TX_DATA_READ_POINTER));
+        self.tx_size().saturating_sub(queued)
+    }
+
+    /// `Sn_RX_RSR`
+    fn received_size(&self) -> u16 {
+        self.register_u16(socketn::RX_DATA_WRITE_POINTER)
+            .wrapping_sub(self.register_u16(socketn::RX_DATA_READ_POINTER))
+    }
+
+    fn rx_free_size(&self) -> u16 {
+        self.rx_size().saturating_sub(self.received_size())
+    }
+
+    fn reset_pointers(&mut self) {
+        for pointer in [
+            socketn::TX_DATA_READ_POINTER,
+            socketn::TX_DATA_WRITE_POINTER,
+            socketn::RX_DATA_READ_POINTER,
+            socketn::RX_DATA_WRITE_POINTER,
+        ] {
+            self.set_register_u16(pointer, 0);
+        }
+        self.send_end = 0;
+    }
+
+    /// Copy `len` bytes of the queued data out of the TX buffer
+    fn peek_tx(&self, len: u16) -> Vec<u8> {
+        let size = usize::from(self.tx_size()).max(1);
+        let read_pointer = self.register_u16(socketn::TX_DATA_READ_POINTER);
+        (0..len)
+            .map(|offset| self.tx[usize::from(read_pointer.wrapping_add(offset)) % size])
+            .collect()
+    }
+
+    /// Mark `len` bytes of the queued data as sent
+    fn advance_tx(&mut self, len: u16) {
+        let read_pointer = self.register_u16(socketn::TX_DATA_READ_POINTER);
+        self.set_register_u16(
+            socketn::TX_DATA_READ_POINTER,
+            read_pointer.wrapping_add(len),
+        );
+    }
+
+    /// Take `len` bytes of the queued data from the TX buffer
+    fn take_tx(&mut self, len: u16) -> Vec<u8> {
+        let data = self.peek_tx(len);
+        self.advance_tx(len);
+        data
+    }
+
+    /// The data up to the last SEND that hasn't been sent yet
+    fn pending_tx(&self) -> u16 {
+        self.send_end
+            .wrapping_sub(self.register_u16(socketn::TX_DATA_READ_POINTER))
+    }
+
+    /// Take all data queued in the TX buffer
+    fn take_all_tx(&mut self) -> Vec<u8> {
+        let queued = self
+            .register_u16(socketn::TX_DATA_WRITE_POINTER)
+            .wrapping_sub(self.register_u16(socketn::TX_DATA_READ_POINTER));
+        self.take_tx(queued)
+    }
+
+    /// Append `parts` to the RX buffer, or drop them if they don't fit
+    fn push_rx(&mut self, parts: &[&[u8]]) -> bool {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        if len > usize::from(self.rx_free_size()) {
+            return false;
+        }
+        let size = usize::from(self.rx_size());
+        let mut write_pointer = self.register_u16(socketn::RX_DATA_WRITE_POINTER);
+        for byte in parts.iter().flat_map(|part| part.iter()) {
+            self.rx[usize::from(write_pointer) % size] = *byte;
+            write_pointer = write_pointer.wrapping_add(1);
+        }
+        self.set_register_u16(socketn::RX_DATA_WRITE_POINTER, write_pointer);
+        self.interrupt(RECEIVE);
+        true
+    }
+}
+
+impl Emulator {
+    /// A chip after power-on, with the link up and without a [`Network`]
+    pub fn new() -> Self {
+        Self::with_network(NoNetwork)
+    }
+}
+
+impl<Net: Network> Emulator<Net> {
+    /// A chip after power-on, with the link up and connected to `network`
+    pub fn with_network(network: Net) -> Self {
+        let mut emulator = Self {
+            common: [0; COMMON_REGISTERS],
+            sockets: core::array::from_fn(|_| SocketModel::new()),
+            link_up: true,
+            phase: Phase::START,
+            frames: Vec::new(),
+            network,
+        };
+        emulator.reset();
+        emulator
+    }
+
+    /// Plug or unplug the Ethernet cable
+    pub fn set_link_up(&mut self, up: bool) {
+        self.link_up = up;
+    }
+
+    /// The [`Network`] the chip is connected to
+    pub fn network_mut(&mut self) -> &mut Net {
+        &mut self.network
+    }
+
+    /// Whether INTn is asserted: an interrupt is pending that is enabled in IMR, or in SIMR and
+    /// the socket's `Sn_IMR`
+    pub fn interrupt_asserted(&self) -> bool {
+        let common = self.common[usize::from(common::INTERRUPT)]
+            & self.common[usize::from(common::INTERRUPT_MASK)];
+        let sockets = self.common[usize::from(common::SOCKET_INTERRUPT_MASK)];
+        common != 0
+            || self.sockets.iter().enumerate().any(|(index, socket)| {
+                sockets & (1 << index) != 0
+                    && socket.registers[usize::from(socketn::INTERRUPT)]
+                        & socket.registers[usize::from(socketn::INTERRUPT_MASK)]
+                        != 0
+            })
+    }
+
+    /// The frames sent in MACRAW mode since the last call
+    pub fn take_frames(&mut self) -> Vec<Vec<u8>> {
+        core::mem::take(&mut self.frames)
+    }
+
+    /// Receive `frame` on the socket in MACRAW mode. Returns `false` if the frame was dropped,
+    /// because no socket is in MACRAW mode, the filter set in `Sn_MR` rejected it or the RX buffer
+    /// is full.
+    pub fn inject_frame(&mut self, frame: &[u8]) -> bool {
+        let mac = self.common_bytes::<6>(common::MAC);
+        let socket = &mut self.sockets[0];
+        if socket.status() != Some(Status::MacRaw) {
+            return false;
+        }
+        if frame.len() < 14 {
+            return false;
+        }
+        let mode = socket.registers[usize::from(socketn::MODE)];
+        let broadcast = frame[..6] == [0xFF; 6];
+        let multicast = !broadcast && frame[0] & 1 == 1;
+        let ipv6 = frame[12..14] == [0x86, 0xDD];
+        let dropped =
+            (mode & socketn::MODE_MAC_FILTER != 0 && !broadcast && !multicast && frame[..6] != mac)
+                || (mode & socketn::MODE_BROADCAST_BLOCK != 0 && broadcast)
+                || (mode & socketn::MODE_MULTICAST_BLOCK != 0 && multicast)
+                || (mode & socketn::MODE_IPV6_BLOCK != 0 && ipv6);
+        if dropped {
+            return false;
+        }
+        // The frame is preceded by its size, including the two bytes of the size itself.
+        let Ok(size) = u16::try_from(frame.len() + 2) else {
+            return false;
+        };
+        socket.push_rx(&[&size.to_be_bytes(), frame])
+    }
+
+    fn reset(&mut self) {
+        self.common = [0; COMMON_REGISTERS];
+        let retry_time = usize::from(common::RETRY_TIME);
+        self.common[retry_time..retry_time + 2].copy_from_slice(&0x07D0u16.to_be_bytes());
+        self.common[usize::from(common::RETRY_COUNT)] = 0x08;
+        self.common[usize::from(common::PHY_CONFIG)] = 0b1011_1000;
+        self.common[usize::from(common::VERSION)] = 0x04;
+        self.sockets = core::array::from_fn(|_| SocketModel::new());
+    }
+
+    fn common_bytes<const N: usize>(&self, address: u16) -> [u8; N] {
+        let address = usize::from(address);
+        self.common[address..address + N].try_into().unwrap()
+    }
+
+    /// Process a byte shifted in over MOSI and return the byte shifted out over MISO
+    fn shift(&mut self, input: u8) -> u8 {
+        match self.phase {
+            Phase::Address { high: None } => {
+                self.poll_network();
+                self.phase = Phase::Address { high: Some(input) };
+                0
+            }
+            Phase::Address { high: Some(high) } => {
+                self.phase = Phase::Control {
+                    address: u16::from_be_bytes([high, input]),
+                };
+                0
+            }
+            Phase::Control { address } => {
+                self.phase = Phase::Data {
+                    block: input >> 3,
+                    address,
+                    write: input & WRITE_MODE_MASK != 0,
+                    remaining: match input & OPERATION_MODE_MASK {
+                        0 => None,
+                        1 => Some(1),
+                        2 => Some(2),
+                        _ => Some(4),
+                    },
+                };
+                0
+            }
+            Phase::Data {
+                block,
+                address,
+                write,
+                remaining,
+            } => {
+                let output = if write {
+                    self.write_byte(block, address, input);
+                    0
+                } else {
+                    self.read_byte(block, address)
+                };
+                self.phase = match remaining {
+                    Some(1) => Phase::START,
+                    remaining => Phase::Data {
+                        block,
+                        address: address.wrapping_add(1),
+                        write,
+                        remaining: remaining.map(|remaining| remaining - 1),
+                    },
+                };
+                output
+            }
+        }
+    }
+
+    fn shift_operation(&mut self, operation: &mut Operation<'_, u8>) {
+        match operation {
+            Operation::Read(words) => words.iter_mut().for_each(|word| *word = self.shift(0)),
+            Operation::Write(words) => words.iter().for_each(|word| {
+                self.shift(*word);
+            }),
+            Operation::Transfer(read, write) => self.shift_transfer(read, write),
+            Operation::TransferInPlace(words) => {
+                words.iter_mut().for_each(|word| *word = self.shift(*word))
+            }
+            Operation::DelayNs(_) => {}
+        }
+    }
+
+    fn shift_transfer(&mut self, read: &mut [u8], write: &[u8]) {
+        for index in 0..read.len().max(write.len()) {
+            let output = self.shift(write.get(index).copied().unwrap_or(0));
+            if let Some(word) = read.get_mut(index) {
+                *word = output;
+            }
+        }
+    }
+
+    fn read_byte(&self, block: u8, address: u16) -> u8 {
+        if block == register::COMMON {
+            return self.read_common(address);
+        }
+        let Some(socket) = self.sockets.get(usize::from(block >> 2)) else {
+            return 0;
+        };
+        match block & 0b11 {
+            0b01 => Self::read_socket_register(socket, address),
+            0b10 => buffer_byte(&socket.tx, socket.tx_size(), address),
+            0b11 => buffer_byte(&socket.rx, socket.rx_size(), address),
+            _ => 0,
+        }
+    }
+
+    fn write_byte(&mut self, block: u8, address: u16, value: u8) {
+        if block == register::COMMON {
+            return self.write_common(address, value);
+        }
+        let index = usize::from(block >> 2);
+        if index >= self.sockets.len() {
+            return;
+        }
+        let socket = &mut self.sockets[index];
+        match block & 0b11 {
+            0b01 => self.write_socket_register(index, address, value),
+            0b10 => {
+                let size = socket.tx_size();
+                if size > 0 {
+                    socket.tx[usize::from(address % size)] = value;
+                }
+            }
+            0b11 => {
+                let size = socket.rx_size();
+                if size > 0 {
+                    socket.rx[usize::from(address % size)] = value;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_common(&self, address: u16) -> u8 {
+        match address {
+            common::SOCKET_INTERRUPT => self
+                .sockets
+                .iter()
+                .enumerate()
+                .filter(|(_, socket)| socket.registers[usize::from(socketn::INTERRUPT)] != 0)
+                .fold(0, |interrupts, (index, _)| interrupts | (1 << index)),
+            common::PHY_CONFIG => {
+                let status = if self.link_up { 0b111 } else { 0b000 };
+                (self.common[usize::from(address)] & !0b111) | status
+            }
+            address => self.common.get(usize::from(address)).copied().unwrap_or(0),
+        }
+    }
+
+    fn write_common(&mut self, address: u16, value: u8) {
+        match address {
+            common::MODE if value & MODE_RESET != 0 => self.reset(),
+            common::INTERRUPT => self.common[usize::from(address)] &= !value,
+            // Read-only
+            common::SOCKET_INTERRUPT | common::VERSION => {}
+            address
+                if (common::UNREACHABLE_IP..common::UNREACHABLE_PORT + 2).contains(&address) => {}
+            address => {
+                if let Some(register) = self.common.get_mut(usize::from(address)) {
+                    *register = value;
+                }
+            }
+        }
+    }
+
+    fn read_socket_register(socket: &SocketModel, address: u16) -> u8 {
+        match address {
+            socketn::TX_FREE_SIZE => socket.tx_free_size().to_be_bytes()[0],
+            address if address == socketn::TX_FREE_SIZE + 1 => {
+                socket.tx_free_size().to_be_bytes()[1]
+            }
+            socketn::RECEIVED_SIZE => socket.received_size().to_be_bytes()[0],
+            address if address == socketn::RECEIVED_SIZE + 1 => {
+                socket.received_size().to_be_bytes()[1]
+            }
+            address => socket
+                .registers
+                .get(usize::from(address))
+                .copied()
+                .unwrap_or(0),
+        }
+    }
+
+    fn write_socket_register(&mut self, index: usize, address: u16, value: u8) {
+        let socket = &mut self.sockets[index];
+        match address {
+            socketn::COMMAND => self.command(index, value),
+            socketn::INTERRUPT => socket.registers[usize::from(address)] &= !value,
+            // Read-only: Sn_SR, Sn_TX_FSR, Sn_TX_RD, Sn_RX_RSR and Sn_RX_WR
+            socketn::STATUS => {}
+            address
+                if (socketn::TX_FREE_SIZE..socketn::TX_DATA_WRITE_POINTER).contains(&address)
+                    || (socketn::RECEIVED_SIZE..socketn::RX_DATA_READ_POINTER)
+                        .contains(&address)
+                    || (socketn::RX_DATA_WRITE_POINTER..socketn::RX_DATA_WRITE_POINTER + 2)
+                        .contains(&address) => {}
+            address => {
+                if let Some(register) = socket.registers.get_mut(usize::from(address)) {
+                    *register = value;
+                }
+            }
+        }
+    }
+
+    fn command(&mut self, index: usize, command: u8) {
+        let status = self.sockets[index].status();
+        match command {
+            OPEN => self.open(index),
+            LISTEN if status == Some(Status::Init) => {
+                self.sockets[index].set_status(Status::Listen);
+            }
+            CONNECT if status == Some(Status::Init) => self.connect(index),
+            DISCON => self.disconnect(index),
+            CLOSE => {
+                self.reset_connection(index);
+                self.network.close(index as u8);
+                self.sockets[index].set_status(Status::Closed);
+            }
+            SEND | SEND_MAC => self.send(index),
+            RECV => {
+                // The data the peer couldn't send before may fit now.
+                if let Some(Peer::Socket(peer)) = self.sockets[index].peer {
+                    self.transmit(peer);
+                }
+                let socket = &mut self.sockets[index];
+                if socket.received_size() > 0 {
+                    socket.interrupt(RECEIVE);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn open(&mut self, index: usize) {
+        self.reset_connection(index);
+        self.network.close(index as u8);
+        let socket = &mut self.sockets[index];
+        let protocol = socket.registers[usize::from(socketn::MODE)] & 0x0F;
+        let status = match protocol {
+            protocol if protocol == socketn::Protocol::Tcp as u8 => Status::Init,
+            protocol if protocol == socketn::Protocol::Udp as u8 => Status::Udp,
+            protocol if protocol == socketn::Protocol::IpRaw as u8 => Status::IpRaw,
+            // Only socket 0 supports MACRAW.
+            protocol if protocol == socketn::Protocol::MacRaw as u8 && index == 0 => Status::MacRaw,
+            _ => Status::Closed,
+        };
+        socket.set_status(status);
+        socket.reset_pointers();
+        if status == Status::Udp {
+            let port = socket.port();
+            self.network.open_udp(index as u8, port);
+        }
+    }
+
+    /// Answer the peer of a TCP connection with a RST
+    fn reset_connection(&mut self, index: usize) {
+        match self.sockets[index].peer.take() {
+            Some(Peer::Socket(peer)) => {
+                let peer = &mut self.sockets[peer];
+                peer.peer = None;
+                peer.set_status(Status::Closed);
+                peer.interrupt(DISCONNECT);
+            }
+            Some(Peer::Network) => self.network.close(index as u8),
+            None => {}
+        }
+    }
+
+    fn connect(&mut self, index: usize) {
+        let socket = &self.sockets[index];
+        let remote_ip = socket.destination_ip();
+        let remote_port = socket.register_u16(socketn::DESTINATION_PORT);
+        let local_port = socket.port();
+
+        if !self.link_up || remote_ip != self.common_bytes(common::IP) {
+            let remote = SocketAddrV4::new(Ipv4Addr::from(remote_ip), remote_port);
+            let result = if self.link_up {
+                self.network.connect(index as u8, remote)
+            } else {
+                Err(ConnectError::TimedOut)
+            };
+            let socket = &mut self.sockets[index];
+            match result {
+                Ok(()) => {
+                    socket.peer = Some(Peer::Network);
+                    socket.set_status(Status::Established);
+                    socket.interrupt(CON);
+                }
+                Err(ConnectError::Refused) => {
+                    socket.set_status(Status::Closed);
+                    socket.interrupt(DISCONNECT);
+                }
+                Err(ConnectError::TimedOut) => {
+                    socket.set_status(Status::Closed);
+                    socket.interrupt(TIMEOUT);
+                }
+            }
+            return;
+        }
+
+        let listener = self.sockets.iter().position(|socket| {
+            socket.status() == Some(Status::Listen) && socket.port() == remote_port
+        });
+        let Some(listener) = listener else {
+            // Nobody listens on the port, the SYN is answered with a RST.
+            let socket = &mut self.sockets[index];
+            socket.set_status(Status::Closed);
+            socket.interrupt(DISCONNECT);
+            return;
+        };
+
+        let ip = self.common_bytes::<4>(common::IP);
+        let mac = self.common_bytes::<6>(common::MAC);
+        let destination_ip = usize::from(socketn::DESTINATION_IP);
+        let destination_mac = usize::from(socketn::DESTINATION_MAC);
+        for (this, peer) in [(index, listener), (listener, index)] {
+            let socket = &mut self.sockets[this];
+            socket.peer = Some(Peer::Socket(peer));
+            socket.set_status(Status::Established);
+            socket.interrupt(CON);
+            socket.registers[destination_mac..destination_mac + 6].copy_from_slice(&mac);
+        }
+        let listener = &mut self.sockets[listener];
+        listener.registers[destination_ip..destination_ip + 4].copy_from_slice(&ip);
+        listener.set_register_u16(socketn::DESTINATION_PORT, local_port);
+    }
+
+    fn disconnect(&mut self, index: usize) {
+        match self.sockets[index].status() {
+            Some(Status::Established) | Some(Status::CloseWait) if !self.link_up => {
+                self.time_out(index)
+            }
+            Some(Status::Established) => {
+                // The peer acknowledges the FIN and answers with its own FIN right away.
+                match self.sockets[index].peer.take() {
+                    Some(Peer::Socket(peer)) => {
+                        let peer = &mut self.sockets[peer];
+                        peer.peer = None;
+                        peer.set_status(Status::CloseWait);
+                        peer.interrupt(DISCONNECT);
+                    }
+                    Some(Peer::Network) => self.network.close(index as u8),
+                    None => {}
+                }
+                let socket = &mut self.sockets[index];
+                socket.set_status(Status::Closed);
+                socket.interrupt(DISCONNECT);
+            }
+            // The peer closed first and acknowledges the FIN.
+            Some(Status::CloseWait) => {
+                if self.sockets[index].peer.take() == Some(Peer::Network) {
+                    self.network.close(index as u8);
+                }
+                self.sockets[index].set_status(Status::Closed);
+            }
+            _ => {}
+        }
+    }
+
+    fn send(&mut self, index: usize) {
+        match self.sockets[index].status() {
+            Some(Status::Established) | Some(Status::CloseWait) => {
+                let socket = &mut self.sockets[index];
+                socket.send_end = socket.register_u16(socketn::TX_DATA_WRITE_POINTER);
+                if !self.link_up {
+                    self.time_out(index);
+                } else if socket.peer.is_some() {
+                    socket.interrupt(SEND_OK);
+                    self.transmit(index);
+                } else {
+                    // The peer is gone and answers with a RST.
+                    socket.take_all_tx();
+                    socket.set_status(Status::Closed);
+                    socket.interrupt(DISCONNECT);
+                }
+            }
+            Some(Status::Udp) => self.send_datagram(index),
+            Some(Status::IpRaw) => self.send_ip_packet(index),
+            Some(Status::MacRaw) => {
+                let socket = &mut self.sockets[index];
+                let frame = socket.take_all_tx();
+                socket.interrupt(SEND_OK);
+                self.frames.push(frame);
+            }
+            _ => {}
+        }
+    }
+
+    /// Give up on the TCP connection of socket `index`, whose peer doesn't acknowledge the
+    /// retransmitted data. The peer isn't told.
+    fn time_out(&mut self, index: usize) {
+        match self.sockets[index].peer.take() {
+            Some(Peer::Socket(peer)) => self.sockets[peer].peer = None,
+            Some(Peer::Network) => self.network.close(index as u8),
+            None => {}
+        }
+        let socket = &mut self.sockets[index];
+        socket.take_all_tx();
+        socket.set_status(Status::Closed);
+        socket.interrupt(TIMEOUT);
+    }
+
+    /// Move the sent TCP data of socket `index` to its peer, as far as the peer has room
+    fn transmit(&mut self, index: usize) {
+        let pending = self.sockets[index].pending_tx();
+        match self.sockets[index].peer {
+            Some(Peer::Socket(peer)) => {
+                let len = pending.min(self.sockets[peer].rx_free_size());
+                if len > 0 {
+                    let data = self.sockets[index].take_tx(len);
+                    self.sockets[peer].push_rx(&[&data]);
+                }
+            }
+            Some(Peer::Network) if pending > 0 => {
+                let data = self.sockets[index].peek_tx(pending);
+                let sent = self.network.send(index as u8, &data);
+                self.sockets[index].advance_tx(sent as u16);
+            }
+            _ => {}
+        }
+    }
+
+    /// Exchange the data of the sockets connected to the [`Network`]
+    fn poll_network(&mut self) {
+        for index in 0..self.sockets.len() {
+            let socket = &self.sockets[index];
+            match socket.status() {
+                Some(Status::Established) | Some(Status::CloseWait)
+                    if socket.peer == Some(Peer::Network) =>
+                {
+                    self.transmit(index);
+                    self.receive_stream(index);
+                }
+                Some(Status::Udp) => self.receive_datagrams(index),
+                _ => {}
+            }
+        }
+    }
+
+    fn receive_stream(&mut self, index: usize) {
+        let socket = &mut self.sockets[index];
+        let free_size = usize::from(socket.rx_free_size());
+        if socket.status() != Some(Status::Established) || free_size == 0 {
+            return;
+        }
+        let mut buffer = vec![0; free_size];
+        match self.network.receive(index as u8, &mut buffer) {
+            Ok(Some(0)) => {
+                // The peer sent its FIN.
+                socket.set_status(Status::CloseWait);
+                socket.interrupt(DISCONNECT);
+            }
+            Ok(Some(len)) => {
+                socket.push_rx(&[&buffer[..len]]);
+            }
+            Ok(None) => {}
+            Err(ConnectionReset) => {
+                socket.peer = None;
+                socket.set_status(Status::Closed);
+                socket.interrupt(DISCONNECT);
+                self.network.close(index as u8);
+            }
+        }
+    }
+
+    fn receive_datagrams(&mut self, index: usize) {
+        loop {
+            let socket = &mut self.sockets[index];
+            let Some(free_size) = usize::from(socket.rx_free_size()).checked_sub(8) else {
+                return;
+            };
+            let mut buffer = vec![0; free_size];
+            let Some((origin, len)) = self.network.receive_from(index as u8, &mut buffer) else {
+                return;
+            };
+            let mut header = [0u8; 8];
+            header[..4].copy_from_slice(&origin.ip().octets());
+            header[4..6].copy_from_slice(&origin.port().to_be_bytes());
+            header[6..].copy_from_slice(&(len as u16).to_be_bytes());
+            socket.push_rx(&[&header, &buffer[..len]]);
+        }
+    }
+
+    fn send_datagram(&mut self, index: usize) {
+        let ip = self.common_bytes::<4>(common::IP);
+        let subnet = self.common_bytes::<4>(common::SUBNET_MASK);
+        let socket = &mut self.sockets[index];
+        let data = socket.take_all_tx();
+        let remote_ip = socket.destination_ip();
+        let remote_port = socket.register_u16(socketn::DESTINATION_PORT);
+        let local_port = socket.port();
+
+        let broadcast = remote_ip == [255; 4]
+            || (0..4).all(|octet| remote_ip[octet] == ip[octet] | !subnet[octet]);
+        let multicast = remote_ip[0] & 0xF0 == 224;
+        if !self.link_up {
+            // The ARP request isn't answered.
+            self.sockets[index].interrupt(TIMEOUT);
+            return;
+        }
+        if !(broadcast || multicast || remote_ip == ip) {
+            let remote = SocketAddrV4::new(Ipv4Addr::from(remote_ip), remote_port);
+            let sent = self.network.send_to(index as u8, remote, &data);
+            self.sockets[index].interrupt(if sent { SEND_OK } else { TIMEOUT });
+            return;
+        }
+        self.sockets[index].interrupt(SEND_OK);
+        if broadcast {
+            let remote = SocketAddrV4::new(Ipv4Addr::from(remote_ip), remote_port);
+            self.network.send_to(index as u8, remote, &data);
+        }
+
+        let Ok(len) = u16::try_from(data.len()) else {
+            return;
+        };
+        let mut header = [0u8; 8];
+        header[..4].copy_from_slice(&ip);
+        header[4..6].copy_from_slice(&local_port.to_be_bytes());
+        header[6..].copy_from_slice(&len.to_be_bytes());
+        for (receiver, socket) in self.sockets.iter_mut().enumerate() {
+            let member = if multicast {
+                socket.registers[usize::from(socketn::MODE)] & socketn::MODE_MULTICAST != 0
+                    && socket.destination_ip() == remote_ip
+            } else {
+                true
+            };
+            let own = (broadcast || multicast) && receiver == index;
+            if socket.status() == Some(Status::Udp)
+                && socket.port() == remote_port
+                && member
+                && !own
+            {
+                socket.push_rx(&[&header, &data]);
+            }
+        }
+    }
+
+    fn send_ip_packet(&mut self, index: usize) {
+        let ip = self.common_bytes::<4>(common::IP);
+        let socket = &mut self.sockets[index];
+        let mut data = socket.take_all_tx();
+        let remote_ip = socket.destination_ip();
+        let protocol = socket.registers[usize::from(socketn::IP_PROTOCOL)];
+
+        if !self.link_up || remote_ip != ip {
+            // The ARP request isn't answered.
+            self.sockets[index].interrupt(TIMEOUT);
+            return;
+        }
+        self.sockets[index].interrupt(SEND_OK);
+
+        if protocol == ICMP && data.first() == Some(&ICMP_ECHO_REQUEST) && data.len() >= 4 {
+            if self.common[usize::from(common::MODE)] & MODE_PING_BLOCK != 0 {
+                return;
+            }
+            // The chip answers the request itself.
+            data[0] = ICMP_ECHO_REPLY;
+            data[2..4].fill(0);
+            let checksum = internet_checksum(&data);
+            data[2..4].copy_from_slice(&checksum.to_be_bytes());
+        }
+
+        let Ok(len) = u16::try_from(data.len()) else {
+            return;
+        };
+        let mut header = [0u8; 6];
+        header[..4].copy_from_slice(&ip);
+        header[4..].copy_from_slice(&len.to_be_bytes());
+        for socket in self.sockets.iter_mut() {
+            if socket.status() == Some(Status::IpRaw)
+                && socket.registers[usize::from(socketn::IP_PROTOCOL)] == protocol
+            {
+                socket.push_rx(&[&header, &data]);
+            }
+        }
+    }
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Net: Network> fmt::Debug for Emulator<Net> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Emulator")
+            .field("link_up", &self.link_up)
+            .field("phase", &self.phase)
+            .finish_non_exhaustive()
+    }
+}
+
+fn buffer_byte(buffer: &[u8], size: u16, address: u16) -> u8 {
+    if size == 0 {
+        return 0;
+    }
+    buffer[usize::from(address % size)]
+}
+
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|chunk| u32::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)])))
+        .sum::<u32>();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+impl<Net: Network> ErrorType for Emulator<Net> {
+    type Error = Infallible;
+}
+
+impl<Net: Network> SpiDevice for Emulator<Net> {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+        // A frame in variable data length mode ends when SCSn is deasserted.
+        self.phase = Phase::START;
+        for operation in operations {
+            self.shift_operation(operation);
+        }
+        self.phase = Phase::START;
+        Ok(())
+    }
+}
+
+impl<Net: Network> SpiBus for Emulator<Net> {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+        self.shift_operation(&mut Operation::Read(words));
+        Ok(())
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+        self.shift_operation(&mut Operation::Write(words));
+        Ok(())
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Infallible> {
+        self.shift_transfer(read, write);
+        Ok(())
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+        self.shift_operation(&mut Operation::TransferInPlace(words));
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+impl<Net: Network> Bus for Emulator<Net> {
+    type Error = Infallible;
+
+    fn read_frame(&mut self, block: u8, address: u16, data: &mut [u8]) -> Result<(), Infallible> {
+        self.poll_network();
+        for (offset, byte) in data.iter_mut().enumerate() {
+            *byte = self.read_byte(block, address.wrapping_add(offset as u16));
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, block: u8, address: u16, data: &[u8]) -> Result<(), Infallible> {
+        self.poll_network();
+        for (offset, byte) in data.iter().enumerate() {
+            self.write_byte(block, address.wrapping_add(offset as u16), *byte);
+        }
+        Ok(())
+    }
+}
+
+/// Lets a test access the emulator, e.g. to inject frames, while the device owns the bus
+#[cfg(test)]
+pub(crate) struct Shared<'a>(pub(crate) &'a core::cell::RefCell<Emulator>);
+
+#[cfg(test)]
+impl ErrorType for Shared<'_> {
+    type Error = Infallible;
+}
+
+#[cfg(test)]
+impl SpiDevice for Shared<'_> {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+        self.0.borrow_mut().transaction(operations)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::cell::RefCell;
+    use core::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use embedded_nal::nb;
+
+    use super::*;
+    use crate::bus::{FourWire, ThreeWire};
+    use crate::raw_device::RawConfig;
+    use crate::tcp::TcpSocketError;
+    use crate::{MacAddress, Mode, UninitializedDevice};
+
+    const MAC: MacAddress = MacAddress {
+        octets: [0x02, 0x00, 0x00, 0x00, 0x00, 0x01],
+    };
+    const IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+    #[test]
+    fn test_tcp_connection() {
+        use embedded_nal::{TcpClientStack, TcpFullStack};
+
+        let mut device = UninitializedDevice::new(FourWire::new(Emulator::new()))
+            .initialize_manual(MAC, IP, Mode::default())
+            .unwrap();
+
+        let mut listener = device.socket().unwrap();
+        device.bind(&mut listener, 8080).unwrap();
+        device.listen(&mut listener).unwrap();
+
+        let mut client = device.socket().unwrap();
+        let remote = SocketAddr::new(IpAddr::V4(IP), 8080);
+        assert!(matches!(
+            device.connect(&mut client, remote),
+            Err(nb::Error::WouldBlock)
+        ));
+        device.connect(&mut client, remote).unwrap();
+        let (mut server, peer) = nb::block!(device.accept(&mut listener)).unwrap();
+        assert_eq!(peer, client.local_addr(&mut device).unwrap());
+
+        let mut buffer = [0; 64];
+        assert!(matches!(
+            device.receive(&mut server, &mut buffer),
+            Err(nb::Error::WouldBlock)
+        ));
+        assert_eq!(nb::block!(device.send(&mut client, b"ping")).unwrap(), 4);
+        let len = nb::block!(device.receive(&mut server, &mut buffer)).unwrap();
+        assert_eq!(&buffer[..len], b"ping");
+        assert_eq!(nb::block!(device.send(&mut server, b"pong")).unwrap(), 4);
+        let len = nb::block!(device.receive(&mut client, &mut buffer)).unwrap();
+        assert_eq!(&buffer[..len], b"pong");
+
+        device.close(client).unwrap();
+        assert_eq!(
+            nb::block!(device.receive(&mut server, &mut buffer)).unwrap(),
+            0
+        );
+        device.close(server).unwrap();
+
+        let mut client = device.socket().unwrap();
+        let refused = SocketAddr::new(IpAddr::V4(IP), 8081);
+        assert!(matches!(
+            nb::block!(device.connect(&mut client, refused)),
+            Err(TcpSocketError::ConnectionRefused)
+        ));
+        let unreachable = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 3)), 8080);
+        assert!(matches!(
+            nb::block!(device.connect(&mut client, unreachable)),
+            Err(TcpSocketError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn test_udp_buffer_wraparound() {
+        use embedded_nal::{UdpClientStack, UdpFullStack};
+
+        let mut device = UninitializedDevice::new(FourWire::new(Emulator::new()))
+            .initialize_manual(MAC, IP, Mode::default())
+            .unwrap();
+
+        let mut receiver = device.socket().unwrap();
+        device.bind(&mut receiver, 5000).unwrap();
+        let mut sender = UdpClientStack::socket(&mut device).unwrap();
+        UdpClientStack::connect(
+            &mut device,
+            &mut sender,
+            SocketAddr::new(IpAddr::V4(IP), 5000),
+        )
+        .unwrap();
+        let origin = sender.local_addr(&mut device).unwrap();
+
+        // 50 datagrams of 100 bytes wrap around the 2 KB buffers a few times.
+        let mut buffer = [0; 128];
+        for round in 0..50u8 {
+            let datagram = [round; 100];
+            nb::block!(UdpClientStack::send(&mut device, &mut sender, &datagram)).unwrap();
+            let (len, from) = nb::block!(UdpClientStack::receive(
+                &mut device,
+                &mut receiver,
+                &mut buffer
+            ))
+            .unwrap();
+            assert_eq!(&buffer[..len], &datagram[..]);
+            assert_eq!(from, origin);
+        }
+    }
+
+    #[test]
+    fn test_three_wire_and_ping() {
+        let mut device = UninitializedDevice::new(ThreeWire::new(Emulator::new()))
+            .initialize_manual(MAC, IP, Mode::default())
+            .unwrap();
+        assert_eq!(device.ip().unwrap(), IP);
+        assert_eq!(device.mac().unwrap(), MAC);
+
+        let mut pinger = crate::ping::Pinger::new();
+        let reply =
+            nb::block!(device.ping(&mut pinger, IpAddr::V4(IP), b"w5500", 1_000, 0)).unwrap();
+        assert_eq!(reply.from, IP);
+    }
+
+    #[test]
+    fn test_interrupt_pin() {
+        use core::cell::Cell;
+
+        use embedded_nal::{TcpClientStack, TcpFullStack, UdpClientStack, UdpFullStack};
+
+        use crate::interrupt::PolledPin;
+
+        /// INTn of the emulator, counting how often it was found asserted
+        struct Int<'a>(&'a RefCell<Emulator>, &'a Cell<usize>);
+
+        impl embedded_hal::digital::ErrorType for Int<'_> {
+            type Error = Infallible;
+        }
+
+        impl embedded_hal::digital::InputPin for Int<'_> {
+            fn is_high(&mut self) -> Result<bool, Infallible> {
+                self.is_low().map(|low| !low)
+            }
+
+            fn is_low(&mut self) -> Result<bool, Infallible> {
+                let asserted = self.0.borrow().interrupt_asserted();
+                self.1.set(self.1.get() + usize::from(asserted));
+                Ok(asserted)
+            }
+        }
+
+        /// Nothing changes while the emulator isn't accessed, so waiting would never end
+        struct Forever;
+
+        impl embedded_hal::delay::DelayNs for Forever {
+            fn delay_ns(&mut self, _ns: u32) {
+                panic!("waiting while INTn is deasserted");
+            }
+        }
+
+        let emulator = RefCell::new(Emulator::new());
+        let wakeups = Cell::new(0);
+        let mut device = UninitializedDevice::new(FourWire::new(Shared(&emulator)))
+            .initialize_manual(MAC, IP, Mode::default())
+            .unwrap()
+            .with_interrupt_pin(PolledPin::new(Int(&emulator, &wakeups), Forever, 100))
+            .unwrap();
+        let asserted = || emulator.borrow().interrupt_asserted();
+
+        /// Retry `operation` like `nb::block!`, but wait on INTn in between
+        macro_rules! block {
+            ($operation:expr) => {
+                loop {
+                    match $operation {
+                        Err(nb::Error::WouldBlock) => device.wait_for_interrupt(),
+                        Err(nb::Error::Other(error)) => break Err(error),
+                        Ok(value) => break Ok(value),
+                    }
+                }
+            };
+        }
+
+        let mut receiver = UdpClientStack::socket(&mut device).unwrap();
+        UdpFullStack::bind(&mut device, &mut receiver, 5000).unwrap();
+        let mut sender = UdpClientStack::socket(&mut device).unwrap();
+        let remote = SocketAddr::new(IpAddr::V4(IP), 5000);
+        UdpClientStack::connect(&mut device, &mut sender, remote).unwrap();
+        let mut buffer = [0; 8];
+        assert!(matches!(
+            UdpClientStack::receive(&mut device, &mut receiver, &mut buffer),
+            Err(nb::Error::WouldBlock)
+        ));
+        assert!(!asserted());
+
+        // RECV stays pending until the last datagram was read.
+        block!(UdpClientStack::send(&mut device, &mut sender, b"one")).unwrap();
+        block!(UdpClientStack::send(&mut device, &mut sender, b"two")).unwrap();
+        assert!(asserted());
+        let (len, _) = block!(UdpClientStack::receive(
+            &mut device,
+            &mut receiver,
+            &mut buffer
+        ))
+        .unwrap();
+        assert_eq!(&buffer[..len], b"one");
+        assert!(asserted());
+        let (len, _) = block!(UdpClientStack::receive(
+            &mut device,
+            &mut receiver,
+            &mut buffer
+        ))
+        .unwrap();
+        assert_eq!(&buffer[..len], b"two");
+        assert!(!asserted());
+
+        let mut listener = TcpClientStack::socket(&mut device).unwrap();
+        TcpFullStack::bind(&mut device, &mut listener, 80).unwrap();
+        device.listen(&mut listener).unwrap();
+        let mut client = TcpClientStack::socket(&mut device).unwrap();
+        let remote = SocketAddr::new(IpAddr::V4(IP), 80);
+        block!(TcpClientStack::connect(&mut device, &mut client, remote)).unwrap();
+        let (mut server, _) = block!(device.accept(&mut listener)).unwrap();
+        block!(TcpClientStack::send(&mut device, &mut client, b"ping")).unwrap();
+        let len = block!(TcpClientStack::receive(
+            &mut device,
+            &mut server,
+            &mut buffer
+        ))
+        .unwrap();
+        assert_eq!(&buffer[..len], b"ping");
+        block!(device.tcp_flush(&mut client)).unwrap();
+
+        TcpClientStack::close(&mut device, client).unwrap();
+        let len = block!(TcpClientStack::receive(
+            &mut device,
+            &mut server,
+            &mut buffer
+        ))
+        .unwrap();
+        assert_eq!(len, 0);
+        TcpClientStack::close(&mut device, server).unwrap();
+        TcpClientStack::close(&mut device, listener).unwrap();
+        UdpClientStack::close(&mut device, sender).unwrap();
+        UdpClientStack::close(&mut device, receiver).unwrap();
+
+        assert!(wakeups.get() > 0);
+        assert!(!asserted());
+    }
+}
@@ -0,0 +1,257 @@
+//! Error kinds shared by the driver's errors and an error type covering all of them.
+//!
+//! Each error of the driver has a `kind()` that classifies it independently of the operation
+//! that failed. [`Error`] wraps any of them, so application code can use `?` across TCP, UDP,
+//! IPRAW and raw bus calls:
+//!
+//! ```no_run
+//! # use core::net::SocketAddr;
+//! use embedded_nal::{nb, TcpClientStack, UdpClientStack};
+//!
+//! fn report<SpiBus: w5500::bus::Bus, HostImpl: w5500::Host>(
+//!     device: &mut w5500::Device<SpiBus, w5500::DeviceState<HostImpl>>,
+//!     server: SocketAddr,
+//!     collector: SocketAddr,
+//! ) -> Result<(), w5500::Error<SpiBus::Error>> {
+//!     let mut udp = UdpClientStack::socket(device)?;
+//!     UdpClientStack::connect(device, &mut udp, collector)?;
+//!     nb::block!(UdpClientStack::send(device, &mut udp, b"hello"))?;
+//!
+//!     let mut tcp = TcpClientStack::socket(device)?;
+//!     nb::block!(TcpClientStack::connect(device, &mut tcp, server))?;
+//!     Ok(())
+//! }
+//! ```
+use core::fmt::{self, Debug, Display};
+
+use crate::dns::DnsError;
+use crate::ipraw::IpRawSocketError;
+use crate::ping::PingError;
+use crate::tcp::TcpSocketError;
+use crate::udp::UdpSocketError;
+use crate::{InitializeError, ResetError, SetBufferLayoutError};
+
+/// The kind of an error, independent of the operation that failed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The SPI bus reported an error
+    Bus,
+    /// The chip didn't respond or reported an unexpected version
+    NoChip,
+    /// All sockets are in use
+    NoMoreSockets,
+    /// An argument is invalid, e.g. an IPv6 address or a payload that is too large
+    InvalidInput,
+    /// The socket is in a mode that doesn't support the operation
+    Unsupported,
+    /// The socket isn't connected (anymore) or hasn't been opened
+    NotConnected,
+    /// The peer refused the connection
+    ConnectionRefused,
+    /// The peer or the chip didn't answer in time
+    TimedOut,
+    /// The local port is already in use
+    AddrInUse,
+    /// The Ethernet link is down
+    LinkDown,
+    /// A DNS name doesn't exist
+    NotFound,
+    Other,
+}
+
+/// The message of the `UnsupportedAddress` errors, all of kind [`ErrorKind::InvalidInput`]
+pub(crate) const UNSUPPORTED_ADDRESS: &str = "only IPv4 is supported";
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorKind::Bus => "SPI bus error",
+            ErrorKind::NoChip => "W5500 not responding",
+            ErrorKind::NoMoreSockets => "no more sockets available",
+            ErrorKind::InvalidInput => "invalid input",
+            ErrorKind::Unsupported => "operation not supported by the socket",
+            ErrorKind::NotConnected => "socket not connected",
+            ErrorKind::ConnectionRefused => "connection refused",
+            ErrorKind::TimedOut => "timed out",
+            ErrorKind::AddrInUse => "port already in use",
+            ErrorKind::LinkDown => "Ethernet link down",
+            ErrorKind::NotFound => "name not found",
+            ErrorKind::Other => "other error",
+        })
+    }
+}
+
+impl From<ErrorKind> for embedded_nal::TcpErrorKind {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::NotConnected => embedded_nal::TcpErrorKind::PipeClosed,
+            _ => embedded_nal::TcpErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl From<ErrorKind> for embedded_io_async::ErrorKind {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::InvalidInput => embedded_io_async::ErrorKind::InvalidInput,
+            ErrorKind::Unsupported => embedded_io_async::ErrorKind::Unsupported,
+            ErrorKind::NotConnected => embedded_io_async::ErrorKind::NotConnected,
+            ErrorKind::ConnectionRefused => embedded_io_async::ErrorKind::ConnectionRefused,
+            ErrorKind::TimedOut => embedded_io_async::ErrorKind::TimedOut,
+            ErrorKind::AddrInUse => embedded_io_async::ErrorKind::AddrInUse,
+            ErrorKind::LinkDown => embedded_io_async::ErrorKind::ConnectionAborted,
+            ErrorKind::NotFound => embedded_io_async::ErrorKind::NotFound,
+            _ => embedded_io_async::ErrorKind::Other,
+        }
+    }
+}
+
+/// Any error of the driver, with the bus error `E`
+///
+/// The errors of the individual operations convert into it with `?`, as do bus errors, e.g. of
+/// the [`RawDevice`](crate::raw_device::RawDevice).
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E: Debug> {
+    Bus(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] E),
+    Initialize(InitializeError<E>),
+    Reset(ResetError<E>),
+    BufferLayout(SetBufferLayoutError<E>),
+    Tcp(TcpSocketError<E>),
+    Udp(UdpSocketError<E>),
+    IpRaw(IpRawSocketError<E>),
+    Ping(PingError<E>),
+    Dns(DnsError<E>),
+}
+
+impl<E: Debug> Error<E> {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Bus(_) => ErrorKind::Bus,
+            Error::Initialize(error) => error.kind(),
+            Error::Reset(error) => error.kind(),
+            Error::BufferLayout(error) => error.kind(),
+            Error::Tcp(error) => error.kind(),
+            Error::Udp(error) => error.kind(),
+            Error::IpRaw(error) => error.kind(),
+            Error::Ping(error) => error.kind(),
+            Error::Dns(error) => error.kind(),
+        }
+    }
+
+    /// The error of the SPI bus, if the bus failed
+    ///
+    /// Bus errors are only required to implement `Debug`, so it's not available as
+    /// [`source`](core::error::Error::source).
+    pub fn bus_error(&self) -> Option<&E> {
+        match self {
+            Error::Bus(error)
+            | Error::Initialize(InitializeError::SpiError(error))
+            | Error::Reset(ResetError::Other(error))
+            | Error::BufferLayout(SetBufferLayoutError::Other(error))
+            | Error::Tcp(TcpSocketError::Other(error))
+            | Error::Udp(UdpSocketError::Other(error))
+            | Error::IpRaw(IpRawSocketError::Other(error))
+            | Error::Ping(PingError::Socket(IpRawSocketError::Other(error)))
+            | Error::Dns(DnsError::Socket(UdpSocketError::Other(error))) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl<E: Debug> Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Bus(error) => write!(f, "{}: {:?}", ErrorKind::Bus, error),
+            Error::Initialize(error) => Display::fmt(error, f),
+            Error::Reset(error) => Display::fmt(error, f),
+            Error::BufferLayout(error) => Display::fmt(error, f),
+            Error::Tcp(error) => Display::fmt(error, f),
+            Error::Udp(error) => Display::fmt(error, f),
+            Error::IpRaw(error) => Display::fmt(error, f),
+            Error::Ping(error) => Display::fmt(error, f),
+            Error::Dns(error) => Display::fmt(error, f),
+        }
+    }
+}
+
+impl<E: Debug> core::error::Error for Error<E> {}
+
+impl<E: Debug> From<E> for Error<E> {
+    fn from(error: E) -> Self {
+        Error::Bus(error)
+    }
+}
+
+impl<E: Debug> From<InitializeError<E>> for Error<E> {
+    fn from(error: InitializeError<E>) -> Self {
+        Error::Initialize(error)
+    }
+}
+
+impl<E: Debug> From<ResetError<E>> for Error<E> {
+    fn from(error: ResetError<E>) -> Self {
+        Error::Reset(error)
+    }
+}
+
+impl<E: Debug> From<SetBufferLayoutError<E>> for Error<E> {
+    fn from(error: SetBufferLayoutError<E>) -> Self {
+        Error::BufferLayout(error)
+    }
+}
+
+impl<E: Debug> From<TcpSocketError<E>> for Error<E> {
+    fn from(error: TcpSocketError<E>) -> Self {
+        Error::Tcp(error)
+    }
+}
+
+impl<E: Debug> From<UdpSocketError<E>> for Error<E> {
+    fn from(error: UdpSocketError<E>) -> Self {
+        Error::Udp(error)
+    }
+}
+
+impl<E: Debug> From<IpRawSocketError<E>> for Error<E> {
+    fn from(error: IpRawSocketError<E>) -> Self {
+        Error::IpRaw(error)
+    }
+}
+
+impl<E: Debug> From<PingError<E>> for Error<E> {
+    fn from(error: PingError<E>) -> Self {
+        Error::Ping(error)
+    }
+}
+
+impl<E: Debug> From<DnsError<E>> for Error<E> {
+    fn from(error: DnsError<E>) -> Self {
+        Error::Dns(error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kind_and_bus_error() {
+        let error: Error<u8> = DnsError::Socket(UdpSocketError::Other(3)).into();
+        assert_eq!(error.kind(), ErrorKind::Bus);
+        assert_eq!(error.bus_error(), Some(&3));
+        assert_eq!(error.to_string(), "SPI bus error: 3");
+
+        let error: Error<u8> = TcpSocketError::ConnectionRefused.into();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(error.bus_error(), None);
+        assert_eq!(
+            embedded_nal::TcpErrorKind::from(error.kind()),
+            embedded_nal::TcpErrorKind::Other
+        );
+        assert_eq!(error.to_string(), "connection refused");
+    }
+}
@@ -1,32 +1,848 @@
+use core::net::{Ipv4Addr, SocketAddrV4};
+
 use crate::bus::Bus;
-use crate::host::Host;
+use crate::device::{Device, State};
+use crate::host::{Host, HostConfig};
+use crate::interrupt::InterruptPin;
+use crate::port::Transport;
+use crate::socket::Socket;
+use crate::udp::{NbError, UdpSocket, UdpSocketError};
 use crate::MacAddress;
 
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+
+/// The magic cookie marking the start of the DHCP options (RFC 2131, section 3)
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// Offset of the options field, directly after the magic cookie
+const OPTIONS_OFFSET: usize = 240;
+
+/// Minimum size of a BOOTP message, DHCP messages are padded up to this length
+const MESSAGE_LEN: usize = 300;
+
+/// Maximum DHCP message size a client has to be able to receive (RFC 2131, section 2)
+const MAX_MESSAGE_LEN: usize = 576;
+
+/// Retransmission timeout of DISCOVER and REQUEST messages, doubled on every retry
+const INITIAL_RETRY_MS: u64 = 4_000;
+const MAX_RETRY_MS: u64 = 64_000;
+
+/// Number of REQUEST retransmissions before starting over with a DISCOVER
+const MAX_REQUEST_RETRIES: u8 = 4;
+
+/// Lower bound of the retransmission timeout while renewing or rebinding (RFC 2131, section 4.4.5)
+const MIN_RENEW_RETRY_MS: u64 = 60_000;
+
+mod option {
+    pub const PAD: u8 = 0;
+    pub const SUBNET_MASK: u8 = 1;
+    pub const ROUTER: u8 = 3;
+    pub const DNS_SERVER: u8 = 6;
+    pub const REQUESTED_IP: u8 = 50;
+    pub const LEASE_TIME: u8 = 51;
+    pub const MESSAGE_TYPE: u8 = 53;
+    pub const SERVER_ID: u8 = 54;
+    pub const PARAMETER_REQUEST_LIST: u8 = 55;
+    pub const RENEWAL_TIME: u8 = 58;
+    pub const REBINDING_TIME: u8 = 59;
+    pub const CLIENT_ID: u8 = 61;
+    pub const END: u8 = 255;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Ack = 5,
+    Nak = 6,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Discover),
+            2 => Some(Self::Offer),
+            3 => Some(Self::Request),
+            5 => Some(Self::Ack),
+            6 => Some(Self::Nak),
+            _ => None,
+        }
+    }
+}
+
+/// Events reported by [`Device::poll_dhcp`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DhcpEvent {
+    /// A lease was acquired (or renewed with different settings) and written to the chip.
+    Configured(HostConfig),
+    /// The lease expired or was revoked by the server. The chip has no IP address anymore.
+    Deconfigured,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum DhcpState {
+    /// No lease, a DISCOVER has to be sent
+    Init,
+    /// DISCOVER sent, waiting for an OFFER
+    Selecting,
+    /// REQUEST sent for an OFFER, waiting for the ACK
+    Requesting {
+        #[cfg_attr(feature = "defmt", defmt(Display2Format))]
+        server: Ipv4Addr,
+        #[cfg_attr(feature = "defmt", defmt(Display2Format))]
+        offered: Ipv4Addr,
+    },
+    /// A lease is active and T1 has not yet passed
+    Bound,
+    /// T1 passed, renewing the lease with the server that handed it out
+    Renewing,
+    /// T2 passed, trying to extend the lease with any server
+    Rebinding,
+}
+
+/// An acquired lease. All points in time are in milliseconds as passed to [`Device::poll_dhcp`].
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct Lease {
+    #[cfg_attr(feature = "defmt", defmt(Display2Format))]
+    server: Ipv4Addr,
+    renew_at: u64,
+    rebind_at: u64,
+    expires_at: u64,
+}
+
+/// Dynamic host configuration
+///
+/// The lease is negotiated by calling [`Device::poll_dhcp`] periodically. While negotiating,
+/// renewing or rebinding, a socket is taken from the device. It is released once a lease has been
+/// acquired.
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Dhcp {
-    // settings: HostConfig,
-    // current: HostConfig,
+    is_setup: bool,
+    settings: HostConfig,
+    current: HostConfig,
+    state: DhcpState,
+    lease: Option<Lease>,
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    dns_servers: [Option<Ipv4Addr>; 3],
+    socket: Option<UdpSocket>,
+    xid: u32,
+    retries: u8,
+    retry_interval: u64,
+    retry_at: u64,
 }
 
 impl Dhcp {
-    pub fn new(_mac: MacAddress) -> Self {
-        // let settings = HostConfig {
-        //     mac,
-        //     ..HostConfig::default()
-        // };
+    pub fn new(mac: MacAddress) -> Self {
+        let settings = HostConfig {
+            mac,
+            ..HostConfig::default()
+        };
         Self {
-            // settings,
-            // current: HostConfig::default(),
+            is_setup: false,
+            settings,
+            current: HostConfig::default(),
+            state: DhcpState::Init,
+            lease: None,
+            dns_servers: [None; 3],
+            socket: None,
+            xid: 0,
+            retries: 0,
+            retry_interval: INITIAL_RETRY_MS,
+            retry_at: 0,
+        }
+    }
+
+    /// The settings currently written to the chip
+    pub fn current(&self) -> &HostConfig {
+        &self.current
+    }
+
+    /// Whether a lease is currently held
+    pub fn is_bound(&self) -> bool {
+        self.lease.is_some()
+    }
+
+    /// DNS servers announced by the DHCP server (option 6)
+    pub fn dns_servers(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.dns_servers.iter().flatten().copied()
+    }
+
+    /// Drop the current lease and start over with a DISCOVER on the next poll.
+    pub fn restart(&mut self) {
+        self.state = DhcpState::Init;
+        self.lease = None;
+    }
+
+    fn wants_socket(&self, now: u64) -> bool {
+        self.socket.is_none()
+            && match (self.state, self.lease) {
+                (DhcpState::Bound, Some(lease)) => now >= lease.renew_at,
+                _ => true,
+            }
+    }
+
+    fn attach_socket<SpiBus: Bus>(
+        &mut self,
+        bus: &mut SpiBus,
+        socket: Socket,
+    ) -> Result<(), UdpSocketError<SpiBus::Error>> {
+        let mut socket = UdpSocket::new(socket, CLIENT_PORT);
+        socket.open(bus)?;
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    fn detach_socket<SpiBus: Bus>(
+        &mut self,
+        bus: &mut SpiBus,
+    ) -> Result<Option<Socket>, UdpSocketError<SpiBus::Error>> {
+        if self.state != DhcpState::Bound {
+            return Ok(None);
+        }
+        match self.socket.take() {
+            Some(socket) => {
+                socket.socket_close(bus)?;
+                Ok(Some(socket.socket))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn schedule_retry(&mut self, now: u64, interval: u64) {
+        self.retry_interval = interval;
+        self.retry_at = now.saturating_add(interval);
+    }
+
+    fn poll<SpiBus: Bus>(
+        &mut self,
+        bus: &mut SpiBus,
+        interrupt_pin: &mut impl InterruptPin,
+        now: u64,
+    ) -> Result<Option<DhcpEvent>, UdpSocketError<SpiBus::Error>> {
+        match self.state {
+            DhcpState::Init => {
+                self.xid = self.next_xid(now);
+                self.retries = 0;
+                self.send(bus, interrupt_pin, MessageType::Discover, None)?;
+                self.state = DhcpState::Selecting;
+                self.schedule_retry(now, INITIAL_RETRY_MS);
+            }
+            DhcpState::Selecting => {
+                if let Some(reply) = self.receive(bus)? {
+                    if let (MessageType::Offer, Some(server)) = (reply.kind, reply.server) {
+                        let offered = reply.yiaddr;
+                        self.retries = 0;
+                        self.send(
+                            bus,
+                            interrupt_pin,
+                            MessageType::Request,
+                            Some((server, offered)),
+                        )?;
+                        self.state = DhcpState::Requesting { server, offered };
+                        self.schedule_retry(now, INITIAL_RETRY_MS);
+                    }
+                } else if now >= self.retry_at {
+                    self.send(bus, interrupt_pin, MessageType::Discover, None)?;
+                    self.schedule_retry(now, (self.retry_interval * 2).min(MAX_RETRY_MS));
+                }
+            }
+            DhcpState::Requesting { server, offered } => match self.receive(bus)? {
+                Some(reply) if reply.kind == MessageType::Ack => {
+                    return self.bind(bus, &reply, now);
+                }
+                Some(reply) if reply.kind == MessageType::Nak => {
+                    self.state = DhcpState::Init;
+                }
+                _ if now >= self.retry_at => {
+                    if self.retries < MAX_REQUEST_RETRIES {
+                        self.retries += 1;
+                        self.send(
+                            bus,
+                            interrupt_pin,
+                            MessageType::Request,
+                            Some((server, offered)),
+                        )?;
+                        self.schedule_retry(now, (self.retry_interval * 2).min(MAX_RETRY_MS));
+                    } else {
+                        self.state = DhcpState::Init;
+                    }
+                }
+                _ => {}
+            },
+            DhcpState::Bound | DhcpState::Renewing | DhcpState::Rebinding => {
+                let lease = match self.lease {
+                    Some(lease) => lease,
+                    None => {
+                        self.state = DhcpState::Init;
+                        return Ok(None);
+                    }
+                };
+
+                if now >= lease.expires_at {
+                    return self.unbind(bus);
+                }
+
+                // The socket is only held while renewing or rebinding
+                if self.socket.is_some() {
+                    match self.receive(bus)? {
+                        Some(reply) if reply.kind == MessageType::Ack => {
+                            return self.bind(bus, &reply, now);
+                        }
+                        Some(reply) if reply.kind == MessageType::Nak => {
+                            return self.unbind(bus);
+                        }
+                        _ => {}
+                    }
+                }
+
+                if self.state == DhcpState::Bound && now >= lease.renew_at {
+                    self.xid = self.next_xid(now);
+                    self.state = DhcpState::Renewing;
+                    self.retry_at = now;
+                }
+                if self.state == DhcpState::Renewing && now >= lease.rebind_at {
+                    self.state = DhcpState::Rebinding;
+                    self.retry_at = now;
+                }
+
+                if self.state != DhcpState::Bound && now >= self.retry_at {
+                    let (deadline, destination) = match self.state {
+                        DhcpState::Renewing => (lease.rebind_at, Some(lease.server)),
+                        _ => (lease.expires_at, None),
+                    };
+                    self.send_extend(bus, interrupt_pin, destination)?;
+                    // Retransmit after half of the remaining time (RFC 2131, section 4.4.5)
+                    self.schedule_retry(now, ((deadline - now) / 2).max(MIN_RENEW_RETRY_MS));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn bind<SpiBus: Bus>(
+        &mut self,
+        bus: &mut SpiBus,
+        reply: &Reply,
+        now: u64,
+    ) -> Result<Option<DhcpEvent>, UdpSocketError<SpiBus::Error>> {
+        let server = reply
+            .server
+            .or(self.lease.map(|lease| lease.server))
+            .unwrap_or(Ipv4Addr::BROADCAST);
+
+        // A lease time of 0xFFFFFFFF means infinity
+        let lease_time = reply.lease_time.unwrap_or(u32::MAX);
+        let (renew_at, rebind_at, expires_at) = if lease_time == u32::MAX {
+            (u64::MAX, u64::MAX, u64::MAX)
+        } else {
+            let lease_ms = u64::from(lease_time) * 1000;
+            let renew_ms = reply
+                .renewal_time
+                .map(|t| u64::from(t) * 1000)
+                .unwrap_or(lease_ms / 2);
+            let rebind_ms = reply
+                .rebinding_time
+                .map(|t| u64::from(t) * 1000)
+                .unwrap_or(lease_ms * 7 / 8);
+            (
+                now.saturating_add(renew_ms),
+                now.saturating_add(rebind_ms),
+                now.saturating_add(lease_ms),
+            )
+        };
+
+        self.lease = Some(Lease {
+            server,
+            renew_at,
+            rebind_at,
+            expires_at,
+        });
+        self.state = DhcpState::Bound;
+        self.dns_servers = reply.dns_servers;
+
+        let previous = self.current;
+        self.settings.ip = reply.yiaddr;
+        self.settings.subnet = reply.subnet.unwrap_or(Ipv4Addr::new(255, 255, 255, 0));
+        self.settings.gateway = reply.router.unwrap_or(Ipv4Addr::UNSPECIFIED);
+        Self::write_settings(bus, &mut self.current, &self.settings)?;
+
+        if previous != self.current {
+            Ok(Some(DhcpEvent::Configured(self.current)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn unbind<SpiBus: Bus>(
+        &mut self,
+        bus: &mut SpiBus,
+    ) -> Result<Option<DhcpEvent>, UdpSocketError<SpiBus::Error>> {
+        self.restart();
+        self.dns_servers = [None; 3];
+        self.settings.ip = Ipv4Addr::UNSPECIFIED;
+        self.settings.subnet = Ipv4Addr::UNSPECIFIED;
+        self.settings.gateway = Ipv4Addr::UNSPECIFIED;
+        Self::write_settings(bus, &mut self.current, &self.settings)?;
+        Ok(Some(DhcpEvent::Deconfigured))
+    }
+
+    fn next_xid(&self, now: u64) -> u32 {
+        let mac = self.settings.mac.octets;
+        let seed = u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]]);
+        // Any value that differs between clients and transactions will do
+        (seed ^ (now as u32))
+            .wrapping_mul(0x9E37_79B9)
+            .wrapping_add(self.xid)
+    }
+
+    fn send<SpiBus: Bus>(
+        &mut self,
+        bus: &mut SpiBus,
+        interrupt_pin: &mut impl InterruptPin,
+        kind: MessageType,
+        selected: Option<(Ipv4Addr, Ipv4Addr)>,
+    ) -> Result<(), UdpSocketError<SpiBus::Error>> {
+        let mut message = Message::new(self.xid, self.settings.mac, Ipv4Addr::UNSPECIFIED, true);
+        message.option(option::MESSAGE_TYPE, &[kind as u8]);
+        if let Some((server, offered)) = selected {
+            message.option(option::REQUESTED_IP, &offered.octets());
+            message.option(option::SERVER_ID, &server.octets());
+        }
+        self.transmit(bus, interrupt_pin, message, Ipv4Addr::BROADCAST)
+    }
+
+    /// Send a REQUEST to extend the current lease, to `server` when renewing or broadcast when
+    /// rebinding.
+    fn send_extend<SpiBus: Bus>(
+        &mut self,
+        bus: &mut SpiBus,
+        interrupt_pin: &mut impl InterruptPin,
+        server: Option<Ipv4Addr>,
+    ) -> Result<(), UdpSocketError<SpiBus::Error>> {
+        let mut message = Message::new(self.xid, self.settings.mac, self.current.ip, false);
+        message.option(option::MESSAGE_TYPE, &[MessageType::Request as u8]);
+        self.transmit(
+            bus,
+            interrupt_pin,
+            message,
+            server.unwrap_or(Ipv4Addr::BROADCAST),
+        )
+    }
+
+    fn transmit<SpiBus: Bus>(
+        &mut self,
+        bus: &mut SpiBus,
+        interrupt_pin: &mut impl InterruptPin,
+        mut message: Message,
+        destination: Ipv4Addr,
+    ) -> Result<(), UdpSocketError<SpiBus::Error>> {
+        let mac = self.settings.mac.octets;
+        message.option(
+            option::CLIENT_ID,
+            &[1, mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]],
+        );
+        message.option(
+            option::PARAMETER_REQUEST_LIST,
+            &[
+                option::SUBNET_MASK,
+                option::ROUTER,
+                option::DNS_SERVER,
+                option::LEASE_TIME,
+                option::RENEWAL_TIME,
+                option::REBINDING_TIME,
+            ],
+        );
+        let buffer = message.finish();
+
+        let socket = self.socket.as_mut().ok_or(UdpSocketError::SocketNotOpen)?;
+        let remote = SocketAddrV4::new(destination, SERVER_PORT);
+        match socket.socket_send_to(bus, interrupt_pin, remote, buffer) {
+            Ok(()) | Err(NbError::WouldBlock) => Ok(()),
+            Err(NbError::Other(error)) => Err(error),
+        }
+    }
+
+    fn receive<SpiBus: Bus>(
+        &mut self,
+        bus: &mut SpiBus,
+    ) -> Result<Option<Reply>, UdpSocketError<SpiBus::Error>> {
+        let socket = self.socket.as_mut().ok_or(UdpSocketError::SocketNotOpen)?;
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        loop {
+            match socket.socket_receive(bus, &mut buffer) {
+                Ok((len, header)) => {
+                    if header.origin.port() != SERVER_PORT {
+                        continue;
+                    }
+                    if let Some(reply) = Reply::parse(&buffer[..len], self.xid, self.settings.mac) {
+                        return Ok(Some(reply));
+                    }
+                }
+                Err(NbError::WouldBlock) => return Ok(None),
+                Err(NbError::Other(error)) => return Err(error),
+            }
         }
     }
 }
 
 impl Host for Dhcp {
     /// Gets (if necessary) and sets the host settings on the chip
-    fn refresh<SpiBus: Bus>(&mut self, _bus: &mut SpiBus) -> Result<(), SpiBus::Error> {
-        // TODO actually negotiate settings from DHCP
-        // TODO figure out how should receive socket for DHCP negotiations
+    ///
+    /// The lease itself is negotiated by [`Device::poll_dhcp`].
+    fn refresh<SpiBus: Bus>(&mut self, bus: &mut SpiBus) -> Result<(), SpiBus::Error> {
+        if !self.is_setup {
+            Self::write_settings(bus, &mut self.current, &self.settings)?;
+            self.is_setup = true;
+        }
         Ok(())
     }
+
+    /// The network may have changed while the link was down, so the lease is negotiated again
+    /// once it is back up. The current address is kept until the server assigns a different one.
+    fn on_link_change(&mut self, up: bool) {
+        if up {
+            self.restart();
+        }
+    }
+}
+
+impl<SpiBus: Bus, StateImpl: State<Host = Dhcp>, IntPin: InterruptPin>
+    Device<SpiBus, StateImpl, IntPin>
+{
+    /// Drive the DHCP client.
+    ///
+    /// This never blocks and should be called periodically from the main loop. `now` is a
+    /// monotonic timestamp in milliseconds, used to time retransmissions and the lease.
+    ///
+    /// A socket is taken from the device while a lease is negotiated, renewed or rebound.
+    /// [`UdpSocketError::NoMoreSockets`] is returned if none is available at that time, and
+    /// [`UdpSocketError::PortInUse`] if another UDP socket is bound to the client port 68.
+    pub fn poll_dhcp(
+        &mut self,
+        now: u64,
+    ) -> Result<Option<DhcpEvent>, UdpSocketError<SpiBus::Error>> {
+        let Device {
+            bus,
+            state,
+            interrupt_pin,
+        } = self;
+
+        if state.host().wants_socket(now) {
+            let socket = state.socket().ok_or(UdpSocketError::NoMoreSockets)?;
+            if !state
+                .ports_mut()
+                .bind(socket.index, Transport::Udp, CLIENT_PORT)
+            {
+                state.release_socket(socket);
+                return Err(UdpSocketError::PortInUse);
+            }
+            state.host_mut().attach_socket(bus, socket)?;
+        }
+
+        let event = state.host_mut().poll(bus, interrupt_pin, now)?;
+
+        if let Some(socket) = state.host_mut().detach_socket(bus)? {
+            state.release_socket(socket);
+        }
+
+        Ok(event)
+    }
+}
+
+/// Builder for outgoing DHCP messages
+struct Message {
+    buffer: [u8; MESSAGE_LEN],
+    len: usize,
+}
+
+impl Message {
+    fn new(xid: u32, mac: MacAddress, ciaddr: Ipv4Addr, broadcast: bool) -> Self {
+        let mut buffer = [0u8; MESSAGE_LEN];
+        buffer[0] = 1; // op: BOOTREQUEST
+        buffer[1] = 1; // htype: Ethernet
+        buffer[2] = 6; // hlen
+        buffer[4..8].copy_from_slice(&xid.to_be_bytes());
+        if broadcast {
+            // Ask the server to broadcast the reply, we can't receive unicast without an address
+            buffer[10] = 0x80;
+        }
+        buffer[12..16].copy_from_slice(&ciaddr.octets());
+        buffer[28..34].copy_from_slice(&mac.octets);
+        buffer[236..240].copy_from_slice(&MAGIC_COOKIE);
+        Self {
+            buffer,
+            len: OPTIONS_OFFSET,
+        }
+    }
+
+    fn option(&mut self, code: u8, data: &[u8]) {
+        self.buffer[self.len] = code;
+        self.buffer[self.len + 1] = data.len() as u8;
+        self.buffer[self.len + 2..self.len + 2 + data.len()].copy_from_slice(data);
+        self.len += 2 + data.len();
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        self.buffer[self.len] = option::END;
+        // Padded up to the minimum BOOTP message size
+        &self.buffer[..]
+    }
+}
+
+/// The relevant parts of a DHCP message received from a server
+#[derive(Debug)]
+struct Reply {
+    kind: MessageType,
+    yiaddr: Ipv4Addr,
+    server: Option<Ipv4Addr>,
+    subnet: Option<Ipv4Addr>,
+    router: Option<Ipv4Addr>,
+    dns_servers: [Option<Ipv4Addr>; 3],
+    lease_time: Option<u32>,
+    renewal_time: Option<u32>,
+    rebinding_time: Option<u32>,
+}
+
+impl Reply {
+    /// Parse a server reply, returns `None` if the message is not a reply to transaction `xid` of
+    /// this client.
+    fn parse(message: &[u8], xid: u32, mac: MacAddress) -> Option<Self> {
+        if message.len() < OPTIONS_OFFSET
+            || message[0] != 2
+            || message[4..8] != xid.to_be_bytes()
+            || message[28..34] != mac.octets
+            || message[236..240] != MAGIC_COOKIE
+        {
+            return None;
+        }
+
+        let ip = |data: &[u8]| match data {
+            [a, b, c, d, ..] => Some(Ipv4Addr::new(*a, *b, *c, *d)),
+            _ => None,
+        };
+        let seconds = |data: &[u8]| match data {
+            [a, b, c, d, ..] => Some(u32::from_be_bytes([*a, *b, *c, *d])),
+            _ => None,
+        };
+
+        let mut reply = Reply {
+            kind: MessageType::Discover,
+            yiaddr: ip(&message[16..20])?,
+            server: None,
+            subnet: None,
+            router: None,
+            dns_servers: [None; 3],
+            lease_time: None,
+            renewal_time: None,
+            rebinding_time: None,
+        };
+        let mut kind = None;
+
+        let mut options = &message[OPTIONS_OFFSET..];
+        while let [code, rest @ ..] = options {
+            match *code {
+                option::PAD => {
+                    options = rest;
+                    continue;
+                }
+                option::END => break,
+                _ => {}
+            }
+            let (len, rest) = rest.split_first()?;
+            let data = rest.get(..usize::from(*len))?;
+            match *code {
+                option::MESSAGE_TYPE => kind = data.first().copied().and_then(MessageType::from_u8),
+                option::SERVER_ID => reply.server = ip(data),
+                option::SUBNET_MASK => reply.subnet = ip(data),
+                option::ROUTER => reply.router = ip(data),
+                option::DNS_SERVER => {
+                    for (slot, chunk) in reply.dns_servers.iter_mut().zip(data.chunks_exact(4)) {
+                        *slot = ip(chunk);
+                    }
+                }
+                option::LEASE_TIME => reply.lease_time = seconds(data),
+                option::RENEWAL_TIME => reply.renewal_time = seconds(data),
+                option::REBINDING_TIME => reply.rebinding_time = seconds(data),
+                _ => {}
+            }
+            options = &rest[usize::from(*len)..];
+        }
+
+        reply.kind = kind?;
+        Some(reply)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::convert::TryInto;
+    use std::collections::VecDeque;
+
+    use embedded_nal::{UdpClientStack, UdpFullStack};
+
+    use super::*;
+    use crate::emulator::{ConnectError, ConnectionReset, Emulator, Network};
+    use crate::{Mode, UninitializedDevice};
+
+    const MAC: MacAddress = MacAddress::new(0x02, 0, 0, 0, 0, 1);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+    const OFFERED: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 50);
+
+    /// A DHCP server, which offers and acknowledges [`OFFERED`] to any client
+    #[derive(Default)]
+    struct Server {
+        /// The type and destination of the messages received from the client
+        requests: Vec<(MessageType, Ipv4Addr)>,
+        replies: VecDeque<Vec<u8>>,
+    }
+
+    impl Server {
+        fn reply(&mut self, xid: u32, kind: MessageType) {
+            let mut message = Message::new(xid, MAC, Ipv4Addr::UNSPECIFIED, false);
+            message.buffer[0] = 2;
+            message.buffer[16..20].copy_from_slice(&OFFERED.octets());
+            message.option(option::MESSAGE_TYPE, &[kind as u8]);
+            message.option(option::SERVER_ID, &SERVER.octets());
+            message.option(option::SUBNET_MASK, &[255, 255, 255, 0]);
+            message.option(option::ROUTER, &SERVER.octets());
+            message.option(option::DNS_SERVER, &[1, 1, 1, 1, 8, 8, 8, 8]);
+            message.option(option::LEASE_TIME, &3600u32.to_be_bytes());
+            self.replies.push_back(message.finish().to_vec());
+        }
+    }
+
+    impl Network for Server {
+        fn connect(&mut self, _socket: u8, _remote: SocketAddrV4) -> Result<(), ConnectError> {
+            Err(ConnectError::TimedOut)
+        }
+
+        fn send(&mut self, _socket: u8, _data: &[u8]) -> usize {
+            0
+        }
+
+        fn receive(
+            &mut self,
+            _socket: u8,
+            _buffer: &mut [u8],
+        ) -> Result<Option<usize>, ConnectionReset> {
+            Ok(None)
+        }
+
+        fn open_udp(&mut self, _socket: u8, _local_port: u16) {}
+
+        fn send_to(&mut self, _socket: u8, remote: SocketAddrV4, data: &[u8]) -> bool {
+            assert_eq!(remote.port(), SERVER_PORT);
+            let xid = u32::from_be_bytes(data[4..8].try_into().unwrap());
+            // The message type is always the first option.
+            let kind = MessageType::from_u8(data[OPTIONS_OFFSET + 2]).unwrap();
+            self.requests.push((kind, *remote.ip()));
+            // A reply to another client's transaction comes first, which has to be ignored.
+            self.reply(xid.wrapping_add(1), MessageType::Nak);
+            match kind {
+                MessageType::Discover => self.reply(xid, MessageType::Offer),
+                MessageType::Request => self.reply(xid, MessageType::Ack),
+                _ => {}
+            }
+            true
+        }
+
+        fn receive_from(
+            &mut self,
+            _socket: u8,
+            buffer: &mut [u8],
+        ) -> Option<(SocketAddrV4, usize)> {
+            let reply = self.replies.pop_front()?;
+            buffer[..reply.len()].copy_from_slice(&reply);
+            Some((SocketAddrV4::new(SERVER, SERVER_PORT), reply.len()))
+        }
+
+        fn close(&mut self, _socket: u8) {}
+    }
+
+    #[test]
+    fn test_lease_lifecycle() {
+        let mut device = UninitializedDevice::new(Emulator::with_network(Server::default()))
+            .initialize(MAC, Mode::default())
+            .unwrap();
+
+        assert_eq!(device.poll_dhcp(0).unwrap(), None);
+        assert_eq!(device.host().state, DhcpState::Selecting);
+
+        assert_eq!(device.poll_dhcp(10).unwrap(), None);
+        assert_eq!(
+            device.host().state,
+            DhcpState::Requesting {
+                server: SERVER,
+                offered: OFFERED
+            }
+        );
+
+        let config = match device.poll_dhcp(20).unwrap() {
+            Some(DhcpEvent::Configured(config)) => config,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(config.ip, OFFERED);
+        assert_eq!(config.gateway, SERVER);
+        assert_eq!(config.subnet, Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(device.host().state, DhcpState::Bound);
+        assert_eq!(device.ip().unwrap(), OFFERED);
+        assert!(device
+            .host()
+            .dns_servers()
+            .eq([Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(8, 8, 8, 8)]));
+        // The socket is released while the lease is bound.
+        assert!(!device.state.any_allocated());
+
+        // T1 is half of the lease time.
+        assert_eq!(device.poll_dhcp(20 + 1_799_999).unwrap(), None);
+        assert_eq!(device.host().state, DhcpState::Bound);
+        assert_eq!(device.poll_dhcp(20 + 1_800_000).unwrap(), None);
+        assert_eq!(device.host().state, DhcpState::Renewing);
+
+        // The ACK extends the lease without changing the settings.
+        let renewed = 20 + 1_800_010;
+        assert_eq!(device.poll_dhcp(renewed).unwrap(), None);
+        assert_eq!(device.host().state, DhcpState::Bound);
+        let lease = device.host().lease.unwrap();
+        assert_eq!(lease.renew_at, renewed + 1_800_000);
+        assert_eq!(lease.expires_at, renewed + 3_600_000);
+
+        assert_eq!(
+            device.bus.network_mut().requests,
+            [
+                (MessageType::Discover, Ipv4Addr::BROADCAST),
+                (MessageType::Request, Ipv4Addr::BROADCAST),
+                // Renewing asks the server that handed out the lease directly.
+                (MessageType::Request, SERVER),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_client_port_in_use() {
+        let mut device = UninitializedDevice::new(Emulator::with_network(Server::default()))
+            .initialize(MAC, Mode::default())
+            .unwrap();
+        let mut socket = device.socket().unwrap();
+        device.bind(&mut socket, CLIENT_PORT).unwrap();
+
+        assert!(matches!(
+            device.poll_dhcp(0),
+            Err(UdpSocketError::PortInUse)
+        ));
+        assert!(device.bus.network_mut().requests.is_empty());
+
+        // The DHCP client's socket was released again.
+        UdpClientStack::close(&mut device, socket).unwrap();
+        assert!(!device.state.any_allocated());
+        assert_eq!(device.poll_dhcp(10).unwrap(), None);
+        assert_eq!(device.host().state, DhcpState::Selecting);
+    }
 }
@@ -3,13 +3,13 @@ use core::net::Ipv4Addr;
 mod dhcp;
 mod manual;
 
-pub use self::dhcp::Dhcp;
+pub use self::dhcp::{Dhcp, DhcpEvent};
 pub use self::manual::Manual;
 use crate::bus::Bus;
 use crate::register;
 use crate::MacAddress;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct HostConfig {
     mac: MacAddress,
@@ -21,6 +21,24 @@ pub struct HostConfig {
     subnet: Ipv4Addr,
 }
 
+impl HostConfig {
+    pub fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    pub fn ip(&self) -> Ipv4Addr {
+        self.ip
+    }
+
+    pub fn gateway(&self) -> Ipv4Addr {
+        self.gateway
+    }
+
+    pub fn subnet(&self) -> Ipv4Addr {
+        self.subnet
+    }
+}
+
 impl Default for HostConfig {
     fn default() -> Self {
         Self {
@@ -36,6 +54,9 @@ pub trait Host {
     /// Gets (if necessary) and sets the host settings on the chip
     fn refresh<SpiBus: Bus>(&mut self, bus: &mut SpiBus) -> Result<(), SpiBus::Error>;
 
+    /// Called by [`Device::poll_link`](crate::Device::poll_link) when the link went up or down
+    fn on_link_change(&mut self, up: bool) {}
+
     /// Write changed settings to chip
     ///
     /// Will check all settings and write any new ones to the chip.  Will update the settings returned by `current`
@@ -0,0 +1,455 @@
+//! Interrupt dispatching.
+//!
+//! [`Device::service_interrupts`] collects and clears all pending chip and socket interrupts in
+//! one go, so an interrupt handler or task only needs to act on the returned [`InterruptEvents`]
+//! instead of polling every socket.
+//!
+//! With the INTn line connected, [`Device::with_interrupt_pin`] and
+//! [`Device::wait_for_interrupt`] let a caller wait on the pin between retries of non-blocking
+//! operations instead of continuously polling the chip's registers over SPI. [`PolledPin`] reads
+//! an [`InputPin`] with a delay in between, an [`InterruptPin`] implementation can also sleep
+//! until the pin's interrupt fires, e.g. with `WFI`. With the `async` feature, an
+//! `embedded_hal_async::digital::Wait` pin is awaited through `AsyncInterruptPin`.
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::InputPin;
+
+use crate::bus::Bus;
+use crate::device::{Device, State};
+use crate::register::{self, common, socketn};
+use crate::socket::Socket;
+
+/// The INTn line of the chip, which is asserted (low) while an unmasked interrupt is pending.
+pub trait InterruptPin {
+    /// Wait until INTn is asserted.
+    ///
+    /// Returning early is always fine, the caller checks the chip's registers afterwards.
+    fn wait(&mut self);
+}
+
+/// No INTn line is connected, operations poll the chip instead.
+#[derive(Debug, Default, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NoPin;
+
+impl InterruptPin for NoPin {
+    fn wait(&mut self) {}
+}
+
+/// INTn connected to an [`InputPin`], which is read every `interval_us` until it is low.
+///
+/// The chip isn't accessed while waiting. The CPU only idles if the delay does, e.g. by sleeping
+/// until a timer interrupt.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PolledPin<Pin, Delay> {
+    pin: Pin,
+    delay: Delay,
+    interval_us: u32,
+}
+
+impl<Pin: InputPin, Delay: DelayNs> PolledPin<Pin, Delay> {
+    pub fn new(pin: Pin, delay: Delay, interval_us: u32) -> Self {
+        Self {
+            pin,
+            delay,
+            interval_us,
+        }
+    }
+
+    /// Return the pin and the delay.
+    pub fn release(self) -> (Pin, Delay) {
+        (self.pin, self.delay)
+    }
+}
+
+impl<Pin: InputPin, Delay: DelayNs> InterruptPin for PolledPin<Pin, Delay> {
+    fn wait(&mut self) {
+        // Fall back to polling the chip if the pin can't be read.
+        while let Ok(false) = self.pin.is_low() {
+            self.delay.delay_us(self.interval_us);
+        }
+    }
+}
+
+/// The INTn line of the chip, awaited by async operations.
+#[cfg(feature = "async")]
+#[allow(async_fn_in_trait)]
+pub trait AsyncInterruptPin {
+    /// Wait until INTn is asserted.
+    ///
+    /// Returning early is always fine, the caller checks the chip's registers afterwards.
+    async fn wait(&mut self);
+}
+
+#[cfg(feature = "async")]
+impl<Pin: embedded_hal_async::digital::Wait> AsyncInterruptPin for Pin {
+    async fn wait(&mut self) {
+        // Fall back to polling the chip if the pin can't be read.
+        let _ = self.wait_for_low().await;
+    }
+}
+
+/// No INTn line is connected, async operations wait `interval_us` before polling the chip again.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PollDelay<Delay> {
+    delay: Delay,
+    interval_us: u32,
+}
+
+#[cfg(feature = "async")]
+impl<Delay: embedded_hal_async::delay::DelayNs> PollDelay<Delay> {
+    pub fn new(delay: Delay, interval_us: u32) -> Self {
+        Self { delay, interval_us }
+    }
+
+    /// Return the delay.
+    pub fn release(self) -> Delay {
+        self.delay
+    }
+}
+
+#[cfg(feature = "async")]
+impl<Delay: embedded_hal_async::delay::DelayNs> AsyncInterruptPin for PollDelay<Delay> {
+    async fn wait(&mut self) {
+        self.delay.delay_us(self.interval_us).await;
+    }
+}
+
+/// The interrupts of a single socket (`Sn_IR`)
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SocketInterrupts(u8);
+
+impl SocketInterrupts {
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, interrupt: socketn::Interrupt) -> bool {
+        self.0 & interrupt as u8 != 0
+    }
+
+    /// `RECV`: data has been received
+    pub fn received(&self) -> bool {
+        self.contains(socketn::Interrupt::Receive)
+    }
+
+    /// `SEND_OK`: a SEND command completed
+    pub fn send_ok(&self) -> bool {
+        self.contains(socketn::Interrupt::SendOk)
+    }
+
+    /// `TIMEOUT`: an ARP request or TCP retransmission timed out
+    pub fn timeout(&self) -> bool {
+        self.contains(socketn::Interrupt::Timeout)
+    }
+
+    /// `CON`: a TCP connection was established
+    pub fn connected(&self) -> bool {
+        self.contains(socketn::Interrupt::Connect)
+    }
+
+    /// `DISCON`: the peer closed the TCP connection (FIN or RST)
+    pub fn disconnected(&self) -> bool {
+        self.contains(socketn::Interrupt::Disconnect)
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+}
+
+/// The interrupts cleared by [`Device::service_interrupts`]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InterruptEvents {
+    common: u8,
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    unreachable: Option<SocketAddrV4>,
+    sockets: [SocketInterrupts; 8],
+}
+
+impl InterruptEvents {
+    pub fn is_empty(&self) -> bool {
+        self.common == 0 && self.sockets.iter().all(SocketInterrupts::is_empty)
+    }
+
+    pub fn contains(&self, interrupt: common::Interrupt) -> bool {
+        self.common & interrupt as u8 != 0
+    }
+
+    /// `CONFLICT`: another host uses the chip's IP address
+    pub fn ip_conflict(&self) -> bool {
+        self.contains(common::Interrupt::Conflict)
+    }
+
+    /// `UNREACH`: the destination of a UDP datagram was reported unreachable via ICMP
+    pub fn unreachable(&self) -> Option<SocketAddrV4> {
+        self.unreachable
+    }
+
+    /// `MP`: a magic packet was received
+    pub fn magic_packet(&self) -> bool {
+        self.contains(common::Interrupt::MagicPacket)
+    }
+
+    /// The interrupts of the socket with `index`
+    pub fn socket(&self, index: u8) -> SocketInterrupts {
+        self.sockets[usize::from(index)]
+    }
+
+    /// The indices and interrupts of all sockets with pending interrupts
+    pub fn sockets(&self) -> impl Iterator<Item = (u8, SocketInterrupts)> + '_ {
+        (0..8u8)
+            .zip(self.sockets.iter().copied())
+            .filter(|(_, interrupts)| !interrupts.is_empty())
+    }
+}
+
+impl<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin> Device<SpiBus, StateImpl, IntPin> {
+    /// Read and clear all pending interrupts (IR, SIR and the `Sn_IR` of each flagged socket).
+    ///
+    /// The events are meant to tell which sockets need attention, not to replace the socket's own
+    /// state. Note that the socket implementations clear some of the interrupts themselves, e.g.
+    /// `SEND_OK` while sending, so they may be missing from the events.
+    ///
+    /// The sockets check their status and buffer pointers instead of the cleared interrupts. The
+    /// exception is `TIMEOUT` of a TCP socket, which tells a failed connect, send or close from a
+    /// completed one. It's reported but left set until the socket returns it as
+    /// [`TcpSocketError::Timeout`](crate::tcp::TcpSocketError::Timeout), so it keeps INTn
+    /// asserted until then. The UDP and IPRAW sockets consume their `SEND_OK` and `TIMEOUT`
+    /// within the send call, so servicing can't interfere.
+    pub fn service_interrupts(&mut self) -> Result<InterruptEvents, SpiBus::Error> {
+        let mut events = InterruptEvents::default();
+
+        let mut common = [0u8];
+        self.bus
+            .read_frame(register::COMMON, common::INTERRUPT, &mut common)?;
+        events.common = common[0] & 0xF0;
+        if events.common != 0 {
+            if events.contains(common::Interrupt::Unreachable) {
+                let mut ip = [0u8; 4];
+                let mut port = [0u8; 2];
+                self.bus
+                    .read_frame(register::COMMON, common::UNREACHABLE_IP, &mut ip)?;
+                self.bus
+                    .read_frame(register::COMMON, common::UNREACHABLE_PORT, &mut port)?;
+                events.unreachable = Some(SocketAddrV4::new(
+                    Ipv4Addr::from(ip),
+                    u16::from_be_bytes(port),
+                ));
+            }
+            self.bus
+                .write_frame(register::COMMON, common::INTERRUPT, &[events.common])?;
+        }
+
+        let mut sockets = [0u8];
+        self.bus
+            .read_frame(register::COMMON, common::SOCKET_INTERRUPT, &mut sockets)?;
+        for index in (0..8u8).filter(|index| sockets[0] & (1 << index) != 0) {
+            let socket = Socket::new(index);
+            let mut interrupts = [0u8];
+            self.bus
+                .read_frame(socket.register(), socketn::INTERRUPT, &mut interrupts)?;
+            let mut clear = interrupts[0];
+            if clear & socketn::Interrupt::Timeout as u8 != 0 {
+                let mut mode = [0u8];
+                self.bus
+                    .read_frame(socket.register(), socketn::MODE, &mut mode)?;
+                if mode[0] & 0x0F == socketn::Protocol::Tcp as u8 {
+                    clear &= !(socketn::Interrupt::Timeout as u8);
+                }
+            }
+            if clear != 0 {
+                self.bus
+                    .write_frame(socket.register(), socketn::INTERRUPT, &[clear])?;
+            }
+            events.sockets[usize::from(index)] = SocketInterrupts(interrupts[0]);
+        }
+
+        Ok(events)
+    }
+
+    /// Use the INTn line behind `pin`, e.g. a [`PolledPin`], to wait for socket events.
+    ///
+    /// This enables the interrupts of all sockets in SIMR. The sockets themselves only unmask the
+    /// events they wait for (`SEND_OK`, `TIMEOUT`, `RECV` and for TCP `CON` and `DISCON`). While
+    /// other interrupts are pending, e.g. the ones enabled with [`Device::enable_interrupts`] or
+    /// the `RECV` of a socket nobody reads from, INTn stays asserted and waiting falls back to
+    /// polling until they are cleared with [`Device::service_interrupts`].
+    pub fn with_interrupt_pin<Pin: InterruptPin>(
+        mut self,
+        pin: Pin,
+    ) -> Result<Device<SpiBus, StateImpl, Pin>, SpiBus::Error> {
+        self.bus
+            .write_frame(register::COMMON, common::SOCKET_INTERRUPT_MASK, &[0xFF])?;
+        let (device, _) = self.replace_interrupt_pin(pin);
+        Ok(device)
+    }
+
+    /// Wait until INTn is asserted, e.g. before retrying an operation that returned
+    /// [`nb::Error::WouldBlock`](embedded_nal::nb::Error::WouldBlock).
+    ///
+    /// Returns immediately if no pin was set with [`Device::with_interrupt_pin`].
+    pub fn wait_for_interrupt(&mut self) {
+        self.interrupt_pin.wait();
+    }
+
+    /// Set INTLEVEL, the minimum time INTn is deasserted between two interrupts.
+    ///
+    /// The wait time is `(level + 1) * 4 / 150 MHz`, the default is `0`.
+    pub fn set_interrupt_level(&mut self, level: u16) -> Result<(), SpiBus::Error> {
+        self.bus.write_frame(
+            register::COMMON,
+            common::INTERRUPT_TIMER,
+            &level.to_be_bytes(),
+        )
+    }
+
+    /// Select the interrupts that assert the INTn pin.
+    ///
+    /// `common` is a combination of [`common::Interrupt`]s (IMR), `sockets` has bit `n` set to
+    /// enable the interrupts of socket `n` (SIMR).
+    pub fn enable_interrupts(&mut self, common: u8, sockets: u8) -> Result<(), SpiBus::Error> {
+        self.bus
+            .write_frame(register::COMMON, common::INTERRUPT_MASK, &[common])?;
+        self.bus
+            .write_frame(register::COMMON, common::SOCKET_INTERRUPT_MASK, &[sockets])
+    }
+
+    /// Stop using the INTn line and return the pin.
+    pub fn release_interrupt_pin(
+        mut self,
+    ) -> Result<(Device<SpiBus, StateImpl>, IntPin), SpiBus::Error> {
+        self.bus
+            .write_frame(register::COMMON, common::SOCKET_INTERRUPT_MASK, &[0])?;
+        Ok(self.replace_interrupt_pin(NoPin))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use embedded_nal::{nb, TcpClientStack, TcpFullStack, UdpClientStack, UdpFullStack};
+
+    use crate::emulator::Emulator;
+    use crate::tcp::TcpSocketError;
+    use crate::{MacAddress, Mode, UninitializedDevice};
+
+    #[test]
+    fn test_service_interrupts() {
+        let ip = Ipv4Addr::new(192, 168, 0, 2);
+        let mut device = UninitializedDevice::new(Emulator::new())
+            .initialize_manual(MacAddress::new(2, 0, 0, 0, 0, 1), ip, Mode::default())
+            .unwrap();
+        assert!(device.service_interrupts().unwrap().is_empty());
+
+        let mut receiver = UdpClientStack::socket(&mut device).unwrap();
+        UdpFullStack::bind(&mut device, &mut receiver, 5000).unwrap();
+        let mut sender = UdpClientStack::socket(&mut device).unwrap();
+        let remote = SocketAddr::new(IpAddr::V4(ip), 5000);
+        UdpClientStack::connect(&mut device, &mut sender, remote).unwrap();
+        nb::block!(UdpClientStack::send(&mut device, &mut sender, b"ping")).unwrap();
+
+        let mut listener = TcpClientStack::socket(&mut device).unwrap();
+        TcpFullStack::bind(&mut device, &mut listener, 80).unwrap();
+        device.listen(&mut listener).unwrap();
+        let mut client = TcpClientStack::socket(&mut device).unwrap();
+        let remote = SocketAddr::new(IpAddr::V4(ip), 80);
+        nb::block!(TcpClientStack::connect(&mut device, &mut client, remote)).unwrap();
+        let (mut server, _) = nb::block!(device.accept(&mut listener)).unwrap();
+        nb::block!(TcpClientStack::send(&mut device, &mut client, b"pong")).unwrap();
+
+        let events = device.service_interrupts().unwrap();
+        assert!(!events.is_empty());
+        // The UDP sender cleared SEND_OK itself, the TCP sockets cleared CON.
+        assert!(events.socket(sender.index()).is_empty());
+        assert!(events.socket(receiver.index()).received());
+        assert!(events.socket(client.index()).send_ok());
+        assert!(events.socket(server.index()).received());
+        let mut sockets = events.sockets().map(|(index, _)| index).collect::<Vec<_>>();
+        sockets.sort();
+        let mut expected = [receiver.index(), client.index(), server.index()];
+        expected.sort();
+        assert_eq!(sockets, expected);
+        assert_eq!(events.unreachable(), None);
+
+        // Everything was cleared, but the data can still be received.
+        assert!(device.service_interrupts().unwrap().is_empty());
+        let mut buffer = [0; 8];
+        let (len, _) = UdpClientStack::receive(&mut device, &mut receiver, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"ping");
+        let len = TcpClientStack::receive(&mut device, &mut server, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"pong");
+    }
+
+    #[test]
+    fn test_service_interrupts_keeps_tcp_timeout() {
+        let ip = Ipv4Addr::new(192, 168, 0, 2);
+        let mut device = UninitializedDevice::new(Emulator::new())
+            .initialize_manual(MacAddress::new(2, 0, 0, 0, 0, 1), ip, Mode::default())
+            .unwrap();
+
+        // Nothing answers ARP for other addresses.
+        let mut client = TcpClientStack::socket(&mut device).unwrap();
+        let unreachable = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 99)), 80);
+        assert!(matches!(
+            TcpClientStack::connect(&mut device, &mut client, unreachable),
+            Err(nb::Error::WouldBlock)
+        ));
+        assert!(device
+            .service_interrupts()
+            .unwrap()
+            .socket(client.index())
+            .timeout());
+        assert!(matches!(
+            TcpClientStack::connect(&mut device, &mut client, unreachable),
+            Err(nb::Error::Other(TcpSocketError::Timeout))
+        ));
+        assert!(device.service_interrupts().unwrap().is_empty());
+
+        // The driver doesn't poll the link, so the chip times out sending and closing.
+        let mut listener = TcpClientStack::socket(&mut device).unwrap();
+        TcpFullStack::bind(&mut device, &mut listener, 80).unwrap();
+        device.listen(&mut listener).unwrap();
+        let remote = SocketAddr::new(IpAddr::V4(ip), 80);
+        let mut connections = Vec::new();
+        for _ in 0..2 {
+            let mut client = TcpClientStack::socket(&mut device).unwrap();
+            nb::block!(TcpClientStack::connect(&mut device, &mut client, remote)).unwrap();
+            let (server, _) = nb::block!(device.accept(&mut listener)).unwrap();
+            connections.push((client, server));
+        }
+        device.service_interrupts().unwrap();
+        device.bus.set_link_up(false);
+
+        let (mut client, _) = connections.remove(0);
+        TcpClientStack::send(&mut device, &mut client, b"lost").unwrap();
+        assert!(device
+            .service_interrupts()
+            .unwrap()
+            .socket(client.index())
+            .timeout());
+        assert!(matches!(
+            TcpClientStack::send(&mut device, &mut client, b"lost"),
+            Err(nb::Error::Other(TcpSocketError::Timeout))
+        ));
+
+        let (mut client, _) = connections.remove(0);
+        nb::block!(device.tcp_shutdown_write(&mut client)).unwrap();
+        assert!(device
+            .service_interrupts()
+            .unwrap()
+            .socket(client.index())
+            .timeout());
+        assert!(matches!(
+            device.tcp_close(client, 100),
+            Err(TcpSocketError::Timeout)
+        ));
+    }
+}
@@ -0,0 +1,395 @@
+//! IPRAW sockets for IP protocols other than TCP and UDP.
+//!
+//! The chip adds and strips the IP header, the socket exchanges the payload of IP packets with
+//! the protocol number it was opened with, e.g. `1` for ICMP.
+use core::{
+    convert::TryFrom,
+    fmt::{self, Debug},
+    net::{IpAddr, Ipv4Addr},
+};
+
+use embedded_nal::nb;
+
+use crate::{
+    bus::Bus,
+    device::{Device, State},
+    error::{ErrorKind, UNSUPPORTED_ADDRESS},
+    interrupt::InterruptPin,
+    register::socketn::{self, Status},
+    socket::Socket,
+};
+
+/// W5500 IPRAW Header
+///
+/// Precedes every received packet in the RX buffer, like the [`UdpHeader`](crate::udp::UdpHeader)
+/// of UDP datagrams:
+///
+/// ```text
+/// | Destination IP Address | Byte Size of DATA | Actual DATA ... |
+/// |    --- 4 Bytes ---     |  --- 2 Bytes ---  |      ....       |
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct IpRawHeader {
+    /// The IP address of the peer that sent the packet.
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub origin: Ipv4Addr,
+    /// Length of the packet's payload in bytes.
+    pub len: usize,
+}
+
+impl IpRawHeader {
+    pub fn from_array(array: [u8; 6]) -> Self {
+        Self::from(array)
+    }
+}
+
+impl From<[u8; 6]> for IpRawHeader {
+    fn from(header: [u8; 6]) -> Self {
+        Self {
+            origin: Ipv4Addr::new(header[0], header[1], header[2], header[3]),
+            len: u16::from_be_bytes([header[4], header[5]]).into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct IpRawSocket {
+    pub(crate) socket: Socket,
+    /// The IP protocol number of the socket
+    protocol: u8,
+}
+
+impl IpRawSocket {
+    pub(crate) fn new(socket: Socket, protocol: u8) -> Self {
+        Self { socket, protocol }
+    }
+
+    pub(crate) fn open<SpiBus: Bus>(&self, bus: &mut SpiBus) -> Result<(), SpiBus::Error> {
+        self.socket.command(bus, socketn::Command::Close)?;
+        self.socket.reset_interrupt(bus, socketn::Interrupt::All)?;
+        self.socket.set_ip_protocol(bus, self.protocol)?;
+        self.socket.set_mode(bus, socketn::Protocol::IpRaw)?;
+        // Only the events the socket waits for assert INTn.
+        self.socket.set_interrupt_mask(
+            bus,
+            socketn::Interrupt::SendOk as u8
+                | socketn::Interrupt::Timeout as u8
+                | socketn::Interrupt::Receive as u8,
+        )?;
+        self.socket.command(bus, socketn::Command::Open)?;
+        Ok(())
+    }
+
+    fn check_open<SpiBus: Bus>(
+        &self,
+        bus: &mut SpiBus,
+    ) -> Result<(), IpRawSocketError<SpiBus::Error>> {
+        match Status::try_from(self.socket.get_status(bus)?) {
+            Ok(Status::IpRaw) => Ok(()),
+            Ok(_) => Err(IpRawSocketError::SocketNotOpen),
+            Err(_) => Err(IpRawSocketError::UnrecognisedStatus),
+        }
+    }
+
+    /// Send a single packet, waiting on `interrupt_pin` until the chip sent it. Returns `None` if
+    /// the TX buffer has no room for the packet yet.
+    pub(crate) fn socket_send_to<SpiBus: Bus>(
+        &mut self,
+        bus: &mut SpiBus,
+        interrupt_pin: &mut impl InterruptPin,
+        remote: Ipv4Addr,
+        data: &[u8],
+    ) -> Result<Option<()>, IpRawSocketError<SpiBus::Error>> {
+        self.check_open(bus)?;
+
+        // The whole packet has to be in the TX buffer when SEND is issued.
+        let mut buffer_size = [0u8];
+        bus.read_frame(
+            self.socket.register(),
+            socketn::TXBUF_SIZE,
+            &mut buffer_size,
+        )?;
+        if data.len() > usize::from(buffer_size[0]) * 1024 {
+            return Err(IpRawSocketError::PacketTooLarge);
+        }
+        if usize::from(self.socket.get_tx_free_size(bus)?) < data.len() {
+            return Ok(None);
+        }
+
+        self.socket.set_destination_ip(bus, remote)?;
+
+        let write_pointer = self.socket.get_tx_write_pointer(bus)?;
+        bus.write_frame(self.socket.tx_buffer(), write_pointer, data)?;
+        // safe to cast to `u16` because the maximum buffer size in w5500 is 16 KB!
+        self.socket
+            .set_tx_write_pointer(bus, write_pointer.wrapping_add(data.len() as u16))?;
+        self.socket.command(bus, socketn::Command::Send)?;
+
+        loop {
+            if self.socket.has_interrupt(bus, socketn::Interrupt::SendOk)? {
+                self.socket
+                    .reset_interrupt(bus, socketn::Interrupt::SendOk)?;
+                return Ok(Some(()));
+            }
+            if self
+                .socket
+                .has_interrupt(bus, socketn::Interrupt::Timeout)?
+            {
+                // The ARP request for the destination timed out.
+                self.socket
+                    .reset_interrupt(bus, socketn::Interrupt::Timeout)?;
+                return Err(IpRawSocketError::WriteTimeout);
+            }
+            interrupt_pin.wait();
+        }
+    }
+
+    /// Receive a single packet, truncating it if it doesn't fit into `buffer`. Returns `None` if
+    /// no packet has been received.
+    pub(crate) fn socket_receive<SpiBus: Bus>(
+        &mut self,
+        bus: &mut SpiBus,
+        buffer: &mut [u8],
+    ) -> Result<Option<(usize, IpRawHeader)>, IpRawSocketError<SpiBus::Error>> {
+        self.check_open(bus)?;
+
+        let rx_size = usize::from(self.socket.get_receive_size(bus)?);
+        // Nothing to read until at least a complete header has been received.
+        if rx_size < 6 {
+            return Ok(None);
+        }
+
+        let read_pointer = self.socket.get_rx_read_pointer(bus)?;
+        let mut header = [0u8; 6];
+        bus.read_frame(self.socket.rx_buffer(), read_pointer, &mut header)?;
+        let header = IpRawHeader::from_array(header);
+
+        let data_read_pointer = read_pointer.wrapping_add(6);
+        let packet_size = header.len.min(rx_size - 6);
+        let read_length = packet_size.min(buffer.len());
+        bus.read_frame(
+            self.socket.rx_buffer(),
+            data_read_pointer,
+            &mut buffer[..read_length],
+        )?;
+
+        // Skip the rest of the packet, any following packets stay in the RX buffer.
+        self.socket
+            .set_rx_read_pointer(bus, data_read_pointer.wrapping_add(packet_size as u16))?;
+        // Clear the interrupt before the RECV command, which sets it again if more packets are
+        // buffered. A stale interrupt would keep INTn asserted.
+        self.socket
+            .reset_interrupt(bus, socketn::Interrupt::Receive)?;
+        self.socket.command(bus, socketn::Command::Receive)?;
+
+        Ok(Some((read_length, header)))
+    }
+
+    pub(crate) fn socket_close<SpiBus: Bus>(&self, bus: &mut SpiBus) -> Result<(), SpiBus::Error> {
+        self.socket.set_mode(bus, socketn::Protocol::Closed)?;
+        self.socket.command(bus, socketn::Command::Close)?;
+        // Pending interrupts of a closed socket would keep INTn asserted.
+        self.socket.reset_interrupt(bus, socketn::Interrupt::All)
+    }
+
+    /// The IP protocol number of the socket
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    /// returns the index of the socket
+    #[inline]
+    pub fn index(&self) -> u8 {
+        self.socket.index
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IpRawSocketError<E: Debug> {
+    NoMoreSockets,
+    /// Only IP V4 is supported
+    UnsupportedAddress,
+    /// Returned [`Status`] for the socket was not recognised.
+    UnrecognisedStatus,
+    /// The socket was closed, e.g. by a reset of the device.
+    SocketNotOpen,
+    /// The packet does not fit into the socket's TX buffer.
+    PacketTooLarge,
+    WriteTimeout,
+    Other(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] E),
+}
+
+impl<E: Debug> IpRawSocketError<E> {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            IpRawSocketError::NoMoreSockets => ErrorKind::NoMoreSockets,
+            IpRawSocketError::UnsupportedAddress | IpRawSocketError::PacketTooLarge => {
+                ErrorKind::InvalidInput
+            }
+            IpRawSocketError::UnrecognisedStatus => ErrorKind::Other,
+            IpRawSocketError::SocketNotOpen => ErrorKind::NotConnected,
+            IpRawSocketError::WriteTimeout => ErrorKind::TimedOut,
+            IpRawSocketError::Other(_) => ErrorKind::Bus,
+        }
+    }
+}
+
+impl<E: Debug> fmt::Display for IpRawSocketError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpRawSocketError::UnsupportedAddress => f.write_str(UNSUPPORTED_ADDRESS),
+            IpRawSocketError::UnrecognisedStatus => f.write_str("unrecognised socket status"),
+            IpRawSocketError::SocketNotOpen => f.write_str("socket not open"),
+            IpRawSocketError::PacketTooLarge => f.write_str("packet larger than the TX buffer"),
+            IpRawSocketError::Other(error) => write!(f, "{}: {:?}", ErrorKind::Bus, error),
+            error => fmt::Display::fmt(&error.kind(), f),
+        }
+    }
+}
+
+impl<E: Debug> core::error::Error for IpRawSocketError<E> {}
+
+impl<E: Debug> From<E> for IpRawSocketError<E> {
+    fn from(error: E) -> IpRawSocketError<E> {
+        IpRawSocketError::Other(error)
+    }
+}
+
+impl<SpiBus, StateImpl, IntPin> Device<SpiBus, StateImpl, IntPin>
+where
+    SpiBus: Bus,
+    StateImpl: State,
+    IntPin: InterruptPin,
+{
+    /// Open an IPRAW socket for the IP protocol number `protocol`.
+    pub fn ipraw_socket(
+        &mut self,
+        protocol: u8,
+    ) -> Result<IpRawSocket, IpRawSocketError<SpiBus::Error>> {
+        let socket = self.take_socket().ok_or(IpRawSocketError::NoMoreSockets)?;
+        let socket = IpRawSocket::new(socket, protocol);
+        if let Err(error) = socket.open(&mut self.bus) {
+            self.release_socket(socket.socket);
+            return Err(error.into());
+        }
+        Ok(socket)
+    }
+
+    /// Send `data` as the payload of a single IP packet to `remote`.
+    ///
+    /// Returns [`nb::Error::WouldBlock`] while the TX buffer has no room for the packet. Once
+    /// it's queued, this waits on the INTn pin until the chip sent it.
+    pub fn ipraw_send_to(
+        &mut self,
+        socket: &mut IpRawSocket,
+        remote: IpAddr,
+        data: &[u8],
+    ) -> nb::Result<(), IpRawSocketError<SpiBus::Error>> {
+        let IpAddr::V4(remote) = remote else {
+            return Err(nb::Error::Other(IpRawSocketError::UnsupportedAddress));
+        };
+        socket
+            .socket_send_to(&mut self.bus, &mut self.interrupt_pin, remote, data)?
+            .ok_or(nb::Error::WouldBlock)
+    }
+
+    /// Receive the payload of a single IP packet and the address of its sender.
+    pub fn ipraw_receive(
+        &mut self,
+        socket: &mut IpRawSocket,
+        buffer: &mut [u8],
+    ) -> nb::Result<(usize, IpAddr), IpRawSocketError<SpiBus::Error>> {
+        let (len, header) = socket
+            .socket_receive(&mut self.bus, buffer)?
+            .ok_or(nb::Error::WouldBlock)?;
+        Ok((len, IpAddr::V4(header.origin)))
+    }
+
+    /// Close the socket and return it to the device.
+    pub fn ipraw_close(
+        &mut self,
+        socket: IpRawSocket,
+    ) -> Result<(), IpRawSocketError<SpiBus::Error>> {
+        let result = socket.socket_close(&mut self.bus);
+        self.release_socket(socket.socket);
+        Ok(result?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::FourWire;
+    use crate::emulator::Emulator;
+    use crate::{MacAddress, Mode, UninitializedDevice};
+
+    /// An experimental protocol number (RFC 3692)
+    const PROTOCOL: u8 = 253;
+
+    #[test]
+    fn test_send_and_receive() {
+        let ip = Ipv4Addr::new(192, 168, 0, 2);
+        let mut device = UninitializedDevice::new(FourWire::new(Emulator::new()))
+            .initialize_manual(MacAddress::new(2, 0, 0, 0, 0, 1), ip, Mode::default())
+            .unwrap();
+
+        let mut sender = device.ipraw_socket(PROTOCOL).unwrap();
+        let mut receiver = device.ipraw_socket(PROTOCOL).unwrap();
+        let mut mask = [0u8];
+        device
+            .bus
+            .read_frame(
+                receiver.socket.register(),
+                socketn::INTERRUPT_MASK,
+                &mut mask,
+            )
+            .unwrap();
+        assert_eq!(mask[0], 0b1_1100);
+
+        let mut buffer = [0u8; 16];
+        assert!(matches!(
+            device.ipraw_receive(&mut receiver, &mut buffer),
+            Err(nb::Error::WouldBlock)
+        ));
+        for payload in [&b"first"[..], b"second"] {
+            nb::block!(device.ipraw_send_to(&mut sender, IpAddr::V4(ip), payload)).unwrap();
+        }
+
+        let (len, origin) = device.ipraw_receive(&mut receiver, &mut buffer).unwrap();
+        assert_eq!((&buffer[..len], origin), (&b"first"[..], IpAddr::V4(ip)));
+        // RECV stays set while a packet is buffered and is cleared once all were read.
+        assert!(receiver
+            .socket
+            .has_interrupt(&mut device.bus, socketn::Interrupt::Receive)
+            .unwrap());
+        let (len, _) = device
+            .ipraw_receive(&mut receiver, &mut buffer[..4])
+            .unwrap();
+        assert_eq!(&buffer[..len], b"seco");
+        assert!(!receiver
+            .socket
+            .has_interrupt(&mut device.bus, socketn::Interrupt::Receive)
+            .unwrap());
+        assert!(matches!(
+            device.ipraw_receive(&mut receiver, &mut buffer),
+            Err(nb::Error::WouldBlock)
+        ));
+
+        // Nobody answers the ARP request.
+        let unreachable = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 3));
+        assert!(matches!(
+            device.ipraw_send_to(&mut sender, unreachable, b"lost"),
+            Err(nb::Error::Other(IpRawSocketError::WriteTimeout))
+        ));
+        assert!(matches!(
+            device.ipraw_send_to(&mut sender, IpAddr::V4(ip), &[0; 2049]),
+            Err(nb::Error::Other(IpRawSocketError::PacketTooLarge))
+        ));
+
+        device.ipraw_close(sender).unwrap();
+        device.ipraw_close(receiver).unwrap();
+    }
+}
@@ -1,13 +1,26 @@
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 #![allow(unused)]
 #![deny(rustdoc::broken_intra_doc_links)]
 #![doc = include_str!("../README.md")]
 
+#[cfg(feature = "async")]
+pub mod asynch;
+mod buffer_layout;
 pub mod bus;
 mod cursor;
 mod device;
+pub mod dns;
+#[cfg(feature = "embassy")]
+pub mod embassy;
+#[cfg(any(test, feature = "std"))]
+pub mod emulator;
+pub mod error;
 mod host;
+pub mod interrupt;
+pub mod ipraw;
 pub mod net;
+pub mod ping;
+mod port;
 pub mod raw_device;
 pub mod register;
 mod socket;
@@ -17,8 +30,10 @@ mod uninitialized_device;
 
 #[doc(inline)]
 pub use self::{
-    device::{Device, DeviceState},
-    host::{Dhcp, Host, HostConfig, Manual},
+    buffer_layout::{BufferLayout, BufferLayoutError},
+    device::{Device, DeviceState, LinkEvent, ResetError, SetBufferLayoutError},
+    error::{Error, ErrorKind},
+    host::{Dhcp, DhcpEvent, Host, HostConfig, Manual},
     net::MacAddress,
     uninitialized_device::{InitializeError, UninitializedDevice},
 };
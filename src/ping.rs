@@ -0,0 +1,373 @@
+//! ICMP echo (ping) client.
+//!
+//! The [`Pinger`] keeps the echo request in flight, [`Device::ping`] sends it on an IPRAW socket
+//! and polls for the matching reply:
+//!
+//! ```no_run
+//! # use core::net::{IpAddr, Ipv4Addr};
+//! # fn now() -> u64 { 0 }
+//! # fn example<SpiBus: w5500::bus::Bus, HostImpl: w5500::Host>(
+//! #     device: &mut w5500::Device<SpiBus, w5500::DeviceState<HostImpl>>,
+//! # ) {
+//! let mut pinger = w5500::ping::Pinger::new();
+//! let gateway = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+//!
+//! match nb::block!(device.ping(&mut pinger, gateway, b"w5500", 1_000, now())) {
+//!     Ok(reply) => { /* reply.rtt_ms */ }
+//!     Err(w5500::ping::PingError::Timeout) => { /* unreachable */ }
+//!     Err(error) => { /* bus or socket error */ }
+//! }
+//! # }
+//! ```
+use core::fmt::{self, Debug};
+use core::net::{IpAddr, Ipv4Addr};
+
+use embedded_nal::nb;
+
+use crate::bus::Bus;
+use crate::device::{Device, State};
+use crate::error::{ErrorKind, UNSUPPORTED_ADDRESS};
+use crate::interrupt::InterruptPin;
+use crate::ipraw::{IpRawSocket, IpRawSocketError};
+
+/// IP protocol number of ICMP
+const PROTOCOL_ICMP: u8 = 1;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+
+/// Size of the ICMP echo header
+const HEADER_LEN: usize = 8;
+
+/// Maximum payload of an echo request
+pub const MAX_PAYLOAD_LEN: usize = 128;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PingError<E: Debug> {
+    NoMoreSockets,
+    /// Only IPv4 is supported
+    UnsupportedAddress,
+    /// The payload is longer than [`MAX_PAYLOAD_LEN`]
+    PayloadTooLarge,
+    /// No reply was received within the timeout
+    Timeout,
+    Socket(IpRawSocketError<E>),
+}
+
+impl<E: Debug> PingError<E> {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            PingError::NoMoreSockets => ErrorKind::NoMoreSockets,
+            PingError::UnsupportedAddress | PingError::PayloadTooLarge => ErrorKind::InvalidInput,
+            PingError::Timeout => ErrorKind::TimedOut,
+            PingError::Socket(error) => error.kind(),
+        }
+    }
+}
+
+impl<E: Debug> fmt::Display for PingError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PingError::UnsupportedAddress => f.write_str(UNSUPPORTED_ADDRESS),
+            PingError::PayloadTooLarge => f.write_str("ping payload too large"),
+            PingError::Timeout => f.write_str("no ping reply"),
+            PingError::Socket(error) => fmt::Display::fmt(error, f),
+            error => fmt::Display::fmt(&error.kind(), f),
+        }
+    }
+}
+
+impl<E: Debug> core::error::Error for PingError<E> {}
+
+impl<E: Debug> From<IpRawSocketError<E>> for PingError<E> {
+    fn from(error: IpRawSocketError<E>) -> Self {
+        match error {
+            IpRawSocketError::NoMoreSockets => PingError::NoMoreSockets,
+            error => PingError::Socket(error),
+        }
+    }
+}
+
+impl<E: Debug> From<E> for PingError<E> {
+    fn from(error: E) -> Self {
+        PingError::Socket(IpRawSocketError::Other(error))
+    }
+}
+
+/// A received echo reply
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PingReply {
+    /// The host that replied
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    pub from: Ipv4Addr,
+    /// The sequence number of the echo request
+    pub sequence: u16,
+    /// Round-trip time in milliseconds, as measured by the clock passed to [`Device::ping`]
+    pub rtt_ms: u64,
+    /// Length of the echoed payload
+    pub len: usize,
+}
+
+#[derive(Debug)]
+struct Request {
+    socket: IpRawSocket,
+    remote: Ipv4Addr,
+    sequence: u16,
+    /// When the request was handed to the chip, `None` until then
+    sent_at: Option<u64>,
+}
+
+/// State of the ping client: the identifier of its echo requests and the request in flight.
+///
+/// While a request is in flight, a socket is taken from the device. It is released again once a
+/// reply was received or the request timed out.
+#[derive(Debug)]
+pub struct Pinger {
+    identifier: u16,
+    next_sequence: u16,
+    request: Option<Request>,
+}
+
+impl Default for Pinger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pinger {
+    pub const fn new() -> Self {
+        Self::with_identifier(0x5735)
+    }
+
+    /// Use `identifier` in the echo requests, e.g. to tell several pingers on a network apart.
+    pub const fn with_identifier(identifier: u16) -> Self {
+        Self {
+            identifier,
+            next_sequence: 0,
+            request: None,
+        }
+    }
+
+    /// Whether an echo request is waiting for its reply
+    pub fn is_pending(&self) -> bool {
+        self.request.is_some()
+    }
+
+    /// Abort the request in flight, if any, and return its socket to the device.
+    pub fn cancel<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin>(
+        &mut self,
+        device: &mut Device<SpiBus, StateImpl, IntPin>,
+    ) -> Result<(), PingError<SpiBus::Error>> {
+        if let Some(request) = self.request.take() {
+            let result = request.socket.socket_close(&mut device.bus);
+            device.release_socket(request.socket.socket);
+            result?;
+        }
+        Ok(())
+    }
+
+    fn start<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin>(
+        &mut self,
+        device: &mut Device<SpiBus, StateImpl, IntPin>,
+        remote: Ipv4Addr,
+    ) -> Result<(), PingError<SpiBus::Error>> {
+        let socket = device.ipraw_socket(PROTOCOL_ICMP)?;
+        self.request = Some(Request {
+            socket,
+            remote,
+            sequence: self.next_sequence,
+            sent_at: None,
+        });
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        Ok(())
+    }
+
+    fn poll<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin>(
+        &mut self,
+        device: &mut Device<SpiBus, StateImpl, IntPin>,
+        payload: &[u8],
+        timeout_ms: u64,
+        now: u64,
+    ) -> nb::Result<PingReply, PingError<SpiBus::Error>> {
+        match self.poll_request(device, payload, timeout_ms, now) {
+            Ok(None) => Err(nb::Error::WouldBlock),
+            result => {
+                self.cancel(device)?;
+                result?.ok_or(nb::Error::WouldBlock)
+            }
+        }
+    }
+
+    /// Send the request or check for its reply. Returns `None` while waiting.
+    fn poll_request<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin>(
+        &mut self,
+        device: &mut Device<SpiBus, StateImpl, IntPin>,
+        payload: &[u8],
+        timeout_ms: u64,
+        now: u64,
+    ) -> Result<Option<PingReply>, PingError<SpiBus::Error>> {
+        let identifier = self.identifier;
+        let Some(request) = self.request.as_mut() else {
+            return Ok(None);
+        };
+
+        let mut buffer = [0u8; HEADER_LEN + MAX_PAYLOAD_LEN];
+        let Some(sent_at) = request.sent_at else {
+            let len = encode_echo_request(&mut buffer, identifier, request.sequence, payload);
+            if request
+                .socket
+                .socket_send_to(
+                    &mut device.bus,
+                    &mut device.interrupt_pin,
+                    request.remote,
+                    &buffer[..len],
+                )?
+                .is_some()
+            {
+                request.sent_at = Some(now);
+            }
+            return Ok(None);
+        };
+
+        while let Some((len, header)) = request
+            .socket
+            .socket_receive(&mut device.bus, &mut buffer)?
+        {
+            if header.origin != request.remote {
+                continue;
+            }
+            if let Some(len) = parse_echo_reply(&buffer[..len], identifier, request.sequence) {
+                return Ok(Some(PingReply {
+                    from: header.origin,
+                    sequence: request.sequence,
+                    rtt_ms: now.saturating_sub(sent_at),
+                    len,
+                }));
+            }
+        }
+
+        if now.saturating_sub(sent_at) >= timeout_ms {
+            return Err(PingError::Timeout);
+        }
+        Ok(None)
+    }
+}
+
+impl<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin> Device<SpiBus, StateImpl, IntPin> {
+    /// Send an ICMP echo request with `payload` to `remote` and wait for the reply.
+    ///
+    /// The call is non-blocking: the first call sends the request, following calls with the same
+    /// `remote` poll for the reply until `timeout_ms` elapsed. Calling it with a different
+    /// `remote` abandons the request in flight. `now` is a monotonic timestamp in milliseconds,
+    /// used for the timeout and the round-trip time.
+    ///
+    /// [`OnPingRequest`](crate::OnPingRequest) only controls whether the chip answers echo
+    /// requests of other hosts, it doesn't affect this client.
+    pub fn ping(
+        &mut self,
+        pinger: &mut Pinger,
+        remote: IpAddr,
+        payload: &[u8],
+        timeout_ms: u64,
+        now: u64,
+    ) -> nb::Result<PingReply, PingError<SpiBus::Error>> {
+        let IpAddr::V4(remote) = remote else {
+            return Err(nb::Error::Other(PingError::UnsupportedAddress));
+        };
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(nb::Error::Other(PingError::PayloadTooLarge));
+        }
+
+        match &pinger.request {
+            Some(request) if request.remote == remote => {}
+            _ => {
+                pinger.cancel(self)?;
+                pinger.start(self, remote)?;
+            }
+        }
+
+        pinger.poll(self, payload, timeout_ms, now)
+    }
+}
+
+/// The Internet checksum (RFC 1071) of `data`
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|word| match *word {
+            [high, low] => u32::from(u16::from_be_bytes([high, low])),
+            [high] => u32::from(high) << 8,
+            _ => 0,
+        })
+        .sum::<u32>();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Write an echo request into `buffer`, returning its length.
+fn encode_echo_request(buffer: &mut [u8], identifier: u16, sequence: u16, payload: &[u8]) -> usize {
+    let len = HEADER_LEN + payload.len();
+    buffer[0] = TYPE_ECHO_REQUEST;
+    buffer[1] = 0;
+    buffer[2..4].fill(0);
+    buffer[4..6].copy_from_slice(&identifier.to_be_bytes());
+    buffer[6..8].copy_from_slice(&sequence.to_be_bytes());
+    buffer[HEADER_LEN..len].copy_from_slice(payload);
+
+    let checksum = checksum(&buffer[..len]);
+    buffer[2..4].copy_from_slice(&checksum.to_be_bytes());
+    len
+}
+
+/// Check that `message` is a valid reply to the request `identifier`/`sequence` and return the
+/// length of its payload.
+fn parse_echo_reply(message: &[u8], identifier: u16, sequence: u16) -> Option<usize> {
+    if message.len() < HEADER_LEN
+        || message[0] != TYPE_ECHO_REPLY
+        || message[1] != 0
+        || checksum(message) != 0
+        || message[4..6] != identifier.to_be_bytes()
+        || message[6..8] != sequence.to_be_bytes()
+    {
+        return None;
+    }
+    Some(message.len() - HEADER_LEN)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_echo_request_checksum() {
+        let mut buffer = [0u8; 16];
+        let len = encode_echo_request(&mut buffer, 0x0001, 0x0002, b"ping");
+        assert_eq!(
+            &buffer[..len],
+            &[8, 0, 0x19, 0x2c, 0, 1, 0, 2, b'p', b'i', b'n', b'g']
+        );
+        assert_eq!(checksum(&buffer[..len]), 0);
+    }
+
+    #[test]
+    fn test_parse_echo_reply() {
+        let mut reply = [0u8; 16];
+        let len = encode_echo_request(&mut reply, 0x5735, 7, b"w5500!");
+        // Turn the request into a reply and fix up the checksum
+        reply[0] = TYPE_ECHO_REPLY;
+        reply[2..4].fill(0);
+        let checksum = checksum(&reply[..len]);
+        reply[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+        assert_eq!(parse_echo_reply(&reply[..len], 0x5735, 7), Some(6));
+        assert_eq!(parse_echo_reply(&reply[..len], 0x5735, 8), None);
+        assert_eq!(parse_echo_reply(&reply[..len], 0x0001, 7), None);
+
+        reply[9] ^= 0xFF;
+        assert_eq!(parse_echo_reply(&reply[..len], 0x5735, 7), None);
+    }
+}
@@ -0,0 +1,115 @@
+//! Allocation of the sockets' local ports.
+use rand_core::RngCore;
+
+/// First port of the IANA dynamic (ephemeral) port range
+const EPHEMERAL_START: u16 = 49152;
+
+/// Number of ports in the dynamic range, `49152..=65535`
+const EPHEMERAL_COUNT: u16 = 16384;
+
+/// The transport protocol a port is used with. TCP and UDP ports are independent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum Transport {
+    Tcp,
+    Udp,
+}
+
+/// Keeps track of the local port of each socket and hands out ephemeral ports.
+///
+/// Ephemeral ports are handed out in sequence, starting at an offset into the dynamic range
+/// chosen by [`Device::seed_ports`](crate::Device::seed_ports). Without a seed, every boot uses
+/// the same sequence.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PortAllocator {
+    /// Offset of the next ephemeral port to try
+    next: u16,
+    /// The transport and local port of each socket
+    sockets: [Option<(Transport, u16)>; 8],
+}
+
+impl Default for PortAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PortAllocator {
+    pub(crate) const fn new() -> Self {
+        Self {
+            next: 0,
+            sockets: [None; 8],
+        }
+    }
+
+    /// Start the ephemeral ports at a random offset, so they don't repeat across reboots.
+    pub(crate) fn seed(&mut self, rng: &mut impl RngCore) {
+        self.next = (rng.next_u32() % u32::from(EPHEMERAL_COUNT)) as u16;
+    }
+
+    /// Assign an ephemeral port, which no other socket uses, to the socket `index`.
+    pub(crate) fn allocate(&mut self, index: u8, transport: Transport) -> u16 {
+        loop {
+            let port = EPHEMERAL_START + self.next;
+            self.next = (self.next + 1) % EPHEMERAL_COUNT;
+            // At most 8 ports are in use, so this ends after a few iterations.
+            if !self.sockets.iter().flatten().any(|(_, used)| *used == port) {
+                self.sockets[usize::from(index)] = Some((transport, port));
+                return port;
+            }
+        }
+    }
+
+    /// Assign `port` to the socket `index`, unless another socket already uses it. Returns
+    /// whether the port was assigned.
+    pub(crate) fn bind(&mut self, index: u8, transport: Transport, port: u16) -> bool {
+        let in_use =
+            self.sockets.iter().enumerate().any(|(other, used)| {
+                other != usize::from(index) && *used == Some((transport, port))
+            });
+        if !in_use {
+            self.sockets[usize::from(index)] = Some((transport, port));
+        }
+        !in_use
+    }
+
+    /// Assign `port` to the socket `index`, even if another socket uses it already, e.g. for a
+    /// socket listening on the port of an accepted connection.
+    pub(crate) fn assign(&mut self, index: u8, transport: Transport, port: u16) {
+        self.sockets[usize::from(index)] = Some((transport, port));
+    }
+
+    /// The socket `index` no longer uses a port.
+    pub(crate) fn release(&mut self, index: u8) {
+        self.sockets[usize::from(index)] = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allocate_and_bind() {
+        let mut ports = PortAllocator::new();
+        assert_eq!(ports.allocate(0, Transport::Tcp), 49152);
+        assert!(ports.bind(1, Transport::Udp, 49153));
+        // The port bound to socket 1 is skipped.
+        assert_eq!(ports.allocate(2, Transport::Tcp), 49154);
+
+        // TCP and UDP ports are independent, but a port is only bound once per transport.
+        assert!(ports.bind(3, Transport::Udp, 49152));
+        assert!(!ports.bind(4, Transport::Tcp, 49152));
+        assert!(ports.bind(0, Transport::Tcp, 80));
+        assert!(ports.bind(4, Transport::Tcp, 49152));
+
+        ports.release(4);
+        assert!(ports.bind(5, Transport::Tcp, 49152));
+
+        // The sequence wraps around at the end of the dynamic range.
+        ports.next = EPHEMERAL_COUNT - 1;
+        assert_eq!(ports.allocate(6, Transport::Udp), 65535);
+        assert_eq!(ports.next, 0);
+    }
+}
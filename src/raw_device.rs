@@ -1,4 +1,59 @@
-use crate::{bus::Bus, register, socket::Socket, uninitialized_device::InitializeError};
+use crate::{
+    bus::Bus, net::MacAddress, register, socket::Socket, uninitialized_device::InitializeError,
+};
+
+/// Which frames the chip receives in MACRAW mode (`Sn_MR` of socket 0)
+///
+/// The default only receives frames addressed to the chip's MAC address, broadcasts and
+/// multicasts. Disable `mac_filter` to receive all frames on the wire, e.g. for a sniffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RawConfig {
+    /// `MFEN`: drop unicast frames for other MAC addresses
+    pub mac_filter: bool,
+    /// `BCASTB`: drop broadcast frames
+    pub block_broadcast: bool,
+    /// `MMB`: drop multicast frames
+    pub block_multicast: bool,
+    /// `MIP6B`: drop IPv6 frames
+    pub block_ipv6: bool,
+}
+
+impl RawConfig {
+    /// Receive every frame on the wire
+    pub const PROMISCUOUS: Self = Self {
+        mac_filter: false,
+        block_broadcast: false,
+        block_multicast: false,
+        block_ipv6: false,
+    };
+
+    pub fn to_u8(self) -> u8 {
+        let mut register = 0;
+        if self.mac_filter {
+            register |= register::socketn::MODE_MAC_FILTER;
+        }
+        if self.block_broadcast {
+            register |= register::socketn::MODE_BROADCAST_BLOCK;
+        }
+        if self.block_multicast {
+            register |= register::socketn::MODE_MULTICAST_BLOCK;
+        }
+        if self.block_ipv6 {
+            register |= register::socketn::MODE_IPV6_BLOCK;
+        }
+        register
+    }
+}
+
+impl Default for RawConfig {
+    fn default() -> Self {
+        Self {
+            mac_filter: true,
+            ..Self::PROMISCUOUS
+        }
+    }
+}
 
 /// The W5500 operating in MACRAW mode to send and receive ethernet frames.
 #[derive(Debug)]
@@ -11,12 +66,13 @@ pub struct RawDevice<SpiBus: Bus> {
 impl<SpiBus: Bus> RawDevice<SpiBus> {
     /// Create the raw device.
     ///
-    /// # Note
-    /// The device is configured with MAC filtering enabled.
-    ///
     /// # Args
     /// * `bus` - The bus to communicate with the device.
-    pub(crate) fn new(mut bus: SpiBus) -> Result<Self, InitializeError<SpiBus::Error>> {
+    /// * `config` - The frames to receive.
+    pub(crate) fn new(
+        mut bus: SpiBus,
+        config: RawConfig,
+    ) -> Result<Self, InitializeError<SpiBus::Error>> {
         // Set the raw socket to 16KB RX/TX buffer space.
         let raw_socket = Socket::new(0);
         bus.write_frame(raw_socket.register(), register::socketn::TXBUF_SIZE, &[16])?;
@@ -29,22 +85,53 @@ impl<SpiBus: Bus> RawDevice<SpiBus> {
             bus.write_frame(socket.register(), register::socketn::RXBUF_SIZE, &[0])?;
         }
 
-        // Configure the chip in MACRAW mode with MAC filtering.
-        let mode: u8 = (1 << 7) | // MAC address filtering
-                       (register::socketn::Protocol::MacRaw as u8);
+        let mut device = Self { bus, raw_socket };
+        device.open(config)?;
+        Ok(device)
+    }
 
-        bus.write_frame(raw_socket.register(), register::socketn::MODE, &[mode])?;
-        raw_socket.command(&mut bus, register::socketn::Command::Open)?;
+    /// Change which frames are received.
+    ///
+    /// The chip only applies the filter when the socket is opened, so the socket is reopened and
+    /// frames that haven't been read yet are dropped.
+    pub fn set_filter(&mut self, config: RawConfig) -> Result<(), SpiBus::Error> {
+        self.raw_socket
+            .command(&mut self.bus, register::socketn::Command::Close)?;
+        self.open(config)
+    }
+
+    fn open(&mut self, config: RawConfig) -> Result<(), SpiBus::Error> {
+        self.raw_socket.set_mode_with_flags(
+            &mut self.bus,
+            register::socketn::Protocol::MacRaw,
+            config.to_u8(),
+        )?;
+        self.raw_socket
+            .command(&mut self.bus, register::socketn::Command::Open)
+    }
+
+    /// The MAC address of the chip (`SHAR`)
+    pub fn mac(&mut self) -> Result<MacAddress, SpiBus::Error> {
+        let mut octets = [0u8; 6];
+        self.bus
+            .read_frame(register::COMMON, register::common::MAC, &mut octets)?;
+        Ok(MacAddress { octets })
+    }
 
-        Ok(Self { bus, raw_socket })
+    /// The PHY configuration and status, e.g. whether the link is up
+    pub fn phy_config(&mut self) -> Result<register::common::PhyConfig, SpiBus::Error> {
+        let mut phy = [0u8];
+        self.bus
+            .read_frame(register::COMMON, register::common::PHY_CONFIG, &mut phy)?;
+        Ok(phy[0].into())
     }
 
     /// Enable one or more interrupts
     ///
     /// # Args
     /// * `which` - The interrupts to enable; see `register::socketn::Interrupt`
-    ///             For instance, pass `Interrupt::Receive` to get interrupts
-    ///             on packet reception only.
+    ///   For instance, pass `Interrupt::Receive` to get interrupts
+    ///   on packet reception only.
     ///
     pub fn enable_interrupts(&mut self, which: u8) -> Result<(), SpiBus::Error> {
         self.raw_socket.set_interrupt_mask(&mut self.bus, which)?;
@@ -139,3 +226,168 @@ impl<SpiBus: Bus> RawDevice<SpiBus> {
         Ok(count as _)
     }
 }
+
+#[cfg(feature = "smoltcp")]
+pub use self::phy::{RxToken, TxToken};
+
+/// Lets smoltcp use the W5500 as its Ethernet interface.
+#[cfg(feature = "smoltcp")]
+mod phy {
+    use smoltcp::phy::{self, ChecksumCapabilities, DeviceCapabilities, Medium};
+    use smoltcp::time::Instant;
+
+    use super::RawDevice;
+    use crate::bus::Bus;
+
+    /// The largest Ethernet frame without the FCS, which the chip adds and strips
+    const MAX_FRAME_SIZE: usize = 1514;
+
+    /// A frame received by a [`RawDevice`]
+    pub struct RxToken {
+        frame: [u8; MAX_FRAME_SIZE],
+        len: usize,
+    }
+
+    /// Sends a frame with a [`RawDevice`]
+    pub struct TxToken<'a, SpiBus: Bus> {
+        device: &'a mut RawDevice<SpiBus>,
+    }
+
+    impl phy::RxToken for RxToken {
+        fn consume<R, F>(self, f: F) -> R
+        where
+            F: FnOnce(&[u8]) -> R,
+        {
+            f(&self.frame[..self.len])
+        }
+    }
+
+    impl<SpiBus: Bus> phy::TxToken for TxToken<'_, SpiBus> {
+        fn consume<R, F>(self, len: usize, f: F) -> R
+        where
+            F: FnOnce(&mut [u8]) -> R,
+        {
+            let mut frame = [0u8; MAX_FRAME_SIZE];
+            let frame = &mut frame[..len.min(MAX_FRAME_SIZE)];
+            let result = f(frame);
+            // smoltcp can't handle errors, the frame is lost like on a busy wire.
+            let _ = self.device.write_frame(frame);
+            result
+        }
+    }
+
+    impl<SpiBus: Bus> phy::Device for RawDevice<SpiBus> {
+        type RxToken<'a>
+            = RxToken
+        where
+            Self: 'a;
+        type TxToken<'a>
+            = TxToken<'a, SpiBus>
+        where
+            Self: 'a;
+
+        fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken, TxToken<'_, SpiBus>)> {
+            let mut frame = [0u8; MAX_FRAME_SIZE];
+            // A bus error looks like no frame was received.
+            let len = self.read_frame(&mut frame).ok().filter(|len| *len > 0)?;
+            Some((RxToken { frame, len }, TxToken { device: self }))
+        }
+
+        fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken<'_, SpiBus>> {
+            // `write_frame` waits until the frame was sent, the TX buffer is empty afterwards.
+            Some(TxToken { device: self })
+        }
+
+        fn capabilities(&self) -> DeviceCapabilities {
+            let mut capabilities = DeviceCapabilities::default();
+            capabilities.medium = Medium::Ethernet;
+            capabilities.max_transmission_unit = MAX_FRAME_SIZE;
+            // The chip neither computes nor checks checksums in MACRAW mode.
+            capabilities.checksum = ChecksumCapabilities::default();
+            capabilities
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::cell::RefCell;
+
+    use super::*;
+    use crate::bus::FourWire;
+    use crate::emulator::{Emulator, Shared};
+    use crate::{MacAddress, UninitializedDevice};
+
+    const MAC: MacAddress = MacAddress {
+        octets: [0x02, 0x00, 0x00, 0x00, 0x00, 0x01],
+    };
+
+    #[test]
+    fn test_raw_device() {
+        let emulator = RefCell::new(Emulator::new());
+        let mut raw = UninitializedDevice::new(FourWire::new(Shared(&emulator)))
+            .initialize_macraw(MAC, RawConfig::default())
+            .unwrap();
+
+        let mut frame = [0u8; 60];
+        frame[..6].copy_from_slice(&[0xFF; 6]);
+        frame[6..12].copy_from_slice(&MAC.octets);
+        assert_eq!(raw.write_frame(&frame).unwrap(), 60);
+        assert_eq!(emulator.borrow_mut().take_frames(), vec![frame.to_vec()]);
+
+        let mut buffer = [0u8; 1514];
+        assert_eq!(raw.read_frame(&mut buffer).unwrap(), 0);
+        frame[..6].copy_from_slice(&MAC.octets);
+        assert!(emulator.borrow_mut().inject_frame(&frame));
+        assert_eq!(raw.read_frame(&mut buffer).unwrap(), 60);
+        assert_eq!(&buffer[..60], &frame[..]);
+
+        // Filtered by MAC address.
+        frame[5] = 0x02;
+        assert!(!emulator.borrow_mut().inject_frame(&frame));
+
+        raw.set_filter(RawConfig::PROMISCUOUS).unwrap();
+        assert!(emulator.borrow_mut().inject_frame(&frame));
+        assert_eq!(raw.read_frame(&mut buffer).unwrap(), 60);
+
+        raw.set_filter(RawConfig {
+            block_broadcast: true,
+            block_ipv6: true,
+            ..RawConfig::default()
+        })
+        .unwrap();
+        frame[..6].copy_from_slice(&[0xFF; 6]);
+        assert!(!emulator.borrow_mut().inject_frame(&frame));
+        frame[..6].copy_from_slice(&MAC.octets);
+        frame[12..14].copy_from_slice(&[0x86, 0xDD]);
+        assert!(!emulator.borrow_mut().inject_frame(&frame));
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        assert!(emulator.borrow_mut().inject_frame(&frame));
+    }
+
+    #[cfg(feature = "smoltcp")]
+    #[test]
+    fn test_smoltcp_device() {
+        use smoltcp::phy::{Device, RxToken, TxToken};
+        use smoltcp::time::Instant;
+
+        let emulator = RefCell::new(Emulator::new());
+        let mut raw = UninitializedDevice::new(FourWire::new(Shared(&emulator)))
+            .initialize_macraw(MAC, RawConfig::default())
+            .unwrap();
+        assert_eq!(raw.capabilities().max_transmission_unit, 1514);
+
+        let now = Instant::from_millis(0);
+        assert!(raw.receive(now).is_none());
+        let mut frame = [0u8; 60];
+        frame[..6].copy_from_slice(&MAC.octets);
+        assert!(emulator.borrow_mut().inject_frame(&frame));
+        let (rx, _) = raw.receive(now).unwrap();
+        assert_eq!(rx.consume(|received| received.to_vec()), frame.to_vec());
+
+        raw.transmit(now)
+            .unwrap()
+            .consume(42, |buffer| buffer.fill(0xAB));
+        assert_eq!(emulator.borrow_mut().take_frames(), vec![vec![0xAB; 42]]);
+    }
+}
@@ -22,6 +22,19 @@ pub mod common {
     /// Register: INTLEVEL (Interrupt Low Level Timer Register) [R/W] [0x0013 – 0x0014] [0x0000]
     pub const INTERRUPT_TIMER: u16 = 0x13;
 
+    /// Register: IR (Interrupt Register) [R/W] [0x0015] [0x00]
+    ///
+    /// Bits are cleared by writing `1` to them, see [`Interrupt`].
+    pub const INTERRUPT: u16 = 0x15;
+
+    /// Register: IMR (Interrupt Mask Register) [R/W] [0x0016] [0x00]
+    pub const INTERRUPT_MASK: u16 = 0x16;
+
+    /// Register: SIR (Socket Interrupt Register) [R] [0x0017] [0x00]
+    ///
+    /// Bit `n` is set while `Sn_IR` of socket `n` is not `0`.
+    pub const SOCKET_INTERRUPT: u16 = 0x17;
+
     /// Register: SIMR (Socket Interrupt Mask Register) [R/W] [0x0018] [0x00]
     pub const SOCKET_INTERRUPT_MASK: u16 = 0x18;
 
@@ -31,9 +44,31 @@ pub mod common {
     /// Register: RCR (Retry Count Register) [R/W] [0x001B] [0x08]
     pub const RETRY_COUNT: u16 = 0x1B;
 
+    /// Register: UIPR (Unreachable IP Address Register) [R] [0x0028 – 0x002B] [0x00]
+    pub const UNREACHABLE_IP: u16 = 0x28;
+
+    /// Register: UPORTR (Unreachable Port Register) [R] [0x002C – 0x002D] [0x0000]
+    pub const UNREACHABLE_PORT: u16 = 0x2C;
+
+    /// Register: PHYCFGR (W5500 PHY Configuration Register) [R/W] [0x002E] [0b1011_1XXX]
     pub const PHY_CONFIG: u16 = 0x2E;
     pub const VERSION: u16 = 0x39;
 
+    /// Interrupts in IR (Interrupt Register)
+    #[repr(u8)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Interrupt {
+        /// `CONFLICT`: an ARP request with the chip's own IP address was received
+        Conflict = 0b1000_0000,
+        /// `UNREACH`: an ICMP destination unreachable packet was received, the destination is
+        /// stored in [`UNREACHABLE_IP`] and [`UNREACHABLE_PORT`]
+        Unreachable = 0b0100_0000,
+        /// `PPPoE`: the PPPoE connection was closed
+        PppoeClosed = 0b0010_0000,
+        /// `MP`: a magic packet was received, see [`OnWakeOnLan`](crate::OnWakeOnLan)
+        MagicPacket = 0b0001_0000,
+    }
+
     /// A Retry Time-value
     ///
     /// RTR (Retry Time-value Register) [R/W] [0x0019 – 0x001A] [0x07D0]
@@ -131,6 +166,7 @@ pub mod common {
         FullDuplex = 1,
     }
 
+    /// PHYCFGR (W5500 PHY Configuration Register) [R/W] [0x002E] [0b1011_1XXX]
     #[derive(Debug, Copy, Clone, Eq, PartialEq)]
     pub struct PhyConfig([u8; 1]);
 
@@ -141,12 +177,40 @@ pub mod common {
         const SPD_POS: usize = 1;
         // Duplex status bit position.
         const DPX_POS: usize = 2;
-        // Operation mode bit position.
-        // const OPMDC_POS = 3;
+        // Operation mode bit positions.
+        const OPMDC_POS: core::ops::Range<usize> = 3..6;
         // Configure PHY opeartion mode bit position.
-        // const OPMD_POS = 6;
+        const OPMD_POS: usize = 6;
         // Reset bit position.
-        // const RST_POS = 7;
+        const RST_POS: usize = 7;
+
+        /// Configure the PHY to use `mode` instead of the mode selected by the PMODE pins.
+        ///
+        /// The mode only takes effect after a PHY reset, see
+        /// [`Device::set_phy_config`](crate::Device::set_phy_config).
+        pub fn with_operation_mode(mode: PhyOperationMode) -> Self {
+            let mut config = Self([0]);
+            config.0.set_bits(Self::OPMDC_POS, u8::from(mode) >> 3);
+            config.0.set_bit(Self::OPMD_POS, true);
+            // RST is active low, keep the PHY running.
+            config.0.set_bit(Self::RST_POS, true);
+            config
+        }
+
+        /// Whether the operation mode is configured by software instead of the PMODE pins.
+        pub fn software_configured(&self) -> bool {
+            self.0.get_bit(Self::OPMD_POS)
+        }
+
+        /// Clear or set the (active low) RST bit.
+        pub(crate) fn with_reset(mut self, reset: bool) -> Self {
+            self.0.set_bit(Self::RST_POS, !reset);
+            self
+        }
+
+        pub fn to_register(self) -> [u8; 1] {
+            self.0
+        }
 
         /// PHY link status.
         ///
@@ -193,6 +257,23 @@ pub mod common {
             PhyConfig([val])
         }
     }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_phy_config_write() {
+            let config = PhyConfig::with_operation_mode(PhyOperationMode::PowerDown);
+            assert_eq!(config.to_register(), [0b1111_0000]);
+            assert_eq!(config.operation_mode(), PhyOperationMode::PowerDown);
+            assert!(config.software_configured());
+            assert_eq!(config.with_reset(true).to_register(), [0b0111_0000]);
+
+            let config = PhyConfig::with_operation_mode(PhyOperationMode::FullDuplex10bt);
+            assert_eq!(config.to_register(), [0b1100_1000]);
+        }
+    }
 }
 
 pub const SOCKET0: u8 = 0b000_00001;
@@ -233,12 +314,35 @@ pub mod socketn {
     /// The Protocol mode
     pub const MODE: u16 = 0x00;
 
+    /// `Sn_MR(MULTI)`: enables multicasting for a socket in UDP mode.
+    ///
+    /// The group address, port and MAC address have to be written to `Sn_DIPR`, `Sn_DPORT`
+    /// and `Sn_DHAR` before OPEN, which then joins the group.
+    pub const MODE_MULTICAST: u8 = 1 << 7;
+
+    /// `Sn_MR(MC)`: use IGMP version 1 instead of version 2 in multicast mode.
+    pub const MODE_IGMP_V1: u8 = 1 << 5;
+
+    /// `Sn_MR(MFEN)`: only receive frames for the chip's MAC address, broadcasts and multicasts
+    /// in MACRAW mode.
+    pub const MODE_MAC_FILTER: u8 = 1 << 7;
+
+    /// `Sn_MR(BCASTB)`: drop broadcast frames in MACRAW mode.
+    pub const MODE_BROADCAST_BLOCK: u8 = 1 << 6;
+
+    /// `Sn_MR(MMB)`: drop multicast frames in MACRAW mode.
+    pub const MODE_MULTICAST_BLOCK: u8 = 1 << 5;
+
+    /// `Sn_MR(MIP6B)`: drop IPv6 frames in MACRAW mode.
+    pub const MODE_IPV6_BLOCK: u8 = 1 << 4;
+
     /// The protocol modes that can be used with the `w5500`
     #[repr(u8)]
     pub enum Protocol {
         Closed = 0b00,
         Tcp = 0b01,
         Udp = 0b10,
+        IpRaw = 0b11,
         MacRaw = 0b100,
     }
 
@@ -339,6 +443,7 @@ pub mod socketn {
         Established = 0x17,
         CloseWait = 0x1c,
         Udp = 0x22,
+        IpRaw = 0x32,
         MacRaw = 0x42,
 
         // Transient states.
@@ -365,10 +470,21 @@ pub mod socketn {
 
     pub const SOURCE_PORT: u16 = 0x04;
 
+    /// Socket n Destination Hardware Address Register
+    ///
+    /// `Sn_DHAR`
+    pub const DESTINATION_MAC: u16 = 0x06;
+
     pub const DESTINATION_IP: u16 = 0x0C;
 
     pub const DESTINATION_PORT: u16 = 0x10;
 
+    /// Socket n IP Protocol Register
+    ///
+    /// `Sn_PROTO`, the protocol number in the IP header of packets sent and received in IPRAW
+    /// mode.
+    pub const IP_PROTOCOL: u16 = 0x14;
+
     pub const RXBUF_SIZE: u16 = 0x1E;
 
     /// Socket n TX Buffer Size Register
@@ -435,6 +551,11 @@ pub mod socketn {
 
     pub const RX_DATA_READ_POINTER: u16 = 0x28;
 
+    /// Socket n RX Write Pointer
+    ///
+    /// `Sn_RX_WR`, read-only, the chip writes received data up to it
+    pub const RX_DATA_WRITE_POINTER: u16 = 0x2A;
+
     /// Socket n Interrupt Mask
     ///
     /// offset (register)
@@ -1,6 +1,7 @@
 use core::net::Ipv4Addr;
 
 use crate::bus::Bus;
+use crate::net::MacAddress;
 use crate::register::socketn;
 
 #[derive(Debug)]
@@ -54,6 +55,19 @@ impl Socket {
         Ok(())
     }
 
+    /// Set the protocol mode together with additional `Sn_MR` flags, e.g.
+    /// [`socketn::MODE_MULTICAST`].
+    pub fn set_mode_with_flags<SpiBus: Bus>(
+        &self,
+        bus: &mut SpiBus,
+        mode: socketn::Protocol,
+        flags: u8,
+    ) -> Result<(), SpiBus::Error> {
+        let mode = [mode as u8 | flags];
+        bus.write_frame(self.register(), socketn::MODE, &mode)?;
+        Ok(())
+    }
+
     pub fn get_status<SpiBus: Bus>(&self, bus: &mut SpiBus) -> Result<u8, SpiBus::Error> {
         let mut data = [0u8];
         bus.read_frame(self.register(), socketn::STATUS, &mut data)?;
@@ -91,6 +105,30 @@ impl Socket {
         Ok(())
     }
 
+    pub fn get_source_port<SpiBus: Bus>(&self, bus: &mut SpiBus) -> Result<u16, SpiBus::Error> {
+        let mut data = [0u8; 2];
+        bus.read_frame(self.register(), socketn::SOURCE_PORT, &mut data)?;
+        Ok(u16::from_be_bytes(data))
+    }
+
+    pub fn set_destination_mac<SpiBus: Bus>(
+        &self,
+        bus: &mut SpiBus,
+        mac: MacAddress,
+    ) -> Result<(), SpiBus::Error> {
+        bus.write_frame(self.register(), socketn::DESTINATION_MAC, &mac.octets)?;
+        Ok(())
+    }
+
+    pub fn set_ip_protocol<SpiBus: Bus>(
+        &self,
+        bus: &mut SpiBus,
+        protocol: u8,
+    ) -> Result<(), SpiBus::Error> {
+        bus.write_frame(self.register(), socketn::IP_PROTOCOL, &[protocol])?;
+        Ok(())
+    }
+
     pub fn set_destination_ip<SpiBus: Bus>(
         &self,
         bus: &mut SpiBus,
@@ -101,6 +139,15 @@ impl Socket {
         Ok(())
     }
 
+    pub fn get_destination_ip<SpiBus: Bus>(
+        &self,
+        bus: &mut SpiBus,
+    ) -> Result<Ipv4Addr, SpiBus::Error> {
+        let mut data = [0u8; 4];
+        bus.read_frame(self.register(), socketn::DESTINATION_IP, &mut data)?;
+        Ok(Ipv4Addr::from(data))
+    }
+
     pub fn set_destination_port<SpiBus: Bus>(
         &self,
         bus: &mut SpiBus,
@@ -111,6 +158,15 @@ impl Socket {
         Ok(())
     }
 
+    pub fn get_destination_port<SpiBus: Bus>(
+        &self,
+        bus: &mut SpiBus,
+    ) -> Result<u16, SpiBus::Error> {
+        let mut data = [0u8; 2];
+        bus.read_frame(self.register(), socketn::DESTINATION_PORT, &mut data)?;
+        Ok(u16::from_be_bytes(data))
+    }
+
     pub fn get_tx_read_pointer<SpiBus: Bus>(&self, bus: &mut SpiBus) -> Result<u16, SpiBus::Error> {
         let mut data = [0u8; 2];
         bus.read_frame(self.register(), socketn::TX_DATA_READ_POINTER, &mut data)?;
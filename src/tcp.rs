@@ -3,15 +3,23 @@ use core::{
     net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
 };
 
-use embedded_nal::{nb, TcpClientStack, TcpError, TcpErrorKind};
+use embedded_nal::{nb, TcpClientStack, TcpError, TcpErrorKind, TcpFullStack};
 
 use crate::{
     bus::Bus,
+    cursor::RxCursor,
     device::{Device, State},
+    error::{ErrorKind, UNSUPPORTED_ADDRESS},
+    interrupt::InterruptPin,
+    port::Transport,
     register::socketn,
     socket::Socket,
 };
 
+/// The checks of the chip's status before [`TcpClientStack::close`] gives up on the FIN
+/// handshake, see [`Device::tcp_close`]
+pub const CLOSE_POLLS: u32 = 100_000;
+
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum TcpSocketError<E: core::fmt::Debug> {
@@ -20,17 +28,58 @@ pub enum TcpSocketError<E: core::fmt::Debug> {
     UnsupportedAddress,
     Other(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] E),
     UnsupportedMode,
+    /// The Ethernet link is down, see [`Device::poll_link`]
+    LinkDown,
+    /// The peer did not answer in time, e.g. the ARP request, the SYN or the sent data
+    Timeout,
+    /// The peer refused the connection (RST)
+    ConnectionRefused,
+    /// Another TCP socket is already bound to the port
+    PortInUse,
 }
 
-impl<E: core::fmt::Debug> TcpError for TcpSocketError<E> {
-    fn kind(&self) -> TcpErrorKind {
+impl<E: core::fmt::Debug> TcpSocketError<E> {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            TcpSocketError::NoMoreSockets => ErrorKind::NoMoreSockets,
+            TcpSocketError::NotConnected => ErrorKind::NotConnected,
+            TcpSocketError::UnsupportedAddress => ErrorKind::InvalidInput,
+            TcpSocketError::Other(_) => ErrorKind::Bus,
+            TcpSocketError::UnsupportedMode => ErrorKind::Unsupported,
+            TcpSocketError::LinkDown => ErrorKind::LinkDown,
+            TcpSocketError::Timeout => ErrorKind::TimedOut,
+            TcpSocketError::ConnectionRefused => ErrorKind::ConnectionRefused,
+            TcpSocketError::PortInUse => ErrorKind::AddrInUse,
+        }
+    }
+}
+
+impl<E: core::fmt::Debug> core::fmt::Display for TcpSocketError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
-            TcpSocketError::NotConnected => TcpErrorKind::PipeClosed,
-            _ => TcpErrorKind::Other,
+            TcpSocketError::UnsupportedAddress => f.write_str(UNSUPPORTED_ADDRESS),
+            TcpSocketError::Other(error) => write!(f, "{}: {:?}", ErrorKind::Bus, error),
+            TcpSocketError::UnsupportedMode => f.write_str("socket is not in TCP mode"),
+            error => core::fmt::Display::fmt(&error.kind(), f),
         }
     }
 }
 
+impl<E: core::fmt::Debug> core::error::Error for TcpSocketError<E> {}
+
+impl<E: core::fmt::Debug> TcpError for TcpSocketError<E> {
+    fn kind(&self) -> TcpErrorKind {
+        TcpSocketError::kind(self).into()
+    }
+}
+
+#[cfg(feature = "async")]
+impl<E: core::fmt::Debug> embedded_io_async::Error for TcpSocketError<E> {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        TcpSocketError::kind(self).into()
+    }
+}
+
 impl<E: core::fmt::Debug> From<E> for TcpSocketError<E> {
     fn from(e: E) -> Self {
         TcpSocketError::Other(e)
@@ -41,22 +90,25 @@ impl<E: core::fmt::Debug> From<E> for TcpSocketError<E> {
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct TcpSocket {
     socket: Socket,
+    /// The peer of the connection attempt started by [`TcpClientStack::connect`]
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    connecting: Option<SocketAddrV4>,
+    /// Whether a SEND command is in progress
+    sending: bool,
+    /// `Sn_TX_WR` when the SEND in progress was issued, it's complete once `Sn_TX_RD` gets there
+    send_end: u16,
 }
 
 impl TcpSocket {
-    fn reopen<B: Bus>(&mut self, bus: &mut B) -> Result<(), TcpSocketError<B::Error>> {
-        self.socket.command(bus, socketn::Command::Close)?;
-        self.socket.reset_interrupt(bus, socketn::Interrupt::All)?;
-        self.socket.set_mode(bus, socketn::Protocol::Tcp)?;
-
-        self.socket.set_interrupt_mask(
-            bus,
-            socketn::Interrupt::SendOk as u8 & socketn::Interrupt::Timeout as u8,
-        )?;
-
-        self.socket.command(bus, socketn::Command::Open)?;
-        Ok(())
+    fn new(socket: Socket) -> Self {
+        Self {
+            socket,
+            connecting: None,
+            sending: false,
+            send_end: 0,
+        }
     }
+
     fn open<B: Bus>(
         &mut self,
         bus: &mut B,
@@ -69,165 +121,444 @@ impl TcpSocket {
 
         self.socket.set_interrupt_mask(
             bus,
-            socketn::Interrupt::SendOk as u8 & socketn::Interrupt::Timeout as u8,
+            socketn::Interrupt::SendOk as u8
+                | socketn::Interrupt::Timeout as u8
+                | socketn::Interrupt::Receive as u8
+                | socketn::Interrupt::Connect as u8
+                | socketn::Interrupt::Disconnect as u8,
         )?;
 
         self.socket.command(bus, socketn::Command::Open)?;
         Ok(())
     }
 
-    fn socket_close<B: Bus>(&self, bus: &mut B) -> Result<(), TcpSocketError<B::Error>> {
+    pub(crate) fn socket_close<B: Bus>(&self, bus: &mut B) -> Result<(), TcpSocketError<B::Error>> {
         self.socket.set_mode(bus, socketn::Protocol::Closed)?;
         self.socket.command(bus, socketn::Command::Close)?;
+        // Pending interrupts of a closed socket would keep INTn asserted.
+        self.socket.reset_interrupt(bus, socketn::Interrupt::All)?;
         Ok(())
     }
 
+    /// Start connecting to `remote` from the port returned by `local_port`, or check on the
+    /// connection attempt in progress. Returns `None` while the handshake is in progress.
     fn socket_connect<B: Bus>(
         &mut self,
         bus: &mut B,
+        local_port: impl FnOnce() -> u16,
         remote: SocketAddrV4,
-    ) -> Result<(), TcpSocketError<B::Error>> {
-        // Ensure the socket is open and ready before we attempt to connect it.
+    ) -> Result<Option<()>, TcpSocketError<B::Error>> {
+        if self.connecting != Some(remote) {
+            // Opening closes whatever the socket was doing before and returns it to INIT.
+            self.open(bus, local_port())?;
+            self.socket.set_destination_ip(bus, *remote.ip())?;
+            self.socket.set_destination_port(bus, remote.port())?;
+            self.socket.command(bus, socketn::Command::Connect)?;
+            self.connecting = Some(remote);
+            return Ok(None);
+        }
+
         match socketn::Status::try_from(self.socket.get_status(bus)?) {
-            // Happy case: Nothing to do.
-            Ok(socketn::Status::Init) => {}
+            // The peer may already have closed its side again, data can still be read.
+            Ok(socketn::Status::Established) | Ok(socketn::Status::CloseWait) => {
+                self.connecting = None;
+                // Clear CON, it would keep INTn asserted.
+                self.socket
+                    .reset_interrupt(bus, socketn::Interrupt::Connect)?;
+                Ok(Some(()))
+            }
 
-            // If the socket is in the wrong mode, we can't use it. The user needs to re-open it.
-            Err(_) | Ok(socketn::Status::MacRaw) | Ok(socketn::Status::Udp) => {
-                return Err(TcpSocketError::UnsupportedMode)
+            // The CONNECT command may not have been processed yet.
+            Ok(socketn::Status::Init) | Ok(socketn::Status::SynSent) => Ok(None),
+
+            // The socket is closed if the ARP request or the SYN timed out, or if the peer
+            // answered with a RST. Reopen it, so the user can retry with the same socket.
+            Ok(socketn::Status::Closed) => {
+                self.connecting = None;
+                let timed_out = self
+                    .socket
+                    .has_interrupt(bus, socketn::Interrupt::Timeout)?;
+                let port = self.socket.get_source_port(bus)?;
+                self.open(bus, port)?;
+                if timed_out {
+                    Err(TcpSocketError::Timeout)
+                } else {
+                    Err(TcpSocketError::ConnectionRefused)
+                }
             }
 
-            // All other cases are transient TCP states. For these, we need to reset the TCP
-            // machinery to return to the INIT state.
-            Ok(_) => {
-                self.socket_close(bus)?;
-                self.reopen(bus)?;
+            _ => {
+                self.connecting = None;
+                Err(TcpSocketError::UnsupportedMode)
             }
         }
+    }
 
-        // Write the remote port and IP
-        self.socket.set_destination_ip(bus, *remote.ip())?;
-        self.socket.set_destination_port(bus, remote.port())?;
-
-        // Connect the socket.
-        self.socket.command(bus, socketn::Command::Connect)?;
+    fn socket_listen<B: Bus>(&mut self, bus: &mut B) -> Result<(), TcpSocketError<B::Error>> {
+        match socketn::Status::try_from(self.socket.get_status(bus)?) {
+            // The socket has been bound and is ready to listen.
+            Ok(socketn::Status::Init) => {}
 
-        // Wait for the socket to connect or encounter an error.
-        loop {
-            match socketn::Status::try_from(self.socket.get_status(bus)?) {
-                Ok(socketn::Status::Established) => return Ok(()),
+            // Already waiting for (or processing) an incoming connection.
+            Ok(socketn::Status::Listen) | Ok(socketn::Status::SynRecv) => return Ok(()),
 
-                // The socket is closed if a timeout (ARP or SYN-ACK) or if the TCP socket receives
-                // a RST packet. In this case, the client will need to re-attempt to connect.
+            _ => return Err(TcpSocketError::UnsupportedMode),
+        }
 
-                // TODO: Due to limitations of the embedded-nal, we currently still return the
-                // socket (since we cannot inform the user of the connection failure). The returned
-                // socket will not actually be connected.
-                Ok(socketn::Status::Closed) => {
-                    // For now, always return an open socket so that the user can re-connect with
-                    // it in the future.
-                    self.socket_close(bus)?;
-                    return self.reopen(bus);
-                }
+        self.socket.command(bus, socketn::Command::Listen)?;
+        Ok(())
+    }
 
-                // The socket is still in some transient state. Wait for it to connect or for the
-                // connection to fail.
-                _ => {}
+    /// Check whether a peer has connected to this listening socket.
+    ///
+    /// The W5500 does not have a separate accept queue: the listening socket itself becomes the
+    /// connected socket. Once a connection is established, the hardware socket is handed to the
+    /// caller and `spare` takes its place as the new listener on the same local port.
+    fn socket_accept<B: Bus>(
+        &mut self,
+        bus: &mut B,
+        spare: impl FnOnce() -> Option<Socket>,
+    ) -> Result<Option<(TcpSocket, SocketAddrV4)>, TcpSocketError<B::Error>> {
+        match socketn::Status::try_from(self.socket.get_status(bus)?) {
+            // Nobody connected yet, or the handshake is still in progress.
+            Ok(socketn::Status::Listen) | Ok(socketn::Status::SynRecv) => return Ok(None),
+
+            // A peer has connected. Data may already have been received before the peer closed.
+            Ok(socketn::Status::Established) | Ok(socketn::Status::CloseWait) => {}
+
+            // The handshake failed (e.g. a timeout or RST). Go back to listening.
+            Ok(socketn::Status::Closed) => {
+                let port = self.socket.get_source_port(bus)?;
+                self.open(bus, port)?;
+                self.socket.command(bus, socketn::Command::Listen)?;
+                return Ok(None);
             }
+
+            _ => return Err(TcpSocketError::UnsupportedMode),
         }
+
+        let spare = spare().ok_or(TcpSocketError::NoMoreSockets)?;
+
+        let remote = SocketAddrV4::new(
+            self.socket.get_destination_ip(bus)?,
+            self.socket.get_destination_port(bus)?,
+        );
+        let port = self.socket.get_source_port(bus)?;
+
+        // Clear CON, it would keep INTn asserted.
+        self.socket
+            .reset_interrupt(bus, socketn::Interrupt::Connect)?;
+
+        let mut connection = TcpSocket::new(spare);
+        core::mem::swap(&mut self.socket, &mut connection.socket);
+
+        // Start listening for the next connection on the replacement socket.
+        self.open(bus, port)?;
+        self.socket.command(bus, socketn::Command::Listen)?;
+
+        Ok(Some((connection, remote)))
     }
 
+    /// Whether data can be sent, i.e. the connection is established and no FIN was sent yet.
     fn socket_is_connected<B: Bus>(&self, bus: &mut B) -> Result<bool, TcpSocketError<B::Error>> {
-        Ok(self.socket.get_status(bus)? == socketn::Status::Established as u8)
+        Ok(matches!(
+            socketn::Status::try_from(self.socket.get_status(bus)?),
+            Ok(socketn::Status::Established) | Ok(socketn::Status::CloseWait)
+        ))
     }
 
+    /// Send a FIN once all queued data was acknowledged. Returns `None` while data is
+    /// outstanding.
+    fn socket_shutdown_write<B: Bus>(
+        &mut self,
+        bus: &mut B,
+    ) -> Result<Option<()>, TcpSocketError<B::Error>> {
+        match socketn::Status::try_from(self.socket.get_status(bus)?) {
+            Ok(socketn::Status::Established) | Ok(socketn::Status::CloseWait) => {}
+
+            // The FIN has already been sent.
+            Ok(socketn::Status::FinWait)
+            | Ok(socketn::Status::Closing)
+            | Ok(socketn::Status::TimeWait)
+            | Ok(socketn::Status::LastAck) => return Ok(Some(())),
+
+            // The chip gave up on unacknowledged data and closed the socket.
+            _ if self.take_timeout(bus)? => return Err(TcpSocketError::Timeout),
+            _ => return Err(TcpSocketError::NotConnected),
+        }
+
+        // DISCON drops data that hasn't been sent yet.
+        if self.socket_flush(bus)?.is_none() {
+            return Ok(None);
+        }
+        self.socket.command(bus, socketn::Command::Discon)?;
+        Ok(Some(()))
+    }
+
+    /// Close the connection with the FIN handshake, waiting for the peer to acknowledge it.
+    ///
+    /// If the peer doesn't answer, the chip gives up after its retransmission timeout (RTR and
+    /// RCR) and [`TcpSocketError::Timeout`] is returned. It's returned as well once the chip's
+    /// status was checked `polls` times, e.g. while the peer's receive window stays closed or it
+    /// never sends its FIN.
+    fn socket_disconnect<B: Bus>(
+        &mut self,
+        bus: &mut B,
+        interrupt_pin: &mut impl InterruptPin,
+        polls: u32,
+    ) -> Result<(), TcpSocketError<B::Error>> {
+        let mut polls = 0..polls;
+
+        for _ in &mut polls {
+            match self.socket_shutdown_write(bus) {
+                Ok(Some(())) => break,
+                Ok(None) => interrupt_pin.wait(),
+                // Not connected (anymore), nothing to shut down.
+                Err(TcpSocketError::NotConnected) => return Ok(()),
+                Err(error) => return Err(error),
+            }
+        }
+
+        for _ in &mut polls {
+            if self.socket_poll_closed(bus)?.is_some() {
+                return Ok(());
+            }
+            interrupt_pin.wait();
+        }
+        Err(TcpSocketError::Timeout)
+    }
+
+    /// Check whether the FIN handshake started by [`TcpSocket::socket_shutdown_write`]
+    /// completed. Returns `None` while it's in progress.
+    pub(crate) fn socket_poll_closed<B: Bus>(
+        &mut self,
+        bus: &mut B,
+    ) -> Result<Option<()>, TcpSocketError<B::Error>> {
+        // The socket is closed after a timeout as well.
+        if self.take_timeout(bus)? {
+            return Err(TcpSocketError::Timeout);
+        }
+        match socketn::Status::try_from(self.socket.get_status(bus)?) {
+            // DISCON may not have been processed yet.
+            Ok(socketn::Status::Established)
+            | Ok(socketn::Status::CloseWait)
+            | Ok(socketn::Status::FinWait)
+            | Ok(socketn::Status::Closing)
+            | Ok(socketn::Status::TimeWait)
+            | Ok(socketn::Status::LastAck) => {}
+
+            _ => return Ok(Some(())),
+        }
+        Ok(None)
+    }
+
+    /// Check for and clear TIMEOUT, which the chip sets when it gives up on a connection.
+    ///
+    /// [`Device::service_interrupts`] leaves it set for TCP sockets, so it's reported here.
+    fn take_timeout<B: Bus>(&self, bus: &mut B) -> Result<bool, TcpSocketError<B::Error>> {
+        let timed_out = self
+            .socket
+            .has_interrupt(bus, socketn::Interrupt::Timeout)?;
+        if timed_out {
+            self.socket
+                .reset_interrupt(bus, socketn::Interrupt::Timeout)?;
+        }
+        Ok(timed_out)
+    }
+
+    /// Copy as much of `data` as fits into the TX buffer and send it. Returns `None` if the TX
+    /// buffer is full.
+    ///
+    /// Only one SEND command may be in progress at a time. Data queued while the previous SEND is
+    /// in progress is sent by a later call or by [`TcpSocket::socket_flush`].
     fn socket_send<B: Bus>(
         &mut self,
         bus: &mut B,
         data: &[u8],
-    ) -> Result<usize, TcpSocketError<B::Error>> {
+    ) -> Result<Option<usize>, TcpSocketError<B::Error>> {
+        // A timed out connection is closed already, so this comes first.
+        self.poll_send(bus)?;
         if !self.socket_is_connected(bus)? {
             return Err(TcpSocketError::NotConnected);
         }
 
-        let max_size = self.socket.get_tx_free_size(bus)? as usize;
-
-        let write_data = if data.len() < max_size {
-            data
-        } else {
-            &data[..max_size]
-        };
+        let free_size = usize::from(self.socket.get_tx_free_size(bus)?);
+        let write_data = &data[..data.len().min(free_size)];
+        if !write_data.is_empty() {
+            // Append the data to the write buffer after the current write pointer.
+            let write_pointer = self.socket.get_tx_write_pointer(bus)?;
+            bus.write_frame(self.socket.tx_buffer(), write_pointer, write_data)?;
+            self.socket
+                .set_tx_write_pointer(bus, write_pointer.wrapping_add(write_data.len() as u16))?;
+        }
+        self.start_send(bus)?;
 
-        // Append the data to the write buffer after the current write pointer.
-        let write_pointer = self.socket.get_tx_write_pointer(bus)?;
+        if write_data.is_empty() && !data.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(write_data.len()))
+    }
 
-        // Write data into the buffer and update the writer pointer.
-        bus.write_frame(self.socket.tx_buffer(), write_pointer, write_data)?;
-        self.socket
-            .set_tx_write_pointer(bus, write_pointer.wrapping_add(write_data.len() as u16))?;
+    /// Check whether the SEND command in progress completed.
+    ///
+    /// Completion is detected by the chip's read pointer reaching the end of the sent data, not
+    /// by SEND_OK, which [`Device::service_interrupts`] may have cleared already.
+    fn poll_send<B: Bus>(&mut self, bus: &mut B) -> Result<(), TcpSocketError<B::Error>> {
+        if !self.sending {
+            return Ok(());
+        }
+        if self.take_timeout(bus)? {
+            // The peer didn't acknowledge the data, e.g. because the link is down. The chip gives
+            // up and closes the socket.
+            self.sending = false;
+            return Err(TcpSocketError::Timeout);
+        }
+        if self.socket.get_tx_read_pointer(bus)? == self.send_end {
+            // Clear SEND_OK, it would keep INTn asserted.
+            self.socket
+                .reset_interrupt(bus, socketn::Interrupt::SendOk)?;
+            self.sending = false;
+        }
+        Ok(())
+    }
 
-        // Send the data.
+    /// Issue SEND for the queued data, unless a SEND is still in progress.
+    fn start_send<B: Bus>(&mut self, bus: &mut B) -> Result<(), TcpSocketError<B::Error>> {
+        if self.sending {
+            return Ok(());
+        }
+        let write_pointer = self.socket.get_tx_write_pointer(bus)?;
+        if self.socket.get_tx_read_pointer(bus)? == write_pointer {
+            return Ok(());
+        }
         self.socket.command(bus, socketn::Command::Send)?;
-
-        // Wait until the send command completes.
-        while !self.socket.has_interrupt(bus, socketn::Interrupt::SendOk)? {}
-        self.socket
-            .reset_interrupt(bus, socketn::Interrupt::SendOk)?;
-
-        Ok(write_data.len())
+        self.sending = true;
+        self.send_end = write_pointer;
+        Ok(())
     }
 
-    fn socket_receive<B: Bus>(
+    /// Send all queued data and check whether the peer acknowledged it. Returns `None` while
+    /// data is outstanding.
+    fn socket_flush<B: Bus>(
         &mut self,
         bus: &mut B,
-        data: &mut [u8],
-    ) -> Result<usize, TcpSocketError<B::Error>> {
+    ) -> Result<Option<()>, TcpSocketError<B::Error>> {
+        // A timed out connection is closed already, so this comes first.
+        self.poll_send(bus)?;
         if !self.socket_is_connected(bus)? {
             return Err(TcpSocketError::NotConnected);
         }
-
-        // Check if we've received data.
-        if !self
-            .socket
-            .has_interrupt(bus, socketn::Interrupt::Receive)?
-        {
-            return Ok(0);
+        self.start_send(bus)?;
+        if self.sending {
+            return Ok(None);
         }
 
-        let rx_size = self.socket.get_receive_size(bus)? as usize;
+        // The chip frees the TX buffer once the peer acknowledged the data.
+        let mut buffer_size = [0u8];
+        bus.read_frame(
+            self.socket.register(),
+            socketn::TXBUF_SIZE,
+            &mut buffer_size,
+        )?;
+        let free_size = usize::from(self.socket.get_tx_free_size(bus)?);
+        Ok((free_size == usize::from(buffer_size[0]) * 1024).then_some(()))
+    }
 
-        let read_buffer = if rx_size > data.len() {
-            data
-        } else {
-            &mut data[..rx_size]
+    /// Read the received data into `data`. Returns `None` if no data has been received and
+    /// `Some(0)` once the peer closed the connection and all data has been read.
+    fn socket_receive<B: Bus>(
+        &mut self,
+        bus: &mut B,
+        data: &mut [u8],
+    ) -> Result<Option<usize>, TcpSocketError<B::Error>> {
+        let peer_closed = match socketn::Status::try_from(self.socket.get_status(bus)?) {
+            // Our FIN doesn't stop the peer from sending.
+            Ok(socketn::Status::Established) | Ok(socketn::Status::FinWait) => false,
+
+            // The peer sent its FIN, the data received before can still be read.
+            Ok(socketn::Status::CloseWait)
+            | Ok(socketn::Status::Closing)
+            | Ok(socketn::Status::TimeWait)
+            | Ok(socketn::Status::LastAck) => true,
+
+            _ => return Err(TcpSocketError::NotConnected),
         };
 
-        // Read from the RX ring buffer.
-        let read_pointer = self.socket.get_rx_read_pointer(bus)?;
-        bus.read_frame(self.socket.rx_buffer(), read_pointer, read_buffer)?;
-        self.socket
-            .set_rx_read_pointer(bus, read_pointer.wrapping_add(read_buffer.len() as u16))?;
-
-        // Register the reception as complete.
-        self.socket.command(bus, socketn::Command::Receive)?;
+        // The received size tells whether there is data, not the RECV interrupt: it's only set
+        // when new data arrives and may have been cleared by `Device::service_interrupts`. It's
+        // cleared before checking the size, so data arriving afterwards asserts INTn again.
         self.socket
             .reset_interrupt(bus, socketn::Interrupt::Receive)?;
 
-        Ok(read_buffer.len())
+        let mut cursor = RxCursor::new(&self.socket, bus)?;
+        if cursor.available() == 0 {
+            return Ok((peer_closed || data.is_empty()).then_some(0));
+        }
+        let len = cursor.read(data)?;
+        cursor.commit()?;
+        Ok(Some(usize::from(len)))
+    }
+}
+
+impl TcpSocket {
+    /// The local IP address and port of the socket
+    pub fn local_addr<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin>(
+        &self,
+        device: &mut Device<SpiBus, StateImpl, IntPin>,
+    ) -> Result<SocketAddr, TcpSocketError<SpiBus::Error>> {
+        let ip = device.ip()?;
+        let port = self.socket.get_source_port(&mut device.bus)?;
+        Ok(SocketAddr::new(IpAddr::V4(ip), port))
+    }
+
+    /// The address of the connected peer, e.g. the client of a socket returned by
+    /// [`TcpFullStack::accept`]
+    pub fn peer_addr<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin>(
+        &self,
+        device: &mut Device<SpiBus, StateImpl, IntPin>,
+    ) -> Result<SocketAddr, TcpSocketError<SpiBus::Error>> {
+        let bus = &mut device.bus;
+        match socketn::Status::try_from(self.socket.get_status(bus)?) {
+            Ok(socketn::Status::Established)
+            | Ok(socketn::Status::CloseWait)
+            | Ok(socketn::Status::FinWait)
+            | Ok(socketn::Status::Closing)
+            | Ok(socketn::Status::TimeWait)
+            | Ok(socketn::Status::LastAck) => {}
+            _ => return Err(TcpSocketError::NotConnected),
+        }
+        Ok(SocketAddr::new(
+            IpAddr::V4(self.socket.get_destination_ip(bus)?),
+            self.socket.get_destination_port(bus)?,
+        ))
+    }
+
+    /// returns the index of the socket
+    #[inline]
+    pub fn index(&self) -> u8 {
+        self.socket.index
     }
 }
 
-impl<SpiBus: Bus, StateImpl: State> TcpClientStack for Device<SpiBus, StateImpl> {
+impl<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin> TcpClientStack
+    for Device<SpiBus, StateImpl, IntPin>
+{
     type TcpSocket = TcpSocket;
     type Error = TcpSocketError<SpiBus::Error>;
 
     fn socket(&mut self) -> Result<TcpSocket, Self::Error> {
         match self.take_socket() {
-            Some(socket) => Ok(TcpSocket { socket }),
+            Some(socket) => Ok(TcpSocket::new(socket)),
             None => Err(TcpSocketError::NoMoreSockets),
         }
     }
 
+    /// Connect to `remote`.
+    ///
+    /// The first call starts the handshake, following calls return [`nb::Error::WouldBlock`]
+    /// until the connection is established or failed with [`TcpSocketError::Timeout`] or
+    /// [`TcpSocketError::ConnectionRefused`]. The socket can be reused to try again. Calling it
+    /// with a different `remote` aborts the attempt in progress and starts a new one.
     fn connect(
         &mut self,
         socket: &mut Self::TcpSocket,
@@ -236,11 +567,20 @@ impl<SpiBus: Bus, StateImpl: State> TcpClientStack for Device<SpiBus, StateImpl>
         let SocketAddr::V4(remote) = remote else {
             return Err(nb::Error::Other(Self::Error::UnsupportedAddress));
         };
-        // TODO dynamically select a random port
-        socket.open(&mut self.bus, 49849 + u16::from(socket.socket.index))?; // chosen by fair dice roll.
-        socket.socket_connect(&mut self.bus, remote)?;
-
-        Ok(())
+        if self.is_link_down() {
+            return Err(nb::Error::Other(TcpSocketError::LinkDown));
+        }
+        let Device { bus, state, .. } = self;
+        let index = socket.index();
+        // Each connection attempt gets a new port, so it doesn't reuse the 4-tuple of a previous
+        // connection the peer may still consider open.
+        socket
+            .socket_connect(
+                bus,
+                || state.ports_mut().allocate(index, Transport::Tcp),
+                remote,
+            )?
+            .ok_or(nb::Error::WouldBlock)
     }
 
     fn send(
@@ -248,21 +588,334 @@ impl<SpiBus: Bus, StateImpl: State> TcpClientStack for Device<SpiBus, StateImpl>
         socket: &mut Self::TcpSocket,
         buffer: &[u8],
     ) -> nb::Result<usize, Self::Error> {
-        let len = socket.socket_send(&mut self.bus, buffer)?;
-        Ok(len)
+        if self.is_link_down() {
+            return Err(nb::Error::Other(TcpSocketError::LinkDown));
+        }
+        socket
+            .socket_send(&mut self.bus, buffer)?
+            .ok_or(nb::Error::WouldBlock)
     }
 
+    /// Receive data from the peer.
+    ///
+    /// Returns [`nb::Error::WouldBlock`] while no data is available and `Ok(0)` once the peer
+    /// closed its side of the connection and all received data has been read.
     fn receive(
         &mut self,
         socket: &mut Self::TcpSocket,
         buffer: &mut [u8],
     ) -> nb::Result<usize, Self::Error> {
-        Ok(socket.socket_receive(&mut self.bus, buffer)?)
+        match socket.socket_receive(&mut self.bus, buffer)? {
+            Some(len) => Ok(len),
+            // Data received before the link went down can still be read.
+            None if self.is_link_down() => Err(nb::Error::Other(TcpSocketError::LinkDown)),
+            None => Err(nb::Error::WouldBlock),
+        }
     }
 
+    /// Close the connection with the FIN handshake and release the socket.
+    ///
+    /// This is [`Device::tcp_close`] giving up after [`CLOSE_POLLS`] status checks.
     fn close(&mut self, socket: Self::TcpSocket) -> Result<(), Self::Error> {
-        socket.socket_close(&mut self.bus)?;
+        self.tcp_close(socket, CLOSE_POLLS)
+    }
+}
+
+impl<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin> TcpFullStack
+    for Device<SpiBus, StateImpl, IntPin>
+{
+    fn bind(&mut self, socket: &mut Self::TcpSocket, local_port: u16) -> Result<(), Self::Error> {
+        if !self
+            .state
+            .ports_mut()
+            .bind(socket.index(), Transport::Tcp, local_port)
+        {
+            return Err(TcpSocketError::PortInUse);
+        }
+        socket.open(&mut self.bus, local_port)
+    }
+
+    fn listen(&mut self, socket: &mut Self::TcpSocket) -> Result<(), Self::Error> {
+        socket.socket_listen(&mut self.bus)
+    }
+
+    /// Accept an incoming connection on a listening socket.
+    ///
+    /// Accepting requires a free socket to take over listening on the local port. If none is
+    /// available, [`TcpSocketError::NoMoreSockets`] is returned and the connection stays pending
+    /// until a socket is released.
+    fn accept(
+        &mut self,
+        socket: &mut Self::TcpSocket,
+    ) -> nb::Result<(Self::TcpSocket, SocketAddr), Self::Error> {
+        let Device { bus, state, .. } = self;
+        match socket.socket_accept(bus, || state.socket())? {
+            Some((connection, remote)) => {
+                // The replacement socket listens on the port of the connection.
+                let port = socket
+                    .socket
+                    .get_source_port(bus)
+                    .map_err(TcpSocketError::Other)?;
+                state
+                    .ports_mut()
+                    .assign(socket.index(), Transport::Tcp, port);
+                Ok((connection, SocketAddr::V4(remote)))
+            }
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+}
+
+impl<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin> Device<SpiBus, StateImpl, IntPin> {
+    /// Wait until all data queued by [`TcpClientStack::send`] was sent and acknowledged by the
+    /// peer.
+    ///
+    /// `send` returns as soon as the data is copied into the TX buffer, so a single call doesn't
+    /// wait for a round trip. Flushing returns [`nb::Error::WouldBlock`] while data is
+    /// outstanding.
+    pub fn tcp_flush(
+        &mut self,
+        socket: &mut TcpSocket,
+    ) -> nb::Result<(), TcpSocketError<SpiBus::Error>> {
+        socket
+            .socket_flush(&mut self.bus)?
+            .ok_or(nb::Error::WouldBlock)
+    }
+
+    /// Close the sending side of the connection (half-close).
+    ///
+    /// Once the queued data was acknowledged, a FIN is sent, returning
+    /// [`nb::Error::WouldBlock`] until then. Data sent by the peer can still be received until
+    /// it closes its side as well.
+    pub fn tcp_shutdown_write(
+        &mut self,
+        socket: &mut TcpSocket,
+    ) -> nb::Result<(), TcpSocketError<SpiBus::Error>> {
+        socket
+            .socket_shutdown_write(&mut self.bus)?
+            .ok_or(nb::Error::WouldBlock)
+    }
+
+    /// Close the connection with the FIN handshake and release the socket.
+    ///
+    /// Queued data is sent first. This blocks until the peer acknowledged the FIN, waiting on
+    /// INTn in between. [`TcpSocketError::Timeout`] is returned if the chip's retransmission
+    /// timeout expired or the handshake didn't complete within `polls` checks of the chip's
+    /// status. The socket is closed and released in any case.
+    ///
+    /// Without an INTn pin, each poll is a few SPI transfers. With one, each poll waits for the
+    /// next socket interrupt, so `polls` doesn't bound the time spent waiting for a peer that
+    /// stays silent.
+    pub fn tcp_close(
+        &mut self,
+        mut socket: TcpSocket,
+        polls: u32,
+    ) -> Result<(), TcpSocketError<SpiBus::Error>> {
+        let result = socket.socket_disconnect(&mut self.bus, &mut self.interrupt_pin, polls);
+        let closed = socket.socket_close(&mut self.bus);
         self.release_socket(socket.socket);
-        Ok(())
+        result.and(closed)
+    }
+
+    /// Close the socket immediately, without the FIN handshake, and release it.
+    ///
+    /// Queued and received data is dropped. The W5500 has no command to send a RST, so nothing is
+    /// sent to the peer: the chip answers its next segment with a RST, a peer that only waits
+    /// for data notices once its keep-alive fails.
+    pub fn tcp_close_immediately(
+        &mut self,
+        socket: TcpSocket,
+    ) -> Result<(), TcpSocketError<SpiBus::Error>> {
+        let result = socket.socket_close(&mut self.bus);
+        self.release_socket(socket.socket);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use embedded_nal::{nb, TcpClientStack, TcpFullStack};
+
+    use super::TcpSocketError;
+    use crate::bus::FourWire;
+    use crate::emulator::Emulator;
+    use crate::{MacAddress, Mode, UninitializedDevice};
+
+    #[test]
+    fn test_bind_listen_accept() {
+        let ip = Ipv4Addr::new(192, 168, 0, 2);
+        let mut device = UninitializedDevice::new(FourWire::new(Emulator::new()))
+            .initialize_manual(MacAddress::new(2, 0, 0, 0, 0, 1), ip, Mode::default())
+            .unwrap();
+
+        let mut listener = device.socket().unwrap();
+        device.bind(&mut listener, 80).unwrap();
+        let mut other = device.socket().unwrap();
+        assert!(matches!(
+            device.bind(&mut other, 80),
+            Err(TcpSocketError::PortInUse)
+        ));
+        device.listen(&mut listener).unwrap();
+        assert!(matches!(
+            device.accept(&mut listener),
+            Err(nb::Error::WouldBlock)
+        ));
+
+        // The listener keeps accepting on the port after handing over a connection.
+        let remote = SocketAddr::new(IpAddr::V4(ip), 80);
+        let mut connections = Vec::new();
+        for _ in 0..2 {
+            let mut client = device.socket().unwrap();
+            nb::block!(device.connect(&mut client, remote)).unwrap();
+            let (server, peer) = nb::block!(device.accept(&mut listener)).unwrap();
+            assert_eq!(peer, client.local_addr(&mut device).unwrap());
+            assert_eq!(server.local_addr(&mut device).unwrap().port(), 80);
+            connections.push((client, server));
+        }
+
+        // Accepting needs a spare socket to keep listening.
+        let mut client = device.socket().unwrap();
+        nb::block!(device.connect(&mut client, remote)).unwrap();
+        let spare = device.socket().unwrap();
+        assert!(matches!(
+            device.accept(&mut listener),
+            Err(nb::Error::Other(TcpSocketError::NoMoreSockets))
+        ));
+        device.close(spare).unwrap();
+        let (server, peer) = nb::block!(device.accept(&mut listener)).unwrap();
+        assert_eq!(peer, client.local_addr(&mut device).unwrap());
+
+        // The port stays in use until the listener and its connections are closed.
+        device.close(listener).unwrap();
+        assert!(matches!(
+            device.bind(&mut other, 80),
+            Err(TcpSocketError::PortInUse)
+        ));
+        connections.push((client, server));
+        for (client, server) in connections {
+            device.close(client).unwrap();
+            device.close(server).unwrap();
+        }
+        device.bind(&mut other, 80).unwrap();
+    }
+
+    #[test]
+    fn test_send_after_service_interrupts() {
+        let ip = Ipv4Addr::new(192, 168, 0, 2);
+        let mut device = UninitializedDevice::new(FourWire::new(Emulator::new()))
+            .initialize_manual(MacAddress::new(2, 0, 0, 0, 0, 1), ip, Mode::default())
+            .unwrap();
+
+        let mut listener = device.socket().unwrap();
+        device.bind(&mut listener, 80).unwrap();
+        device.listen(&mut listener).unwrap();
+        let mut client = device.socket().unwrap();
+        nb::block!(device.connect(&mut client, SocketAddr::new(IpAddr::V4(ip), 80))).unwrap();
+        let (mut server, _) = nb::block!(device.accept(&mut listener)).unwrap();
+
+        assert_eq!(nb::block!(device.send(&mut client, b"first")).unwrap(), 5);
+        // Clears SEND_OK of the SEND in progress.
+        let events = device.service_interrupts().unwrap();
+        assert!(events.socket(client.index()).send_ok());
+        assert_eq!(nb::block!(device.send(&mut client, b" second")).unwrap(), 7);
+        nb::block!(device.tcp_flush(&mut client)).unwrap();
+
+        let mut buffer = [0; 16];
+        let mut len = 0;
+        while len < 12 {
+            len += nb::block!(device.receive(&mut server, &mut buffer[len..])).unwrap();
+        }
+        assert_eq!(&buffer[..len], b"first second");
+    }
+
+    #[test]
+    fn test_close_polls() {
+        let ip = Ipv4Addr::new(192, 168, 0, 2);
+        let mut device = UninitializedDevice::new(FourWire::new(Emulator::new()))
+            .initialize_manual(MacAddress::new(2, 0, 0, 0, 0, 1), ip, Mode::default())
+            .unwrap();
+        let remote = SocketAddr::new(IpAddr::V4(ip), 80);
+
+        let mut listener = device.socket().unwrap();
+        device.bind(&mut listener, 80).unwrap();
+        device.listen(&mut listener).unwrap();
+        let mut client = device.socket().unwrap();
+        nb::block!(device.connect(&mut client, remote)).unwrap();
+        let (mut server, _) = nb::block!(device.accept(&mut listener)).unwrap();
+
+        // The server doesn't read, so the data beyond its RX buffer is never acknowledged.
+        let data = [0x55; 1024];
+        for _ in 0..4 {
+            nb::block!(device.send(&mut client, &data)).unwrap();
+        }
+        assert!(matches!(
+            device.tcp_close(client, 100),
+            Err(TcpSocketError::Timeout)
+        ));
+        // The connection was closed anyway.
+        let mut buffer = [0; 16];
+        assert!(matches!(
+            device.receive(&mut server, &mut buffer),
+            Err(nb::Error::Other(TcpSocketError::NotConnected))
+        ));
+        device.close(server).unwrap();
+
+        let mut client = device.socket().unwrap();
+        nb::block!(device.connect(&mut client, remote)).unwrap();
+        let (server, _) = nb::block!(device.accept(&mut listener)).unwrap();
+        nb::block!(device.send(&mut client, &data)).unwrap();
+        device.tcp_close(client, 100).unwrap();
+        device.close(server).unwrap();
+        device.close(listener).unwrap();
+    }
+
+    #[test]
+    fn test_send_timeout() {
+        let ip = Ipv4Addr::new(192, 168, 0, 2);
+        let mut device = UninitializedDevice::new(Emulator::new())
+            .initialize_manual(MacAddress::new(2, 0, 0, 0, 0, 1), ip, Mode::default())
+            .unwrap();
+        let remote = SocketAddr::new(IpAddr::V4(ip), 80);
+
+        let mut listener = device.socket().unwrap();
+        device.bind(&mut listener, 80).unwrap();
+        device.listen(&mut listener).unwrap();
+        let mut connections = Vec::new();
+        for _ in 0..2 {
+            let mut client = device.socket().unwrap();
+            nb::block!(device.connect(&mut client, remote)).unwrap();
+            let (server, _) = nb::block!(device.accept(&mut listener)).unwrap();
+            connections.push((client, server));
+        }
+
+        // The driver doesn't poll the link, so it sends anyway and the chip times out.
+        device.bus.set_link_up(false);
+        let (mut client, server) = connections.remove(0);
+        assert_eq!(device.send(&mut client, b"lost").unwrap(), 4);
+        assert!(matches!(
+            device.send(&mut client, b"lost"),
+            Err(nb::Error::Other(TcpSocketError::Timeout))
+        ));
+        // The timeout is only reported once, the connection is closed.
+        assert!(matches!(
+            device.send(&mut client, b"lost"),
+            Err(nb::Error::Other(TcpSocketError::NotConnected))
+        ));
+        device.close(client).unwrap();
+
+        let (mut client, other_server) = connections.remove(0);
+        assert_eq!(device.send(&mut client, b"lost").unwrap(), 4);
+        assert!(matches!(
+            device.tcp_flush(&mut client),
+            Err(nb::Error::Other(TcpSocketError::Timeout))
+        ));
+        device.close(client).unwrap();
+
+        device.bus.set_link_up(true);
+        device.close(server).unwrap();
+        device.close(other_server).unwrap();
+        device.close(listener).unwrap();
     }
 }
@@ -1,6 +1,6 @@
 use core::{
     convert::TryFrom,
-    fmt::Debug,
+    fmt::{self, Debug},
     net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
 };
 
@@ -9,6 +9,10 @@ use embedded_nal::{nb, UdpClientStack, UdpFullStack};
 use crate::{
     bus::Bus,
     device::{Device, State},
+    error::{ErrorKind, UNSUPPORTED_ADDRESS},
+    interrupt::InterruptPin,
+    net::MacAddress,
+    port::Transport,
     register::socketn::{self, Status},
     socket::Socket,
 };
@@ -58,38 +62,80 @@ impl From<[u8; 8]> for UdpHeader {
     }
 }
 
+/// The IGMP version used to join and leave a multicast group
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IgmpVersion {
+    V1,
+    #[default]
+    V2,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct Multicast {
+    #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
+    group: SocketAddrV4,
+    igmp: IgmpVersion,
+}
+
+impl Multicast {
+    /// The Ethernet address of the group: `01:00:5e` followed by the lower 23 bits of the IP
+    fn mac(&self) -> MacAddress {
+        let ip = self.group.ip().octets();
+        MacAddress::new(0x01, 0x00, 0x5e, ip[1] & 0x7f, ip[2], ip[3])
+    }
+}
+
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct UdpSocket {
-    socket: Socket,
+    pub(crate) socket: Socket,
     /// Whether or not there has been a destination set for the socket.
     #[cfg_attr(feature = "defmt", defmt(Debug2Format))]
     destination: Option<SocketAddrV4>,
     /// The local port of the socket
     port: u16,
+    /// The multicast group the socket joins when it is opened
+    multicast: Option<Multicast>,
 }
 
 impl UdpSocket {
-    fn new(socket: Socket) -> Self {
-        let socket_index = socket.index;
+    pub(crate) fn new(socket: Socket, port: u16) -> Self {
         UdpSocket {
             socket,
             destination: None,
-            // TODO dynamically select a random port
-            // chosen by fair dice roll.
-            // guaranteed to be random.
-            port: 49849 + u16::from(socket_index),
+            port,
+            multicast: None,
         }
     }
 
-    fn open<SpiBus: Bus>(&self, bus: &mut SpiBus) -> Result<(), SpiBus::Error> {
+    pub(crate) fn open<SpiBus: Bus>(&mut self, bus: &mut SpiBus) -> Result<(), SpiBus::Error> {
         self.socket.command(bus, socketn::Command::Close)?;
         self.socket.reset_interrupt(bus, socketn::Interrupt::All)?;
         self.socket.set_source_port(bus, self.port)?;
-        self.socket.set_mode(bus, socketn::Protocol::Udp)?;
+        if let Some(multicast) = self.multicast {
+            // The chip sends the IGMP join for the group in the destination registers on OPEN.
+            self.socket.set_destination_mac(bus, multicast.mac())?;
+            self.socket.set_destination_ip(bus, *multicast.group.ip())?;
+            self.socket
+                .set_destination_port(bus, multicast.group.port())?;
+            self.destination = Some(multicast.group);
+
+            let flags = match multicast.igmp {
+                IgmpVersion::V1 => socketn::MODE_MULTICAST | socketn::MODE_IGMP_V1,
+                IgmpVersion::V2 => socketn::MODE_MULTICAST,
+            };
+            self.socket
+                .set_mode_with_flags(bus, socketn::Protocol::Udp, flags)?;
+        } else {
+            self.socket.set_mode(bus, socketn::Protocol::Udp)?;
+        }
         self.socket.set_interrupt_mask(
             bus,
-            socketn::Interrupt::SendOk as u8 & socketn::Interrupt::Timeout as u8,
+            socketn::Interrupt::SendOk as u8
+                | socketn::Interrupt::Timeout as u8
+                | socketn::Interrupt::Receive as u8,
         )?;
         self.socket.command(bus, socketn::Command::Open)?;
 
@@ -101,6 +147,26 @@ impl UdpSocket {
         self.port
     }
 
+    /// The local IP address and port of the socket
+    pub fn local_addr<SpiBus: Bus, StateImpl: State, IntPin: InterruptPin>(
+        &self,
+        device: &mut Device<SpiBus, StateImpl, IntPin>,
+    ) -> Result<SocketAddr, UdpSocketError<SpiBus::Error>> {
+        Ok(SocketAddr::new(IpAddr::V4(device.ip()?), self.port))
+    }
+
+    /// The destination of [`UdpClientStack::send`], as set by [`UdpClientStack::connect`].
+    ///
+    /// Note that [`UdpFullStack::send_to`] replaces the destination.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.destination.map(SocketAddr::V4)
+    }
+
+    /// The multicast group the socket is a member of
+    pub fn multicast_group(&self) -> Option<SocketAddrV4> {
+        self.multicast.map(|multicast| multicast.group)
+    }
+
     pub fn set_port<SpiBus: Bus>(
         &mut self,
         bus: &mut SpiBus,
@@ -112,7 +178,7 @@ impl UdpSocket {
         Ok(())
     }
 
-    fn set_destination<SpiBus: Bus>(
+    pub(crate) fn set_destination<SpiBus: Bus>(
         &mut self,
         bus: &mut SpiBus,
         remote: SocketAddrV4,
@@ -141,6 +207,7 @@ impl UdpSocket {
     fn socket_send_all<SpiBus: Bus>(
         &self,
         bus: &mut SpiBus,
+        interrupt_pin: &mut impl InterruptPin,
         send_buffer: &[u8],
     ) -> NbResult<(), UdpSocketError<SpiBus::Error>> {
         match Status::try_from(self.socket.get_status(bus)?) {
@@ -178,7 +245,7 @@ impl UdpSocket {
             let send_batch = &remaining_bytes[..write_len];
 
             // but on consequent send calls, we leave that to send
-            let sent = self.socket_send(bus, send_batch)?;
+            let sent = self.socket_send(bus, interrupt_pin, send_batch)?;
 
             total_sent += sent;
 
@@ -200,17 +267,34 @@ impl UdpSocket {
     fn socket_send<SpiBus: Bus>(
         &self,
         bus: &mut SpiBus,
+        interrupt_pin: &mut impl InterruptPin,
         send_buffer: &[u8],
     ) -> NbResult<usize, UdpSocketError<SpiBus::Error>> {
+        let sent = self.socket_start_send(bus, send_buffer)?;
+        self.block_flush_tx(bus, interrupt_pin)?;
+        Ok(sent)
+    }
+
+    /// Copy as much of `send_buffer` as fits into the TX buffer and issue
+    /// [`socketn::Command::Send`] for it, without waiting for the SEND to complete.
+    ///
+    /// # Returns
+    ///
+    /// The amount of bytes that were queued.
+    pub(crate) fn socket_start_send<SpiBus: Bus>(
+        &self,
+        bus: &mut SpiBus,
+        send_buffer: &[u8],
+    ) -> Result<usize, UdpSocketError<SpiBus::Error>> {
         match Status::try_from(self.socket.get_status(bus)?) {
             Ok(Status::Udp) => {}
-            Ok(status) => return Err(NbError::Other(UdpSocketError::SocketNotOpen)),
-            Err(err) => return Err(NbError::Other(UdpSocketError::UnrecognisedStatus)),
+            Ok(status) => return Err(UdpSocketError::SocketNotOpen),
+            Err(err) => return Err(UdpSocketError::UnrecognisedStatus),
         }
 
         // We need to have a set destination before sending data with this method.
         if self.destination.is_none() {
-            return Err(NbError::Other(UdpSocketError::DestinationNotSet));
+            return Err(UdpSocketError::DestinationNotSet);
         }
 
         let free_size = self.socket.get_tx_free_size(bus)?;
@@ -222,7 +306,7 @@ impl UdpSocket {
             // FIXME: add a way to either:
             // - flush the buffer by clearing it up
             // - `Send` the data to its the destination - this might not be possible if the destination has changed.
-            return Err(NbError::Other(UdpSocketError::BufferFull));
+            return Err(UdpSocketError::BufferFull);
         }
 
         // check the size of the data buffer and limit it accordingly to the available (free) TX buffer size.
@@ -242,28 +326,28 @@ impl UdpSocket {
         let new_write_pointer = write_pointer.wrapping_add(write_data.len() as u16);
         self.socket.set_tx_write_pointer(bus, new_write_pointer)?;
 
-        self.block_send_command(bus)?;
+        // Send the data.
+        self.socket.command(bus, socketn::Command::Send)?;
 
         Ok(write_data.len())
     }
 
-    /// Sets the socket to [`socketnCommand::Send`] and block flushes the TX buffer
-    fn block_send_command<SpiBus: Bus>(
+    /// Block until the SEND in progress completed, waiting on INTn in between
+    fn block_flush_tx<SpiBus: Bus>(
         &self,
         bus: &mut SpiBus,
+        interrupt_pin: &mut impl InterruptPin,
     ) -> NbResult<(), UdpSocketError<SpiBus::Error>> {
-        // Send the data.
-        self.socket.command(bus, socketn::Command::Send)?;
-
         loop {
             match self.try_flush_tx(bus) {
-                Err(NbError::WouldBlock) => {}
+                Err(NbError::WouldBlock) => interrupt_pin.wait(),
                 result => return result,
             }
         }
     }
 
-    fn try_flush_tx<SpiBus: Bus>(
+    /// Check whether the SEND in progress completed. Returns [`NbError::WouldBlock`] until then.
+    pub(crate) fn try_flush_tx<SpiBus: Bus>(
         &self,
         bus: &mut SpiBus,
     ) -> NbResult<(), UdpSocketError<SpiBus::Error>> {
@@ -301,15 +385,16 @@ impl UdpSocket {
     ///
     /// The amount of bytes that were sent. The caller should make sure to
     /// check and send the rest of the `send_buffer` data.
-    fn socket_send_to<SpiBus: Bus>(
+    pub(crate) fn socket_send_to<SpiBus: Bus>(
         &mut self,
         bus: &mut SpiBus,
+        interrupt_pin: &mut impl InterruptPin,
         remote: SocketAddrV4,
         send_buffer: &[u8],
     ) -> NbResult<(), UdpSocketError<SpiBus::Error>> {
         self.set_destination(bus, remote)?;
 
-        self.socket_send_all(bus, send_buffer)
+        self.socket_send_all(bus, interrupt_pin, send_buffer)
     }
 
     /// Receive data and mutate the `receive_buffer`.
@@ -321,8 +406,9 @@ impl UdpSocket {
     ///
     /// If the packet len is larger than the provided RX buffer, the data will be truncated.
     ///
-    /// If [`Interrupt::Receive`] is not set, it will always return [`NbError::WouldBlock`].
-    fn socket_receive<SpiBus: Bus>(
+    /// Only a single datagram is consumed per call. If the RX buffer is empty, it will return
+    /// [`NbError::WouldBlock`].
+    pub(crate) fn socket_receive<SpiBus: Bus>(
         &mut self,
         bus: &mut SpiBus,
         receive_buffer: &mut [u8],
@@ -333,15 +419,9 @@ impl UdpSocket {
             Err(err) => return Err(NbError::Other(UdpSocketError::UnrecognisedStatus)),
         }
 
-        if !self
-            .socket
-            .has_interrupt(bus, socketn::Interrupt::Receive)?
-        {
-            return Err(NbError::WouldBlock);
-        }
-
         let rx_size = self.socket.get_receive_size(bus)? as usize;
-        if rx_size == 0 {
+        // Nothing to read until at least a complete header has been received.
+        if rx_size < 8 {
             return Err(NbError::WouldBlock);
         }
 
@@ -350,10 +430,6 @@ impl UdpSocket {
             return Err(NbError::Other(UdpSocketError::BufferOverflow));
         }
 
-        // the amount of bytes we are able to read, the rest will be truncated by setting the RX read pointer
-        // to the end (even if wrapped) of the RX buffer.
-        let read_max_size = rx_size.min(buffer_size);
-
         // Read from the RX ring buffer.
         let read_pointer = self.socket.get_rx_read_pointer(bus)?;
 
@@ -366,42 +442,43 @@ impl UdpSocket {
         // we have to exclude the header's bytes when reading the data we put in the buffer.
         let data_read_pointer = read_pointer.wrapping_add(8);
 
-        // read to either the max RX Buffer length or passed buffer length.
-        // just read to the end the packet
-        let read_length = read_max_size.max(udp_header.len);
+        // the amount of bytes we are able to read, the rest of the packet will be truncated by
+        // moving the RX read pointer past the end of the packet.
+        let packet_size = udp_header.len.min(rx_size - 8);
+        let read_length = packet_size.min(buffer_size);
 
-        /// the maximum amount of bytes we can read based on the smallest value of either:
-        /// - the RX size of the socket
-        /// - Buffer size
         let read_buffer = &mut receive_buffer[..read_length];
 
         // read the rest of the packet's data that can fit in the buffer
         bus.read_frame(self.socket.rx_buffer(), data_read_pointer, read_buffer)?;
 
-        // Set the RX point after the `rx_size`, truncating any bytes that the
-        // `receiving_buffer` was not able to fit
-        // it's safe to cast `rx_size` to u16 as the maximum RX buffer is
+        // Set the RX pointer after the end of this packet, truncating any bytes that the
+        // `receiving_buffer` was not able to fit. Any following packets stay in the RX buffer.
+        // it's safe to cast to u16 as the maximum RX buffer is
         // 16 KB (`16384` maximum value) < u16::MAX
         self.socket
-            .set_rx_read_pointer(bus, read_pointer.wrapping_add(rx_size as u16))?;
+            .set_rx_read_pointer(bus, data_read_pointer.wrapping_add(packet_size as u16))?;
+
+        // Reset the Receive interrupt before the command, which raises it again while more
+        // datagrams are buffered, so INTn stays asserted for them.
+        self.socket
+            .reset_interrupt(bus, socketn::Interrupt::Receive)?;
 
         // > RECV completes the processing of the received data in Socket n RX
         // > Buffer by using a RX read pointer register (Sn_RX_RD).
         self.socket.command(bus, socketn::Command::Receive)?;
 
-        // Reset the Receive interrupt
-        self.socket
-            .reset_interrupt(bus, socketn::Interrupt::Receive)?;
-
         Ok((read_length, udp_header))
     }
 
-    fn socket_close<SpiBus: Bus>(
+    pub(crate) fn socket_close<SpiBus: Bus>(
         &self,
         bus: &mut SpiBus,
     ) -> Result<(), UdpSocketError<SpiBus::Error>> {
         self.socket.set_mode(bus, socketn::Protocol::Closed)?;
         self.socket.command(bus, socketn::Command::Close)?;
+        // Pending interrupts of a closed socket would keep INTn asserted.
+        self.socket.reset_interrupt(bus, socketn::Interrupt::All)?;
         Ok(())
     }
 
@@ -436,18 +513,68 @@ pub enum UdpSocketError<E: Debug> {
     /// Before sending any data over Udp you must first `bind` it to local port
     /// or `connect` to a remote address.
     SocketNotOpen,
+    /// The address passed to [`Device::join_multicast`] is not a multicast address
+    NotMulticast,
+    /// The Ethernet link is down, see [`Device::poll_link`]
+    LinkDown,
+    /// Another UDP socket is already bound to the port
+    PortInUse,
     Other(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] E),
     WriteTimeout,
 }
 
+impl<E: Debug> UdpSocketError<E> {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            UdpSocketError::NoMoreSockets => ErrorKind::NoMoreSockets,
+            UdpSocketError::UnsupportedAddress
+            | UdpSocketError::BufferOverflow
+            | UdpSocketError::NotMulticast => ErrorKind::InvalidInput,
+            UdpSocketError::SocketNotOpen | UdpSocketError::DestinationNotSet => {
+                ErrorKind::NotConnected
+            }
+            UdpSocketError::WriteTimeout => ErrorKind::TimedOut,
+            UdpSocketError::LinkDown => ErrorKind::LinkDown,
+            UdpSocketError::PortInUse => ErrorKind::AddrInUse,
+            UdpSocketError::Other(_) => ErrorKind::Bus,
+            UdpSocketError::UnrecognisedStatus | UdpSocketError::BufferFull => ErrorKind::Other,
+        }
+    }
+}
+
+impl<E: Debug> fmt::Display for UdpSocketError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UdpSocketError::UnsupportedAddress => f.write_str(UNSUPPORTED_ADDRESS),
+            UdpSocketError::UnrecognisedStatus => f.write_str("unrecognised socket status"),
+            UdpSocketError::BufferOverflow => f.write_str("datagram larger than the buffer"),
+            UdpSocketError::BufferFull => f.write_str("socket buffer full"),
+            UdpSocketError::DestinationNotSet => f.write_str("destination not set"),
+            UdpSocketError::SocketNotOpen => f.write_str("socket not open"),
+            UdpSocketError::NotMulticast => f.write_str("not a multicast address"),
+            UdpSocketError::Other(error) => write!(f, "{}: {:?}", ErrorKind::Bus, error),
+            error => fmt::Display::fmt(&error.kind(), f),
+        }
+    }
+}
+
+impl<E: Debug> core::error::Error for UdpSocketError<E> {}
+
+#[cfg(feature = "async")]
+impl<E: Debug> embedded_io_async::Error for UdpSocketError<E> {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        UdpSocketError::kind(self).into()
+    }
+}
+
 impl<E: Debug> From<E> for UdpSocketError<E> {
     fn from(error: E) -> UdpSocketError<E> {
         UdpSocketError::Other(error)
     }
 }
 
-type NbResult<T, E> = Result<T, NbError<E>>;
-enum NbError<E> {
+pub(crate) type NbResult<T, E> = Result<T, NbError<E>>;
+pub(crate) enum NbError<E> {
     Other(E),
     WouldBlock,
 }
@@ -473,17 +600,22 @@ impl<E: Debug> From<NbError<E>> for nb::Error<E> {
     }
 }
 
-impl<SpiBus, StateImpl> UdpClientStack for Device<SpiBus, StateImpl>
+impl<SpiBus, StateImpl, IntPin> UdpClientStack for Device<SpiBus, StateImpl, IntPin>
 where
     SpiBus: Bus,
     StateImpl: State,
+    IntPin: InterruptPin,
 {
     type UdpSocket = UdpSocket;
     type Error = UdpSocketError<SpiBus::Error>;
 
     fn socket(&mut self) -> Result<Self::UdpSocket, Self::Error> {
         if let Some(socket) = self.take_socket() {
-            Ok(UdpSocket::new(socket))
+            let port = self
+                .state
+                .ports_mut()
+                .allocate(socket.index, Transport::Udp);
+            Ok(UdpSocket::new(socket, port))
         } else {
             Err(Self::Error::NoMoreSockets)
         }
@@ -503,7 +635,10 @@ where
     }
 
     fn send(&mut self, socket: &mut Self::UdpSocket, buffer: &[u8]) -> nb::Result<(), Self::Error> {
-        socket.socket_send_all(&mut self.bus, buffer)?;
+        if self.is_link_down() {
+            return Err(nb::Error::Other(Self::Error::LinkDown));
+        }
+        socket.socket_send_all(&mut self.bus, &mut self.interrupt_pin, buffer)?;
         Ok(())
     }
 
@@ -524,12 +659,20 @@ where
     }
 }
 
-impl<SpiBus, StateImpl> UdpFullStack for Device<SpiBus, StateImpl>
+impl<SpiBus, StateImpl, IntPin> UdpFullStack for Device<SpiBus, StateImpl, IntPin>
 where
     SpiBus: Bus,
     StateImpl: State,
+    IntPin: InterruptPin,
 {
     fn bind(&mut self, socket: &mut Self::UdpSocket, local_port: u16) -> Result<(), Self::Error> {
+        if !self
+            .state
+            .ports_mut()
+            .bind(socket.index(), Transport::Udp, local_port)
+        {
+            return Err(Self::Error::PortInUse);
+        }
         socket.set_port(&mut self.bus, local_port)?;
         socket.open(&mut self.bus)?;
         Ok(())
@@ -544,8 +687,68 @@ where
         let SocketAddr::V4(remote) = remote else {
             return Err(nb::Error::Other(Self::Error::UnsupportedAddress));
         };
+        if self.is_link_down() {
+            return Err(nb::Error::Other(Self::Error::LinkDown));
+        }
 
-        socket.socket_send_to(&mut self.bus, remote, buffer)?;
+        socket.socket_send_to(&mut self.bus, &mut self.interrupt_pin, remote, buffer)?;
+        Ok(())
+    }
+}
+
+impl<SpiBus, StateImpl, IntPin> Device<SpiBus, StateImpl, IntPin>
+where
+    SpiBus: Bus,
+    StateImpl: State,
+    IntPin: InterruptPin,
+{
+    /// Join the multicast `group` and send to it by default.
+    ///
+    /// The socket is (re)opened on its local port, the chip then sends the IGMP membership report
+    /// and receives the datagrams sent to the group. The membership is kept when the socket is
+    /// bound to another port with [`UdpFullStack::bind`], which is usually the group's port:
+    ///
+    /// ```no_run
+    /// # fn example<SpiBus: w5500::bus::Bus, HostImpl: w5500::Host>(
+    /// #     device: &mut w5500::Device<SpiBus, w5500::DeviceState<HostImpl>>,
+    /// # ) -> Result<(), w5500::udp::UdpSocketError<SpiBus::Error>> {
+    /// use core::net::{Ipv4Addr, SocketAddrV4};
+    /// use embedded_nal::{UdpClientStack, UdpFullStack};
+    /// use w5500::udp::IgmpVersion;
+    ///
+    /// let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 0, 1), 5000);
+    /// let mut socket = device.socket()?;
+    /// device.join_multicast(&mut socket, group, IgmpVersion::V2)?;
+    /// device.bind(&mut socket, group.port())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn join_multicast(
+        &mut self,
+        socket: &mut UdpSocket,
+        group: SocketAddrV4,
+        igmp: IgmpVersion,
+    ) -> Result<(), UdpSocketError<SpiBus::Error>> {
+        if !group.ip().is_multicast() {
+            return Err(UdpSocketError::NotMulticast);
+        }
+        socket.multicast = Some(Multicast { group, igmp });
+        socket.open(&mut self.bus)?;
+        Ok(())
+    }
+
+    /// Leave the multicast group and reopen the socket as a regular UDP socket.
+    ///
+    /// Closing the socket sends the IGMP leave message (IGMPv2 only). Afterwards a destination
+    /// has to be set again before using [`UdpClientStack::send`].
+    pub fn leave_multicast(
+        &mut self,
+        socket: &mut UdpSocket,
+    ) -> Result<(), UdpSocketError<SpiBus::Error>> {
+        if socket.multicast.take().is_some() {
+            socket.destination = None;
+            socket.open(&mut self.bus)?;
+        }
         Ok(())
     }
 }
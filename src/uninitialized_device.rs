@@ -1,11 +1,16 @@
+use core::fmt::{self, Debug};
 use core::net::Ipv4Addr;
 
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::OutputPin;
 use embedded_hal::spi::SpiDevice;
 
+use crate::buffer_layout::BufferLayout;
 use crate::bus::{Bus, FourWire, ThreeWire};
-use crate::device::{Device, DeviceState};
+use crate::device::{Device, DeviceState, State};
+use crate::error::ErrorKind;
 use crate::host::{Dhcp, Host, Manual};
-use crate::raw_device::RawDevice;
+use crate::raw_device::{RawConfig, RawDevice};
 use crate::{
     register::{self, common::RetryTime},
     MacAddress, Mode,
@@ -15,15 +20,54 @@ use crate::{
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct UninitializedDevice<SpiBus: Bus> {
     bus: SpiBus,
+    buffer_layout: BufferLayout,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum InitializeError<SpiError> {
-    SpiError(SpiError),
+    SpiError(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] SpiError),
     ChipNotConnected,
+    /// The RSTn pin could not be driven
+    ResetPinError,
+    /// The chip did not report its version within 100 ms after a hardware reset
+    ResetTimeout,
+}
+
+/// Minimum time RSTn is held low (T<sub>RC</sub>), in microseconds
+const RESET_PULSE_US: u32 = 500;
+
+/// Maximum time for the PLL to lock after RSTn is released (T<sub>PL</sub>), in microseconds
+const PLL_LOCK_US: u32 = 1_000;
+
+/// How long [`UninitializedDevice::hardware_reset`] polls the VERSION register after the PLL
+/// locked, in milliseconds
+const RESET_TIMEOUT_MS: u32 = 100;
+
+impl<SpiError: Debug> InitializeError<SpiError> {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            InitializeError::SpiError(_) => ErrorKind::Bus,
+            InitializeError::ChipNotConnected | InitializeError::ResetTimeout => ErrorKind::NoChip,
+            InitializeError::ResetPinError => ErrorKind::Other,
+        }
+    }
+}
+
+impl<SpiError: Debug> fmt::Display for InitializeError<SpiError> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitializeError::SpiError(error) => write!(f, "{}: {:?}", ErrorKind::Bus, error),
+            InitializeError::ChipNotConnected => f.write_str("W5500 not connected"),
+            InitializeError::ResetPinError => f.write_str("failed to drive the reset pin"),
+            InitializeError::ResetTimeout => f.write_str("W5500 not responding after reset"),
+        }
+    }
 }
 
+impl<SpiError: Debug> core::error::Error for InitializeError<SpiError> {}
+
 impl<SpiError> From<SpiError> for InitializeError<SpiError> {
     fn from(error: SpiError) -> InitializeError<SpiError> {
         InitializeError::SpiError(error)
@@ -32,7 +76,17 @@ impl<SpiError> From<SpiError> for InitializeError<SpiError> {
 
 impl<SpiBus: Bus> UninitializedDevice<SpiBus> {
     pub fn new(bus: SpiBus) -> UninitializedDevice<SpiBus> {
-        UninitializedDevice { bus }
+        UninitializedDevice {
+            bus,
+            buffer_layout: BufferLayout::default(),
+        }
+    }
+
+    /// Allocate the sockets' buffer memory according to `layout` during initialization instead
+    /// of the default 2 KB per socket and direction.
+    pub fn with_buffer_layout(mut self, layout: BufferLayout) -> Self {
+        self.buffer_layout = layout;
+        self
     }
 
     /// Initialize the device with a MAC address and mode settings.
@@ -100,13 +154,22 @@ impl<SpiBus: Bus> UninitializedDevice<SpiBus> {
         self.reset()?;
 
         self.set_mode(mode_options)?;
+        self.buffer_layout.write(&mut self.bus)?;
         host.refresh(&mut self.bus)?;
-        Ok(Device::new(self.bus, DeviceState::new(host)))
+
+        let mut state = DeviceState::new(host);
+        state.set_available_sockets(self.buffer_layout.sockets());
+        Ok(Device::new(self.bus, state))
     }
 
+    /// Initialize the chip to send and receive Ethernet frames with socket 0 in MACRAW mode.
+    ///
+    /// `config` selects the frames to receive, use [`RawConfig::default()`] for the ones
+    /// addressed to `mac`, broadcasts and multicasts.
     pub fn initialize_macraw(
         mut self,
         mac: MacAddress,
+        config: RawConfig,
     ) -> Result<RawDevice<SpiBus>, InitializeError<SpiBus::Error>> {
         // Reset the device.
         self.bus
@@ -115,7 +178,57 @@ impl<SpiBus: Bus> UninitializedDevice<SpiBus> {
         self.bus
             .write_frame(register::COMMON, register::common::MAC, &mac.octets)?;
 
-        RawDevice::new(self.bus)
+        RawDevice::new(self.bus, config)
+    }
+
+    /// Reset the chip via its RSTn pin.
+    ///
+    /// Unlike [`UninitializedDevice::reset`] this also recovers a chip that has locked up or
+    /// whose SPI interface is out of sync. RSTn is held low for 500 µs, then the PLL is given
+    /// 1 ms to lock before the VERSION register is polled for up to 100 ms.
+    ///
+    /// ```no_run
+    /// # fn example<SpiBus: w5500::bus::Bus>(
+    /// #     bus: SpiBus,
+    /// #     mut reset_pin: impl embedded_hal::digital::OutputPin,
+    /// #     mut delay: impl embedded_hal::delay::DelayNs,
+    /// # ) -> Result<(), w5500::InitializeError<SpiBus::Error>> {
+    /// let mut device = w5500::UninitializedDevice::new(bus);
+    /// device.hardware_reset(&mut reset_pin, &mut delay)?;
+    /// let device = device.initialize(
+    ///     w5500::MacAddress::new(0x02, 0x00, 0x00, 0x00, 0x00, 0x01),
+    ///     w5500::Mode::default(),
+    /// )?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn hardware_reset(
+        &mut self,
+        reset_pin: &mut impl OutputPin,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), InitializeError<SpiBus::Error>> {
+        reset_pin
+            .set_low()
+            .map_err(|_| InitializeError::ResetPinError)?;
+        delay.delay_us(RESET_PULSE_US);
+        reset_pin
+            .set_high()
+            .map_err(|_| InitializeError::ResetPinError)?;
+        delay.delay_us(PLL_LOCK_US);
+
+        #[cfg(not(feature = "no-chip-version-assertion"))]
+        {
+            let mut remaining_ms = RESET_TIMEOUT_MS;
+            while self.version()? != 0x04 {
+                if remaining_ms == 0 {
+                    return Err(InitializeError::ResetTimeout);
+                }
+                remaining_ms -= 1;
+                delay.delay_ms(1);
+            }
+        }
+
+        Ok(())
     }
 
     /// Reset the device
@@ -244,3 +357,55 @@ impl<SpiBus: Bus> UninitializedDevice<SpiBus> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+    use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction as PinTransaction};
+    use embedded_hal_mock::eh1::spi::{Mock as SpiMock, Transaction as SpiTransaction};
+
+    use super::*;
+
+    fn read_version(version: u8) -> [SpiTransaction<u8>; 5] {
+        [
+            SpiTransaction::transaction_start(),
+            SpiTransaction::write_vec(register::common::VERSION.to_be_bytes().to_vec()),
+            SpiTransaction::write(register::COMMON << 3),
+            SpiTransaction::transfer_in_place(vec![0], vec![version]),
+            SpiTransaction::transaction_end(),
+        ]
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-chip-version-assertion"))]
+    fn test_hardware_reset() {
+        let pin_expectations = [
+            PinTransaction::set(State::Low),
+            PinTransaction::set(State::High),
+        ];
+
+        // The chip only answers on the second poll.
+        let mut spi_expectations = read_version(0xFF).to_vec();
+        spi_expectations.extend(read_version(0x04));
+        let mut device = UninitializedDevice::new(FourWire::new(SpiMock::new(&spi_expectations)));
+        let mut reset_pin = PinMock::new(&pin_expectations);
+        assert!(device
+            .hardware_reset(&mut reset_pin, &mut NoopDelay::new())
+            .is_ok());
+        reset_pin.done();
+        device.bus.release().done();
+
+        // The chip never answers.
+        let spi_expectations = (0..=RESET_TIMEOUT_MS)
+            .flat_map(|_| read_version(0x00))
+            .collect::<Vec<_>>();
+        let mut device = UninitializedDevice::new(FourWire::new(SpiMock::new(&spi_expectations)));
+        let mut reset_pin = PinMock::new(&pin_expectations);
+        assert!(matches!(
+            device.hardware_reset(&mut reset_pin, &mut NoopDelay::new()),
+            Err(InitializeError::ResetTimeout)
+        ));
+        reset_pin.done();
+        device.bus.release().done();
+    }
+}